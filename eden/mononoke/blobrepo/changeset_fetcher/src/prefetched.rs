@@ -80,15 +80,24 @@ impl PrefetchedChangesetsFetcher {
         })
     }
 
-    async fn get_cs_entry(&self, ctx: &CoreContext, cs_id: ChangesetId) -> Result<ChangesetEntry> {
+    /// Get the entry of `cs_id`, or None if the changeset doesn't exist.
+    pub async fn find_cs_entry(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetEntry>> {
         let prefetched_entry = self.prefetched.get(&cs_id);
         if let Some(prefetched_entry) = prefetched_entry {
-            Ok(prefetched_entry.clone())
+            Ok(Some(prefetched_entry.clone()))
         } else {
-            let maybe_cs = self.changesets.get(ctx, cs_id).await?;
-            maybe_cs.ok_or_else(|| format_err!("{} not found", cs_id))
+            self.changesets.get(ctx, cs_id).await
         }
     }
+
+    async fn get_cs_entry(&self, ctx: &CoreContext, cs_id: ChangesetId) -> Result<ChangesetEntry> {
+        let maybe_cs = self.find_cs_entry(ctx, cs_id).await?;
+        maybe_cs.ok_or_else(|| format_err!("{} not found", cs_id))
+    }
 }
 
 #[async_trait::async_trait]
@@ -32,13 +32,23 @@ pub async fn fetch_generations(
     ctx: &CoreContext,
     cs_fetcher: &ArcChangesetFetcher,
     cs_ids: Vec<ChangesetId>,
+) -> Result<Vec<(ChangesetId, Generation)>, Error> {
+    fetch_generations_batched(ctx, cs_fetcher, cs_ids, 10).await
+}
+
+/// Same as `fetch_generations`, but with at most `batch_size` fetches in flight at once.
+pub async fn fetch_generations_batched(
+    ctx: &CoreContext,
+    cs_fetcher: &ArcChangesetFetcher,
+    cs_ids: Vec<ChangesetId>,
+    batch_size: usize,
 ) -> Result<Vec<(ChangesetId, Generation)>, Error> {
     let cs_ids_with_gens = iter(cs_ids)
         .map(|cs_id| async move {
             let gen = fetch_generation(ctx, cs_fetcher, cs_id).await?;
             Result::<_, Error>::Ok((cs_id, gen))
         })
-        .buffered(10)
+        .buffered(batch_size)
         .try_collect()
         .await?;
 
@@ -91,17 +101,35 @@ pub async fn get_parents(
     changeset_fetcher.get_parents(ctx, node).await
 }
 
+/// Parents always have a strictly lower generation number than their children.
+/// If that's not the case the commit graph contains a cycle, and walking it
+/// would never terminate, so fail with ErrorKind::GraphCycle instead.
+pub fn check_parent_generation(
+    (node, node_gen): (ChangesetId, Generation),
+    parent_gen: Generation,
+) -> Result<(), Error> {
+    if parent_gen >= node_gen {
+        return Err(ErrorKind::GraphCycle {
+            commit: format!("{}", node),
+        }
+        .into());
+    }
+    Ok(())
+}
+
 // Take ownership of two sets, the current 'layer' of the bfs, and all nodes seen until then.
 // Produce a future which does the following computation:
 // - add all nodes in the current layer to the seen set
 // - get the set of parents of nodes in the current layer
 // - filter out previously seen nodes from the parents
 // - return the parents as the next bfs layer, and the updated seen as the new seen set
+// At most `concurrency` parent fetches are in flight at once.
 pub async fn advance_bfs_layer(
     ctx: &CoreContext,
     changeset_fetcher: &ArcChangesetFetcher,
     curr_layer: HashSet<(ChangesetId, Generation)>,
     mut curr_seen: HashSet<(ChangesetId, Generation)>,
+    concurrency: usize,
 ) -> Result<
     (
         HashSet<(ChangesetId, Generation)>,
@@ -114,15 +142,23 @@ pub async fn advance_bfs_layer(
     }
 
     let parent_gen: Vec<_> = iter(curr_layer)
-        .map(|(hash, _gen)| get_parents(ctx, changeset_fetcher, hash))
-        .buffer_unordered(100)
-        .map_ok(|parents| iter(parents.into_iter().map(Ok::<_, Error>)))
+        .map(|(hash, gen)| async move {
+            let parents = get_parents(ctx, changeset_fetcher, hash).await?;
+            Ok::<_, Error>((hash, gen, parents))
+        })
+        .buffer_unordered(concurrency)
+        .map_ok(|(hash, gen, parents)| {
+            iter(
+                parents
+                    .into_iter()
+                    .map(move |parent| Ok::<_, Error>((hash, gen, parent))),
+            )
+        })
         .try_flatten()
-        .and_then(|parent| async move {
-            Ok((
-                parent,
-                fetch_generation(ctx, changeset_fetcher, parent).await?,
-            ))
+        .and_then(|(hash, gen, parent)| async move {
+            let parent_gen = fetch_generation(ctx, changeset_fetcher, parent).await?;
+            check_parent_generation((hash, gen), parent_gen)?;
+            Ok((parent, parent_gen))
         })
         .try_collect()
         .await?;
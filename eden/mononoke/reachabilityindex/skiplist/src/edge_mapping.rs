@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use dashmap::mapref::multiple::RefMulti;
+use dashmap::mapref::one::Ref;
+use dashmap::DashMap;
+use lru_cache::LruCache;
+use mononoke_types::ChangesetId;
+use mononoke_types::Generation;
+
+use crate::SkiplistNodeType;
+use crate::DEFAULT_EDGE_COUNT;
+
+/// Tracks the approximate memory used by indexed nodes, and the order in which they
+/// were used, so that the least recently used nodes can be evicted.
+#[derive(Debug)]
+struct MemoryBudget {
+    limit: usize,
+    used: usize,
+    lru: LruCache<ChangesetId, usize>,
+}
+
+impl MemoryBudget {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: 0,
+            lru: LruCache::new(usize::MAX),
+        }
+    }
+}
+
+pub(crate) fn estimated_node_size(node: &SkiplistNodeType) -> usize {
+    let edge_count = match node {
+        SkiplistNodeType::SingleEdge(_) => 1,
+        SkiplistNodeType::SkipEdges(edges) | SkiplistNodeType::ParentEdges(edges) => edges.len(),
+    };
+    size_of::<ChangesetId>()
+        + size_of::<SkiplistNodeType>()
+        + edge_count * size_of::<(ChangesetId, Generation)>()
+}
+
+/// The indexed nodes. With a memory budget, every lookup of a node through `get` marks it as
+/// recently used, and every `insert` evicts the least recently used nodes once the budget is
+/// exceeded. The map is only reachable through these methods, so that no read or write
+/// bypasses the budget.
+#[derive(Debug, Clone)]
+pub(crate) struct SkiplistEdgeMapping {
+    mapping: DashMap<ChangesetId, SkiplistNodeType>,
+    pub skip_edges_per_node: u32,
+    memory_budget: Option<Arc<Mutex<MemoryBudget>>>,
+}
+
+impl SkiplistEdgeMapping {
+    pub fn new() -> Self {
+        SkiplistEdgeMapping {
+            mapping: DashMap::new(),
+            skip_edges_per_node: DEFAULT_EDGE_COUNT,
+            memory_budget: None,
+        }
+    }
+
+    pub fn from_map(map: DashMap<ChangesetId, SkiplistNodeType>) -> Self {
+        SkiplistEdgeMapping {
+            mapping: map,
+            skip_edges_per_node: DEFAULT_EDGE_COUNT,
+            memory_budget: None,
+        }
+    }
+
+    pub fn with_skip_edge_count(self, skip_edges_per_node: u32) -> Self {
+        SkiplistEdgeMapping {
+            skip_edges_per_node,
+            ..self
+        }
+    }
+
+    /// Nodes that are already indexed count towards the budget, in no particular order.
+    pub fn with_memory_budget(self, memory_budget: usize) -> Self {
+        let existing = self.to_map();
+        let mapping = SkiplistEdgeMapping {
+            mapping: DashMap::new(),
+            memory_budget: Some(Arc::new(Mutex::new(MemoryBudget::new(memory_budget)))),
+            ..self
+        };
+        for (cs_id, node) in existing {
+            mapping.insert(cs_id, node);
+        }
+        mapping
+    }
+
+    /// Insert a node, evicting the least recently used nodes if the memory budget is exceeded.
+    pub fn insert(&self, cs_id: ChangesetId, node: SkiplistNodeType) {
+        let size = estimated_node_size(&node);
+        self.mapping.insert(cs_id, node);
+        if let Some(memory_budget) = &self.memory_budget {
+            let mut budget = memory_budget.lock().expect("lock poisoned");
+            budget.used += size;
+            if let Some(old_size) = budget.lru.insert(cs_id, size) {
+                budget.used -= old_size;
+            }
+            // Always keep the node that was just inserted.
+            while budget.used > budget.limit && budget.lru.len() > 1 {
+                if let Some((evicted, evicted_size)) = budget.lru.remove_lru() {
+                    budget.used -= evicted_size;
+                    self.mapping.remove(&evicted);
+                }
+            }
+        }
+    }
+
+    /// Get a node, marking it as recently used.
+    pub fn get(&self, cs_id: &ChangesetId) -> Option<Ref<'_, ChangesetId, SkiplistNodeType>> {
+        if let Some(memory_budget) = &self.memory_budget {
+            memory_budget
+                .lock()
+                .expect("lock poisoned")
+                .lru
+                .get_mut(cs_id);
+        }
+        self.mapping.get(cs_id)
+    }
+
+    /// Whether the node is indexed. This doesn't count as a use of the node.
+    pub fn contains_key(&self, cs_id: &ChangesetId) -> bool {
+        self.mapping.contains_key(cs_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.mapping.len()
+    }
+
+    /// Iterates over all the indexed nodes, without marking them as used.
+    pub fn iter(&self) -> impl Iterator<Item = RefMulti<'_, ChangesetId, SkiplistNodeType>> {
+        self.mapping.iter()
+    }
+
+    /// Returns a copy of all the indexed nodes.
+    pub fn to_map(&self) -> DashMap<ChangesetId, SkiplistNodeType> {
+        self.mapping.clone()
+    }
+}
@@ -6,9 +6,12 @@
  */
 
 use std::cmp::min;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::Write;
 use std::num::NonZeroI64;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 
 use anyhow::Error;
@@ -21,15 +24,20 @@ use cloned::cloned;
 use common::advance_bfs_layer;
 use common::changesets_with_generation_numbers;
 use common::check_if_node_exists;
+use common::check_parent_generation;
 use common::fetch_generation;
+use common::fetch_generations_batched;
 use common::get_parents;
 use context::CoreContext;
 use context::PerfCounterType;
 use dashmap::DashMap;
 use fbthrift::compact_protocol;
 use futures::future::try_join_all;
+use futures::stream;
 use futures::stream::futures_unordered::FuturesUnordered;
+use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
+use futures::Stream;
 use futures_util::try_join;
 use maplit::hashmap;
 use maplit::hashset;
@@ -39,15 +47,21 @@ use mononoke_types::FIRST_GENERATION;
 use reachabilityindex::errors::*;
 use reachabilityindex::LeastCommonAncestorsHint;
 use reachabilityindex::NodeFrontier;
+use reachabilityindex::ReachabilityConfig;
 use reachabilityindex::ReachabilityIndex;
 use reloader::Loader;
 use reloader::Reloader;
 use slog::info;
 use slog::Logger;
 use tokio::task;
+use tokio_util::sync::CancellationToken;
 
+mod edge_mapping;
 pub mod sparse;
 
+use crate::edge_mapping::SkiplistEdgeMapping;
+use crate::sparse::BuildProgress;
+
 const DEFAULT_EDGE_COUNT: u32 = 10;
 
 // Each indexed node fits into one of two categories:
@@ -189,31 +203,14 @@ fn deserialize_skiplist_mapping(logger: Logger, bytes: Bytes) -> Result<Skiplist
     Ok(SkiplistEdgeMapping::from_map(cmap))
 }
 
-#[derive(Debug, Clone)]
-struct SkiplistEdgeMapping {
-    pub mapping: DashMap<ChangesetId, SkiplistNodeType>,
-    pub skip_edges_per_node: u32,
-}
-
-impl SkiplistEdgeMapping {
-    pub fn new() -> Self {
-        SkiplistEdgeMapping {
-            mapping: DashMap::new(),
-            skip_edges_per_node: DEFAULT_EDGE_COUNT,
-        }
-    }
-
-    pub fn from_map(map: DashMap<ChangesetId, SkiplistNodeType>) -> Self {
-        SkiplistEdgeMapping {
-            mapping: map,
-            skip_edges_per_node: DEFAULT_EDGE_COUNT,
-        }
-    }
-
-    pub fn with_skip_edge_count(self, skip_edges_per_node: u32) -> Self {
-        SkiplistEdgeMapping {
-            skip_edges_per_node,
-            ..self
+/// Derives the generation number of an indexed node from its edges: the first skip edge
+/// always points to the parent. Returns None for roots and single edge nodes.
+fn derived_generation(node: &SkiplistNodeType) -> Option<Generation> {
+    match node {
+        SkiplistNodeType::SingleEdge(_) => None,
+        SkiplistNodeType::SkipEdges(edges) => edges.first().map(|(_, gen)| gen.add(1)),
+        SkiplistNodeType::ParentEdges(edges) => {
+            edges.iter().map(|(_, gen)| *gen).max().map(|g| g.add(1))
         }
     }
 }
@@ -284,7 +281,7 @@ async fn compute_skip_edges(
             .checked_sub(1 << (i + 1))
             .unwrap_or(FIRST_GENERATION);
         {
-            match skip_edge_mapping.mapping.get(&curr.0) {
+            match skip_edge_mapping.get(&curr.0) {
                 Some(read_locked_entry) => {
                     // ith edge should point not further than 2^(i+1) commits back
 
@@ -329,6 +326,74 @@ async fn compute_skip_edges(
     }
     Ok(skip_edges)
 }
+/// Returns the reason why the edges of `cs_id` disagree with the commit graph, if they do.
+async fn find_invalid_edge(
+    ctx: &CoreContext,
+    changeset_fetcher: &ArcChangesetFetcher,
+    cs_id: ChangesetId,
+    node: SkiplistNodeType,
+) -> Result<Option<String>, Error> {
+    let parents = get_parents(ctx, changeset_fetcher, cs_id).await?;
+    let edges = match node {
+        SkiplistNodeType::ParentEdges(edges) => {
+            let recorded: HashSet<_> = edges.iter().map(|(parent, _)| *parent).collect();
+            if recorded != parents.iter().cloned().collect() {
+                return Ok(Some("parent edges don't match the parents".to_string()));
+            }
+            edges
+        }
+        SkiplistNodeType::SingleEdge(edge) => vec![edge],
+        SkiplistNodeType::SkipEdges(edges) => edges,
+    };
+    for (target, target_gen) in edges {
+        let actual_gen = fetch_generation(ctx, changeset_fetcher, target).await?;
+        if actual_gen != target_gen {
+            return Ok(Some(format!(
+                "edge to {} has generation {}, expected {}",
+                target,
+                target_gen.value(),
+                actual_gen.value()
+            )));
+        }
+        if !is_ancestor_by_walk(
+            ctx,
+            changeset_fetcher,
+            parents.clone(),
+            (target, target_gen),
+        )
+        .await?
+        {
+            return Ok(Some(format!(
+                "edge to {} doesn't lead to an ancestor",
+                target
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks if `anc` is reachable from `to_visit` by walking the parents, without
+/// using any index.
+async fn is_ancestor_by_walk(
+    ctx: &CoreContext,
+    changeset_fetcher: &ArcChangesetFetcher,
+    mut to_visit: Vec<ChangesetId>,
+    (anc, anc_gen): (ChangesetId, Generation),
+) -> Result<bool, Error> {
+    let mut seen = HashSet::new();
+    while let Some(cs_id) = to_visit.pop() {
+        if cs_id == anc {
+            return Ok(true);
+        }
+        if !seen.insert(cs_id) || fetch_generation(ctx, changeset_fetcher, cs_id).await? <= anc_gen
+        {
+            continue;
+        }
+        to_visit.extend(get_parents(ctx, changeset_fetcher, cs_id).await?);
+    }
+    Ok(false)
+}
+
 /// Structure for indexing skip list edges for reachability queries.
 #[facet::facet]
 #[derive(Debug, Clone)]
@@ -342,6 +407,10 @@ pub struct SkiplistIndex {
     //   from this node (which is always the case for a merge node), so we must
     //   recurse on all the children.
     skip_list_edges: Reloader<SkiplistEdgeMapping>,
+    // Lowest common ancestors computed upfront for pairs of frequently queried
+    // changesets, keyed by the ordered pair of changesets.
+    precomputed_lcas: Arc<DashMap<(ChangesetId, ChangesetId), Vec<ChangesetId>>>,
+    config: ReachabilityConfig,
 }
 
 // Find nodes to index during lazy indexing
@@ -354,6 +423,7 @@ async fn find_nodes_to_index(
     skip_list_edges: &Arc<SkiplistEdgeMapping>,
     (start_node, start_gen): (ChangesetId, Generation),
     depth: u64,
+    concurrency: NonZeroUsize,
 ) -> Result<Vec<(ChangesetId, Generation)>, Error> {
     let mut bfs_layer: HashSet<_> = vec![(start_node, start_gen)].into_iter().collect();
     let mut seen: HashSet<_> = HashSet::new();
@@ -361,13 +431,14 @@ async fn find_nodes_to_index(
 
     check_if_node_exists(ctx, changeset_fetcher, start_node).await?;
     loop {
-        bfs_layer.retain(|(hash, _gen)| !skip_list_edges.mapping.contains_key(hash));
+        bfs_layer.retain(|(hash, _gen)| !skip_list_edges.contains_key(hash));
 
         if curr_depth == 0 || bfs_layer.is_empty() {
             break;
         } else {
             let (next_bfs_layer, next_seen) =
-                advance_bfs_layer(ctx, changeset_fetcher, bfs_layer, seen).await?;
+                advance_bfs_layer(ctx, changeset_fetcher, bfs_layer, seen, concurrency.get())
+                    .await?;
             bfs_layer = next_bfs_layer;
             seen = next_seen;
             curr_depth -= 1;
@@ -375,21 +446,25 @@ async fn find_nodes_to_index(
     }
 
     let mut top_order = seen.into_iter().collect::<Vec<_>>();
-    top_order.sort_by(|a, b| (a.1).cmp(&b.1));
+    // Sort by changeset id within a generation to keep the indexing order deterministic.
+    top_order.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
     Ok(top_order)
 }
 
 /// From a starting node, index all nodes that are reachable within a given distance.
 /// If a previously indexed node is reached, indexing will stop there.
+/// At most `concurrency` requests are sent to the changeset fetcher at once.
 async fn lazy_index_node(
     ctx: &CoreContext,
     changeset_fetcher: &ArcChangesetFetcher,
     skip_edge_mapping: &Arc<SkiplistEdgeMapping>,
     node: ChangesetId,
     max_depth: u64,
+    concurrency: NonZeroUsize,
+    progress: &mut Option<BuildProgress>,
 ) -> Result<(), Error> {
     // if this node is indexed or we've passed the max depth, return
-    if max_depth == 0 || skip_edge_mapping.mapping.contains_key(&node) {
+    if max_depth == 0 || skip_edge_mapping.contains_key(&node) {
         return Ok(());
     }
 
@@ -400,25 +475,26 @@ async fn lazy_index_node(
         skip_edge_mapping,
         (node, gen),
         max_depth,
+        concurrency,
     )
     .await?;
-    let hash_parentgens_gen_vec =
-        try_join_all(node_gen_pairs.into_iter().map(|(hash, _gen)| async move {
+    let hash_parentgens_gen_vec: Vec<_> = stream::iter(node_gen_pairs)
+        .map(|(hash, _gen)| async move {
             let parents = get_parents(ctx, changeset_fetcher, hash).await?;
             let parent_gen_pairs =
                 changesets_with_generation_numbers(ctx, changeset_fetcher, parents).await?;
             let res: Result<_, Error> = Ok((hash, parent_gen_pairs));
             res
-        }))
+        })
+        .buffered(concurrency.get())
+        .try_collect()
         .await?;
 
     for (curr_hash, parent_gen_pairs) in hash_parentgens_gen_vec.into_iter() {
         if parent_gen_pairs.len() != 1 {
             // Merge node or parentless node
             // Reflect this in the index
-            skip_edge_mapping
-                .mapping
-                .insert(curr_hash, SkiplistNodeType::ParentEdges(parent_gen_pairs));
+            skip_edge_mapping.insert(curr_hash, SkiplistNodeType::ParentEdges(parent_gen_pairs));
         } else {
             // Single parent node
             // Compute skip edges assuming a reasonable number of parents are indexed.
@@ -432,9 +508,10 @@ async fn lazy_index_node(
                 skip_edge_mapping.clone(),
             )
             .await?;
-            skip_edge_mapping
-                .mapping
-                .insert(curr_hash, SkiplistNodeType::SkipEdges(new_edges));
+            skip_edge_mapping.insert(curr_hash, SkiplistNodeType::SkipEdges(new_edges));
+        }
+        if let Some(progress) = progress.as_mut() {
+            progress.add_visited(1);
         }
     }
     Ok(())
@@ -488,6 +565,8 @@ impl SkiplistIndex {
     fn from_edges(mapping: SkiplistEdgeMapping) -> Self {
         Self {
             skip_list_edges: Reloader::fixed(mapping),
+            precomputed_lcas: Arc::new(DashMap::new()),
+            config: ReachabilityConfig::default(),
         }
     }
 
@@ -519,6 +598,8 @@ impl SkiplistIndex {
                 .await?;
                 Ok(Arc::new(Self {
                     skip_list_edges: reloader,
+                    precomputed_lcas: Arc::new(DashMap::new()),
+                    config: ReachabilityConfig::default(),
                 }))
             }
             None => Ok(Arc::new(SkiplistIndex::new())),
@@ -535,6 +616,27 @@ impl SkiplistIndex {
         )
     }
 
+    /// Creates an index that keeps the estimated memory used by indexed nodes under
+    /// `memory_budget` bytes by evicting the least recently used nodes. Evicted nodes
+    /// are indexed again by `add_node`, and queries stay correct in the meantime, just
+    /// slower, as they have to fetch parents of unindexed nodes.
+    pub fn with_memory_budget(memory_budget: usize) -> Self {
+        SkiplistIndex::from_edges(SkiplistEdgeMapping::new().with_memory_budget(memory_budget))
+    }
+
+    /// Creates an index that uses `config` to bound the concurrency of index builds
+    /// and queries.
+    pub fn with_config(config: ReachabilityConfig) -> Self {
+        Self {
+            config,
+            ..SkiplistIndex::new()
+        }
+    }
+
+    pub fn config(&self) -> &ReachabilityConfig {
+        &self.config
+    }
+
     pub fn skip_edge_count(&self) -> u32 {
         self.edges().skip_edges_per_node
     }
@@ -545,6 +647,48 @@ impl SkiplistIndex {
         changeset_fetcher: &ArcChangesetFetcher,
         node: ChangesetId,
         max_index_depth: u64,
+    ) -> Result<(), Error> {
+        self.add_node_with_concurrency(
+            ctx,
+            changeset_fetcher,
+            node,
+            max_index_depth,
+            self.config.build_concurrency,
+        )
+        .await
+    }
+
+    /// Same as `add_node`, but sends at most `concurrency` requests to the changeset
+    /// fetcher at once. The resulting index doesn't depend on `concurrency`.
+    pub async fn add_node_with_concurrency(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        node: ChangesetId,
+        max_index_depth: u64,
+        concurrency: NonZeroUsize,
+    ) -> Result<(), Error> {
+        lazy_index_node(
+            ctx,
+            changeset_fetcher,
+            &self.skip_list_edges.load(),
+            node,
+            max_index_depth,
+            concurrency,
+            &mut None,
+        )
+        .await
+    }
+
+    /// Same as `add_node`, but reports the number of nodes indexed so far to `progress` as
+    /// the build goes.
+    pub async fn add_node_with_progress(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        node: ChangesetId,
+        max_index_depth: u64,
+        progress: BuildProgress,
     ) -> Result<(), Error> {
         lazy_index_node(
             ctx,
@@ -552,15 +696,52 @@ impl SkiplistIndex {
             &self.skip_list_edges.load(),
             node,
             max_index_depth,
+            self.config.build_concurrency,
+            &mut Some(progress),
         )
         .await
     }
 
+    /// Index all unindexed ancestors of `nodes`, so that subsequent queries
+    /// starting from them are answered from memory. Walking stops at nodes that are
+    /// already indexed.
+    pub async fn preload(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        nodes: &[ChangesetId],
+    ) -> Result<(), Error> {
+        let mut nodes = fetch_generations_batched(
+            ctx,
+            changeset_fetcher,
+            nodes.to_vec(),
+            self.config.fetch_batch_size.get(),
+        )
+        .await?;
+        // Index lowest generations first, so that the walks from higher nodes stop
+        // as soon as they reach the already indexed part of the graph.
+        nodes.sort_by_key(|(_, gen)| *gen);
+        let skip_list_edges = self.skip_list_edges.load();
+        for (node, _gen) in nodes {
+            lazy_index_node(
+                ctx,
+                changeset_fetcher,
+                &skip_list_edges,
+                node,
+                u64::MAX,
+                self.config.build_concurrency,
+                &mut None,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
     /// get skiplist edges originating from a particular node hash
     /// returns Some(edges) if this node was indexed with skip edges
     /// returns None if this node was unindexed, or was indexed with parent edges only.
     pub fn get_skip_edges(&self, node: ChangesetId) -> Option<Vec<(ChangesetId, Generation)>> {
-        if let Some(read_guard) = self.edges().mapping.get(&node) {
+        if let Some(read_guard) = self.edges().get(&node) {
             if let SkiplistNodeType::SkipEdges(edges) = &*read_guard {
                 Some(edges.clone())
             } else {
@@ -574,7 +755,7 @@ impl SkiplistIndex {
     /// Returns the changesets that are the furthest distance from the
     /// originating changeset.
     pub fn get_furthest_edges(&self, node: ChangesetId) -> Option<Vec<(ChangesetId, Generation)>> {
-        if let Some(read_guard) = self.edges().mapping.get(&node) {
+        if let Some(read_guard) = self.edges().get(&node) {
             match &*read_guard {
                 SkiplistNodeType::SingleEdge(edge) => Some(vec![edge.clone()]),
                 SkiplistNodeType::SkipEdges(edges) => {
@@ -592,7 +773,7 @@ impl SkiplistIndex {
     pub fn has_any_skip_edges(&self, node_frontier: &NodeFrontier) -> bool {
         let skip_list_edges = self.edges();
         node_frontier.iter().any(|(changeset, _)| {
-            if let Some(read_guard) = skip_list_edges.mapping.get(changeset) {
+            if let Some(read_guard) = skip_list_edges.get(changeset) {
                 if let SkiplistNodeType::SkipEdges(_) = &*read_guard {
                     true
                 } else {
@@ -609,27 +790,373 @@ impl SkiplistIndex {
     }
 
     pub fn get_all_skip_edges(&self) -> HashMap<ChangesetId, SkiplistNodeType> {
-        self.edges().mapping.clone().into_iter().collect()
+        self.edges().to_map().into_iter().collect()
     }
 
     pub fn is_node_indexed(&self, node: ChangesetId) -> bool {
-        self.edges().mapping.contains_key(&node)
+        self.edges().contains_key(&node)
+    }
+
+    /// Returns true if the changeset is indexed. If a query says that a changeset is not
+    /// reachable, this can be used to tell whether it simply wasn't indexed yet.
+    pub fn contains(&self, cs_id: &ChangesetId) -> bool {
+        self.is_node_indexed(*cs_id)
+    }
+
+    /// Returns the indexed changesets that no other indexed changeset points to, i.e. the
+    /// heads of the indexed part of the graph. Note that after
+    /// `trim_to_single_entry_per_changeset` parents might be missing from the edges, so
+    /// some of the returned changesets might not be real heads.
+    pub fn covered_heads(&self) -> Vec<ChangesetId> {
+        let skip_list_edges = self.edges();
+        let mut pointed_to = HashSet::new();
+        for entry in skip_list_edges.iter() {
+            match entry.value() {
+                SkiplistNodeType::SingleEdge((cs_id, _)) => {
+                    pointed_to.insert(*cs_id);
+                }
+                SkiplistNodeType::SkipEdges(edges) | SkiplistNodeType::ParentEdges(edges) => {
+                    pointed_to.extend(edges.iter().map(|(cs_id, _)| *cs_id));
+                }
+            }
+        }
+        let mut heads: Vec<_> = skip_list_edges
+            .iter()
+            .map(|entry| *entry.key())
+            .filter(|cs_id| !pointed_to.contains(cs_id))
+            .collect();
+        heads.sort();
+        heads
+    }
+
+    /// Returns the ancestors of `heads` (including the heads themselves) that are not
+    /// indexed yet, sorted by changeset id. The walk doesn't go past indexed nodes, as
+    /// their ancestors were indexed together with them.
+    pub async fn missing_coverage(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        heads: Vec<ChangesetId>,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        let mut missing = HashSet::new();
+        let mut to_visit: Vec<_> = heads
+            .into_iter()
+            .filter(|cs_id| !self.is_node_indexed(*cs_id))
+            .collect();
+        while let Some(cs_id) = to_visit.pop() {
+            if !missing.insert(cs_id) {
+                continue;
+            }
+            let parents = get_parents(ctx, changeset_fetcher, cs_id).await?;
+            to_visit.extend(
+                parents
+                    .into_iter()
+                    .filter(|parent| !self.is_node_indexed(*parent) && !missing.contains(parent)),
+            );
+        }
+        let mut missing: Vec<_> = missing.into_iter().collect();
+        missing.sort();
+        Ok(missing)
+    }
+
+    /// Returns the indexed ancestor of `cs_id` with the highest generation number, which is
+    /// `cs_id` itself if it is indexed, or None if none of its ancestors is indexed. The
+    /// ancestors are walked with `ancestors_stream`, so the walk stops at the first indexed
+    /// one.
+    pub async fn nearest_indexed_ancestor(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetId>, Error> {
+        let mut ancestors = Box::pin(self.ancestors_stream(ctx, changeset_fetcher, cs_id));
+        while let Some(ancestor) = ancestors.try_next().await? {
+            if self.is_node_indexed(ancestor) {
+                return Ok(Some(ancestor));
+            }
+        }
+        Ok(None)
     }
 
     pub fn indexed_node_count(&self) -> usize {
-        self.edges().mapping.len()
+        self.edges().len()
+    }
+
+    /// Combine two independently built indexes into one. Nodes present in both
+    /// indexes must have the same entry, except that a list of skip edges may be
+    /// a prefix of the other one (it was computed while fewer ancestors were
+    /// indexed), in which case the longer list is kept.
+    pub fn merge(self, other: SkiplistIndex) -> Result<SkiplistIndex> {
+        let merged = self.edges().to_map();
+        for (cs_id, other_node) in other.edges().to_map().into_iter() {
+            let merged_node = match merged.get(&cs_id).map(|node| node.clone()) {
+                None => other_node,
+                Some(node) if node == other_node => continue,
+                Some(SkiplistNodeType::SkipEdges(ours)) => match other_node {
+                    SkiplistNodeType::SkipEdges(theirs) if theirs.starts_with(&ours) => {
+                        SkiplistNodeType::SkipEdges(theirs)
+                    }
+                    SkiplistNodeType::SkipEdges(theirs) if ours.starts_with(&theirs) => continue,
+                    _ => {
+                        return Err(ErrorKind::ConflictingSkiplistEntries(cs_id.to_string()).into());
+                    }
+                },
+                Some(_) => {
+                    return Err(ErrorKind::ConflictingSkiplistEntries(cs_id.to_string()).into());
+                }
+            };
+            merged.insert(cs_id, merged_node);
+        }
+        Ok(SkiplistIndex {
+            config: self.config,
+            ..SkiplistIndex::from_edges(
+                SkiplistEdgeMapping::from_map(merged).with_skip_edge_count(self.skip_edge_count()),
+            )
+        })
+    }
+
+    /// Write the part of the index reachable from `roots` as a GraphViz DOT graph.
+    /// Every edge is labeled with its level (position in the edge list) and the
+    /// generation span it covers. Meant for debugging only.
+    pub fn to_dot(&self, writer: &mut dyn Write, roots: &[ChangesetId]) -> Result<()> {
+        let skip_list_edges = self.edges();
+        let mut known_gens: HashMap<ChangesetId, Generation> = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut stack: Vec<ChangesetId> = roots.to_vec();
+
+        writeln!(writer, "digraph skiplist {{")?;
+        while let Some(cs_id) = stack.pop() {
+            if !visited.insert(cs_id) {
+                continue;
+            }
+            let node = match skip_list_edges.get(&cs_id) {
+                Some(node) => node.clone(),
+                None => {
+                    writeln!(writer, "  \"{}\" [style=dashed];", cs_id)?;
+                    continue;
+                }
+            };
+            // Unless we reached it through an edge, a node's generation is derived
+            // from its parents.
+            let derived_gen = derived_generation(&node);
+            let (edges, kind) = match node {
+                SkiplistNodeType::SingleEdge(edge) => (vec![edge], "single"),
+                SkiplistNodeType::SkipEdges(edges) => (edges, "skip"),
+                SkiplistNodeType::ParentEdges(edges) => (edges, "parent"),
+            };
+            let gen = known_gens.get(&cs_id).cloned().or(derived_gen);
+            match gen {
+                Some(gen) => writeln!(
+                    writer,
+                    "  \"{}\" [label=\"{}\\ngen {}\"];",
+                    cs_id,
+                    cs_id,
+                    gen.value()
+                )?,
+                None => writeln!(writer, "  \"{}\";", cs_id)?,
+            }
+            for (level, (target, target_gen)) in edges.into_iter().enumerate() {
+                let span = gen
+                    .map(|gen| gen.value().saturating_sub(target_gen.value()).to_string())
+                    .unwrap_or_else(|| "?".to_string());
+                writeln!(
+                    writer,
+                    "  \"{}\" -> \"{}\" [label=\"{} {} +{}\"];",
+                    cs_id, target, kind, level, span
+                )?;
+                known_gens.insert(target, target_gen);
+                stack.push(target);
+            }
+        }
+        writeln!(writer, "}}")?;
+        Ok(())
+    }
+
+    /// Returns the indexed changesets with generation number `gen`, sorted by changeset id.
+    /// The index doesn't store the generation of the nodes themselves, so it is taken from
+    /// the edges pointing to them, or derived from their parents. Nodes with a single edge
+    /// that no other node points to are skipped, as their generation is unknown.
+    pub fn commits_at_generation(&self, gen: Generation) -> Vec<ChangesetId> {
+        let skip_list_edges = self.edges();
+        let mut known_gens = HashMap::new();
+        for entry in skip_list_edges.iter() {
+            match entry.value() {
+                SkiplistNodeType::SingleEdge(edge) => {
+                    known_gens.insert(edge.0, edge.1);
+                }
+                SkiplistNodeType::SkipEdges(edges) | SkiplistNodeType::ParentEdges(edges) => {
+                    known_gens.extend(edges.iter().cloned());
+                }
+            }
+        }
+        let mut commits: Vec<_> = skip_list_edges
+            .iter()
+            .filter(|entry| {
+                let node_gen = known_gens
+                    .get(entry.key())
+                    .cloned()
+                    .or_else(|| derived_generation(entry.value()));
+                node_gen == Some(gen)
+            })
+            .map(|entry| *entry.key())
+            .collect();
+        commits.sort();
+        commits
+    }
+
+    /// Returns the distribution of the highest skip level across indexed nodes: the value at
+    /// index `i` is the number of nodes whose longest skip edge is at level `i`. Nodes with
+    /// parent edges or a single edge only count as level 0.
+    pub fn level_histogram(&self) -> Vec<usize> {
+        let mut histogram = vec![];
+        for entry in self.edges().iter() {
+            let level = match entry.value() {
+                SkiplistNodeType::SkipEdges(edges) => edges.len().saturating_sub(1),
+                SkiplistNodeType::SingleEdge(_) | SkiplistNodeType::ParentEdges(_) => 0,
+            };
+            if histogram.len() <= level {
+                histogram.resize(level + 1, 0);
+            }
+            histogram[level] += 1;
+        }
+        histogram
+    }
+
+    /// Cheaply estimates the number of ancestors of `cs_id`, including `cs_id` itself.
+    /// The estimate starts from the generation number, which is exact for linear history,
+    /// and adds the length of the side branches of the merges found by following the
+    /// furthest skip edges. It is only approximate for merge-heavy histories, as side
+    /// branches are only measured up to their first indexed jump, and for unindexed nodes
+    /// it falls back to the generation number.
+    pub async fn approx_ancestor_count(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        cs_id: ChangesetId,
+    ) -> Result<u64, Error> {
+        let mut count = fetch_generation(ctx, changeset_fetcher, cs_id)
+            .await?
+            .value();
+        let mut visited = HashSet::new();
+        let mut node = cs_id;
+        while visited.insert(node) {
+            let mut edges = match self.get_furthest_edges(node) {
+                Some(edges) => edges,
+                None => break,
+            };
+            // The parent with the highest generation is on the path that the
+            // generation number already counts.
+            edges.sort_by_key(|(_, gen)| *gen);
+            let next = match edges.pop() {
+                Some((next, _)) => next,
+                None => break,
+            };
+            for (side, side_gen) in edges {
+                count += self
+                    .get_furthest_edges(side)
+                    .and_then(|side_edges| side_edges.into_iter().map(|(_, gen)| gen).min())
+                    .map_or(1, |target_gen| {
+                        side_gen.value().saturating_sub(target_gen.value())
+                    });
+            }
+            node = next;
+        }
+        Ok(count)
+    }
+
+    /// Lazily walks the ancestors of `cs_id`, including `cs_id` itself, in descending
+    /// generation number order. Parents of indexed nodes are read from the index, the
+    /// other ones are fetched from `changeset_fetcher`. Only the frontier of the walk is
+    /// kept in memory.
+    pub fn ancestors_stream<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        changeset_fetcher: &'a ArcChangesetFetcher,
+        cs_id: ChangesetId,
+    ) -> impl Stream<Item = Result<ChangesetId, Error>> + 'a {
+        stream::try_unfold(None, move |frontier: Option<BinaryHeap<_>>| async move {
+            let mut frontier = match frontier {
+                Some(frontier) => frontier,
+                None => {
+                    let gen = fetch_generation(ctx, changeset_fetcher, cs_id).await?;
+                    BinaryHeap::from(vec![(gen, cs_id)])
+                }
+            };
+            let (gen, cs_id) = match frontier.pop() {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            // A changeset is pushed once by each of its children. They all have higher
+            // generation numbers and were visited already, so the copies are on top.
+            while frontier.peek() == Some(&(gen, cs_id)) {
+                frontier.pop();
+            }
+            let parents = self
+                .parents_with_generations(ctx, changeset_fetcher, (cs_id, gen))
+                .await?;
+            frontier.extend(parents.into_iter().map(|(parent, gen)| (gen, parent)));
+            Ok::<_, Error>(Some((cs_id, Some(frontier))))
+        })
+    }
+
+    async fn parents_with_generations(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        (cs_id, gen): (ChangesetId, Generation),
+    ) -> Result<Vec<(ChangesetId, Generation)>, Error> {
+        if let Some(node) = self.edges().get(&cs_id) {
+            match &*node {
+                SkiplistNodeType::ParentEdges(parents) => return Ok(parents.clone()),
+                // The shortest skip edge of a single parent changeset leads to its
+                // parent, unless the edges were trimmed.
+                SkiplistNodeType::SkipEdges(edges) => {
+                    if let Some((parent, parent_gen)) = edges.first() {
+                        if parent_gen.value() + 1 == gen.value() {
+                            return Ok(vec![(*parent, *parent_gen)]);
+                        }
+                    }
+                }
+                SkiplistNodeType::SingleEdge(_) => {}
+            }
+        }
+        let parents = get_parents(ctx, changeset_fetcher, cs_id).await?;
+        changesets_with_generation_numbers(ctx, changeset_fetcher, parents).await
+    }
+
+    /// Checks every indexed node against the commit graph: parent edges must match the
+    /// parents of the changeset, and every other edge must lead to an ancestor with the
+    /// recorded generation number. Fails with the first inconsistent node in changeset
+    /// id order. The graph is walked without the index, so this is slow for large indexes.
+    pub async fn validate(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+    ) -> Result<(), Error> {
+        let mut nodes: Vec<_> = self.get_all_skip_edges().into_iter().collect();
+        nodes.sort_by_key(|(cs_id, _)| *cs_id);
+        for (cs_id, node) in nodes {
+            if let Some(reason) = find_invalid_edge(ctx, changeset_fetcher, cs_id, node).await? {
+                return Err(ErrorKind::InvalidSkiplistEntry {
+                    commit: cs_id.to_string(),
+                    reason,
+                }
+                .into());
+            }
+        }
+        Ok(())
     }
 
     // Remove all but latest skip entry (i.e. entry with the longest jump) to save space.
     pub fn trim_to_single_entry_per_changeset(&self) {
         let skip_list_edges = self.edges();
-        for (cs_id, old_node) in skip_list_edges.mapping.clone().into_iter() {
+        for (cs_id, old_node) in skip_list_edges.to_map().into_iter() {
             let new_node = if let SkiplistNodeType::SkipEdges(skip_edges) = old_node {
                 SkiplistNodeType::SkipEdges(skip_edges.last().cloned().into_iter().collect())
             } else {
                 old_node
             };
-            let _old_node = skip_list_edges.mapping.insert(cs_id, new_node);
+            skip_list_edges.insert(cs_id, new_node);
         }
     }
 }
@@ -643,6 +1170,38 @@ impl ReachabilityIndex for SkiplistIndex {
         desc_hash: ChangesetId,
         anc_hash: ChangesetId,
     ) -> Result<bool, Error> {
+        let (anc_gen, desc_gen) = try_join!(
+            changeset_fetcher.get_generation_number(ctx, anc_hash),
+            changeset_fetcher.get_generation_number(ctx, desc_hash),
+        )?;
+        // Ancestors always have a lower generation number, so there is no need to
+        // walk the graph.
+        if anc_gen > desc_gen {
+            return Ok(false);
+        }
+        self.query_reachability_from_gens(
+            ctx,
+            changeset_fetcher,
+            (desc_hash, desc_gen),
+            (anc_hash, anc_gen),
+            None,
+        )
+        .await
+    }
+
+    /// Checks for cancellation between the steps of the walk, so partially advanced
+    /// frontiers are dropped as soon as `cancellation` is triggered.
+    async fn query_reachability_with_cancellation(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        desc_hash: ChangesetId,
+        anc_hash: ChangesetId,
+        cancellation: &CancellationToken,
+    ) -> Result<bool, Error> {
+        if cancellation.is_cancelled() {
+            return Err(ErrorKind::Cancelled.into());
+        }
         let (anc_gen, desc_gen) = try_join!(
             changeset_fetcher.get_generation_number(ctx, anc_hash),
             changeset_fetcher.get_generation_number(ctx, desc_hash),
@@ -650,19 +1209,43 @@ impl ReachabilityIndex for SkiplistIndex {
         if anc_gen > desc_gen {
             return Ok(false);
         }
+        self.query_reachability_from_gens(
+            ctx,
+            changeset_fetcher,
+            (desc_hash, desc_gen),
+            (anc_hash, anc_gen),
+            Some(cancellation),
+        )
+        .await
+    }
+}
+
+impl SkiplistIndex {
+    async fn query_reachability_from_gens(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        (desc_hash, desc_gen): (ChangesetId, Generation),
+        (anc_hash, anc_gen): (ChangesetId, Generation),
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<bool, Error> {
         ctx.perf_counters()
             .set_counter(PerfCounterType::SkiplistAncestorGen, anc_gen.value() as i64);
         ctx.perf_counters().set_counter(
             PerfCounterType::SkiplistDescendantGen,
             desc_gen.value() as i64,
         );
-        let frontier = process_frontier(
+        let frontier = process_frontier_with_cancellation(
             ctx,
             changeset_fetcher,
             &self.skip_list_edges.load(),
             NodeFrontier::new(hashmap! {desc_gen => hashset!{desc_hash}}),
             anc_gen,
             &None,
+            QueryLimits {
+                cancellation,
+                max_parent_fetches: self.config.max_parent_fetches_per_query,
+            },
         )
         .await?;
         match frontier.get_all_changesets_for_gen_num(anc_gen) {
@@ -711,7 +1294,7 @@ fn move_skippable_nodes(
     let mut node_frontier = NodeFrontier::default();
 
     for cs_id in all_cs_ids {
-        if let Some(read_locked_entry) = skip_edges.mapping.get(&cs_id) {
+        if let Some(read_locked_entry) = skip_edges.get(&cs_id) {
             match &*read_locked_entry {
                 SkiplistNodeType::SingleEdge(edge_pair) => {
                     if edge_pair.1 >= gen {
@@ -755,12 +1338,13 @@ fn move_skippable_nodes(
     (no_skiplist_edges, node_frontier)
 }
 
-// Take all changesets in `cs_ids` and returns a list of their parents with
-// their generations.
+// Take all changesets in `cs_ids` (all of generation `gen`) and returns a list of
+// their parents with their generations.
 async fn move_nonskippable_nodes(
     ctx: &CoreContext,
     changeset_fetcher: &ArcChangesetFetcher,
     cs_ids: Vec<ChangesetId>,
+    gen: Generation,
     trace: &Option<&SkiplistTraversalTrace>,
 ) -> Result<Vec<(ChangesetId, Generation)>, Error> {
     let changeset_parent_gen = cs_ids
@@ -771,8 +1355,9 @@ async fn move_nonskippable_nodes(
         .collect::<FuturesUnordered<_>>()
         .and_then(|(cs_id, parents)| async move {
             let parent_gens = try_join_all(parents.into_iter().map(|p| async move {
-                let gen = fetch_generation(ctx, changeset_fetcher, p).await?;
-                Ok::<_, Error>((p, gen))
+                let parent_gen = fetch_generation(ctx, changeset_fetcher, p).await?;
+                check_parent_generation((cs_id, gen), parent_gen)?;
+                Ok::<_, Error>((p, parent_gen))
             }))
             .await?;
             Ok((cs_id, parent_gens))
@@ -809,6 +1394,7 @@ async fn process_frontier_single_skip(
     mut node_frontier: NodeFrontier,
     target_gen: Generation,
     trace: &Option<&SkiplistTraversalTrace>,
+    parent_fetches: &mut ParentFetches,
 ) -> Result<(NodeFrontier, u64), Error> {
     let old_max_gen = if let Some(val) = node_frontier.max_gen() {
         if val <= target_gen {
@@ -842,9 +1428,16 @@ async fn process_frontier_single_skip(
         }
     }
 
-    let gen_cs = move_nonskippable_nodes(ctx, changeset_fetcher, no_skiplist_edges, trace)
-        .await?
-        .into_iter();
+    parent_fetches.add(no_skiplist_edges.len())?;
+    let gen_cs = move_nonskippable_nodes(
+        ctx,
+        changeset_fetcher,
+        no_skiplist_edges,
+        old_max_gen,
+        trace,
+    )
+    .await?
+    .into_iter();
 
     node_frontier.extend(gen_cs);
 
@@ -870,23 +1463,84 @@ async fn process_frontier(
     max_gen: Generation,
     trace: &Option<&SkiplistTraversalTrace>,
 ) -> Result<NodeFrontier, Error> {
-    let max_skips_without_yield = tunables::tunables().get_skiplist_max_skips_without_yield();
-    let mut skips_without_yield = 0;
-    let mut node_frontier = node_frontier;
+    process_frontier_with_cancellation(
+        ctx,
+        changeset_fetcher,
+        skip_edges,
+        node_frontier,
+        max_gen,
+        trace,
+        QueryLimits::default(),
+    )
+    .await
+}
 
-    loop {
-        let (new_node_frontier, step_size) = process_frontier_single_skip(
-            ctx,
-            changeset_fetcher,
-            skip_edges,
-            node_frontier,
-            max_gen,
-            trace,
-        )
-        .await?;
-        node_frontier = new_node_frontier;
-        if step_size == 0 {
-            break;
+/// Limits on the work done by a single walk of the graph.
+#[derive(Default)]
+struct QueryLimits<'a> {
+    /// The walk fails with `ErrorKind::Cancelled` once this is triggered.
+    cancellation: Option<&'a CancellationToken>,
+    /// The walk fails with `ErrorKind::QueryTooExpensive` instead of fetching the parents
+    /// of more changesets than this.
+    max_parent_fetches: Option<NonZeroUsize>,
+}
+
+/// Counts the changesets whose parents were fetched during a walk.
+struct ParentFetches {
+    count: usize,
+    max: Option<NonZeroUsize>,
+}
+
+impl ParentFetches {
+    fn add(&mut self, count: usize) -> Result<(), Error> {
+        self.count += count;
+        match self.max {
+            Some(max) if self.count > max.get() => {
+                Err(ErrorKind::QueryTooExpensive(max.get()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Same as `process_frontier`, but checks the `limits` before every step.
+async fn process_frontier_with_cancellation(
+    ctx: &CoreContext,
+    changeset_fetcher: &ArcChangesetFetcher,
+    skip_edges: &Arc<SkiplistEdgeMapping>,
+    node_frontier: NodeFrontier,
+    max_gen: Generation,
+    trace: &Option<&SkiplistTraversalTrace>,
+    limits: QueryLimits<'_>,
+) -> Result<NodeFrontier, Error> {
+    let max_skips_without_yield = tunables::tunables().get_skiplist_max_skips_without_yield();
+    let mut skips_without_yield = 0;
+    let mut parent_fetches = ParentFetches {
+        count: 0,
+        max: limits.max_parent_fetches,
+    };
+    let mut node_frontier = node_frontier;
+
+    loop {
+        if limits
+            .cancellation
+            .map_or(false, |token| token.is_cancelled())
+        {
+            return Err(ErrorKind::Cancelled.into());
+        }
+        let (new_node_frontier, step_size) = process_frontier_single_skip(
+            ctx,
+            changeset_fetcher,
+            skip_edges,
+            node_frontier,
+            max_gen,
+            trace,
+            &mut parent_fetches,
+        )
+        .await?;
+        node_frontier = new_node_frontier;
+        if step_size == 0 {
+            break;
         }
         if let Some(val) = node_frontier.max_gen() {
             if val <= max_gen {
@@ -902,6 +1556,14 @@ async fn process_frontier(
     Ok(node_frontier)
 }
 
+fn lca_key(node1: ChangesetId, node2: ChangesetId) -> (ChangesetId, ChangesetId) {
+    if node1 <= node2 {
+        (node1, node2)
+    } else {
+        (node2, node1)
+    }
+}
+
 #[async_trait]
 impl LeastCommonAncestorsHint for SkiplistIndex {
     async fn lca_hint(
@@ -966,6 +1628,48 @@ impl SkiplistIndex {
         node1: ChangesetId,
         node2: ChangesetId,
     ) -> Result<Vec<ChangesetId>, Error> {
+        if let Some(lca) = self.get_precomputed_lca(node1, node2) {
+            return Ok(lca);
+        }
+        let lca = self
+            .lca_with_generation(ctx, changeset_fetcher, node1, node2)
+            .await?;
+        Ok(lca.map_or_else(Vec::new, |(_, lca)| lca))
+    }
+
+    /// Return the generation number of the lowest common ancestors of two changesets,
+    /// or None if they have no common ancestors. Unlike `lca`, it doesn't need to fetch
+    /// anything when the answer was precomputed.
+    pub async fn lca_generation(
+        &self,
+        ctx: CoreContext,
+        changeset_fetcher: ArcChangesetFetcher,
+        node1: ChangesetId,
+        node2: ChangesetId,
+    ) -> Result<Option<Generation>, Error> {
+        if let Some(lca) = self.get_precomputed_lca(node1, node2) {
+            return match lca.first() {
+                Some(cs_id) => Ok(Some(
+                    fetch_generation(&ctx, &changeset_fetcher, *cs_id).await?,
+                )),
+                None => Ok(None),
+            };
+        }
+        let lca = self
+            .lca_with_generation(ctx, changeset_fetcher, node1, node2)
+            .await?;
+        Ok(lca.map(|(gen, _)| gen))
+    }
+
+    /// Return the lowest common ancestors of two changesets, sorted, together with
+    /// their generation number.
+    async fn lca_with_generation(
+        &self,
+        ctx: CoreContext,
+        changeset_fetcher: ArcChangesetFetcher,
+        node1: ChangesetId,
+        node2: ChangesetId,
+    ) -> Result<Option<(Generation, Vec<ChangesetId>)>, Error> {
         // When using skiplists we'll be only using the maximum skip size as in
         // practice that's the only skip that's present.
         let skip_step_size: u64 = 1 << (self.edges().skip_edges_per_node - 1);
@@ -1045,7 +1749,7 @@ impl SkiplistIndex {
         let ca_gen = match ca_gen {
             Some(ca_gen) => ca_gen,
             None => {
-                return Ok(vec![]);
+                return Ok(None);
             }
         };
 
@@ -1060,10 +1764,10 @@ impl SkiplistIndex {
                 .process_frontiers(&ctx, &changeset_fetcher, &frontier1, &frontier2, gen)
                 .await?;
             let mut intersection = candidate_frontier1.intersection(&candidate_frontier2);
-            if let Some((_, lca)) = intersection.remove_max_gen() {
+            if let Some((lca_gen, lca)) = intersection.remove_max_gen() {
                 let mut lca: Vec<_> = lca.into_iter().collect();
                 lca.sort();
-                return Ok(lca);
+                return Ok(Some((lca_gen, lca)));
             } else {
                 frontier1 = candidate_frontier1;
                 frontier2 = candidate_frontier2;
@@ -1075,6 +1779,266 @@ impl SkiplistIndex {
         Err(ErrorKind::ProgrammingError("impossible state during LCA computation").into())
     }
 
+    /// Check whether any node of `ancestors` is reachable from any node of `descendants`
+    /// (a node is reachable from itself). Returns the witnessing pair as
+    /// `(ancestor, descendant)`, or None if there is no such pair.
+    pub async fn query_frontier_reachability(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        descendants: &NodeFrontier,
+        ancestors: &NodeFrontier,
+    ) -> Result<Option<(ChangesetId, ChangesetId)>, Error> {
+        if descendants.is_empty() {
+            return Ok(None);
+        }
+        let skip_list_edges = self.skip_list_edges.load();
+        let mut ancestors = ancestors.clone();
+        let mut frontier = descendants.clone();
+        // Walk the descendants down one ancestor generation at a time. Any ancestor
+        // with that generation that is reachable must be a part of the walked frontier.
+        while let Some((gen, candidates)) = ancestors.remove_max_gen() {
+            frontier = process_frontier(
+                ctx,
+                changeset_fetcher,
+                &skip_list_edges,
+                frontier,
+                gen,
+                &None,
+            )
+            .await?;
+            let reached = match frontier.get_all_changesets_for_gen_num(gen) {
+                Some(reached) => reached,
+                None => continue,
+            };
+            let mut candidates: Vec<_> = candidates.intersection(reached).cloned().collect();
+            candidates.sort();
+            if let Some(ancestor) = candidates.into_iter().next() {
+                let mut possible_descendants: Vec<_> = descendants
+                    .iter()
+                    .filter(|(_, desc_gen)| *desc_gen >= gen)
+                    .map(|(cs_id, _)| *cs_id)
+                    .collect();
+                possible_descendants.sort();
+                for descendant in possible_descendants {
+                    if self
+                        .query_reachability(ctx, changeset_fetcher, descendant, ancestor)
+                        .await?
+                    {
+                        return Ok(Some((ancestor, descendant)));
+                    }
+                }
+                return Err(ErrorKind::ProgrammingError(
+                    "reachable ancestor without a matching descendant",
+                )
+                .into());
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check whether any of `ancestors` is reachable from `descendant` (a node is reachable
+    /// from itself). The graph is walked once for all of them, stopping at the first
+    /// ancestor that is reached.
+    pub async fn query_reachability_any(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        descendant: ChangesetId,
+        ancestors: &[ChangesetId],
+    ) -> Result<bool, Error> {
+        let desc_gen = fetch_generation(ctx, changeset_fetcher, descendant).await?;
+        let mut ancestors: NodeFrontier = fetch_generations_batched(
+            ctx,
+            changeset_fetcher,
+            ancestors.to_vec(),
+            self.config.fetch_batch_size.get(),
+        )
+        .await?
+        .into_iter()
+        // Ancestors with higher generation numbers can't be reached.
+        .filter(|(_, gen)| *gen <= desc_gen)
+        .collect();
+        let skip_list_edges = self.skip_list_edges.load();
+        let mut frontier = NodeFrontier::new_single(descendant, desc_gen);
+        while let Some((gen, candidates)) = ancestors.remove_max_gen() {
+            frontier = process_frontier(
+                ctx,
+                changeset_fetcher,
+                &skip_list_edges,
+                frontier,
+                gen,
+                &None,
+            )
+            .await?;
+            if let Some(reached) = frontier.get_all_changesets_for_gen_num(gen) {
+                if !reached.is_disjoint(&candidates) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Same as `query_reachability`, but only considers the paths made of changesets with
+    /// generations in `[lo, hi]`. Every changeset on a path from `desc_hash` to `anc_hash` has
+    /// a generation between theirs, so either both ends are in the window and so is the whole
+    /// walk, or there is nothing to walk.
+    pub async fn query_reachability_in_window(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        desc_hash: ChangesetId,
+        anc_hash: ChangesetId,
+        lo: Generation,
+        hi: Generation,
+    ) -> Result<bool, Error> {
+        let (anc_gen, desc_gen) = try_join!(
+            changeset_fetcher.get_generation_number(ctx, anc_hash),
+            changeset_fetcher.get_generation_number(ctx, desc_hash),
+        )?;
+        if anc_gen < lo || desc_gen > hi || anc_gen > desc_gen {
+            return Ok(false);
+        }
+        self.query_reachability_from_gens(
+            ctx,
+            changeset_fetcher,
+            (desc_hash, desc_gen),
+            (anc_hash, anc_gen),
+            None,
+        )
+        .await
+    }
+
+    /// Same as `query_reachability`, but if `anc_hash` is reachable from `desc_hash` returns
+    /// a path of changesets starting with `desc_hash` and ending with `anc_hash`, in which
+    /// every changeset is a parent of the previous one. Returns None if it's not reachable.
+    pub async fn query_reachability_with_path(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        desc_hash: ChangesetId,
+        anc_hash: ChangesetId,
+    ) -> Result<Option<Vec<ChangesetId>>, Error> {
+        let (anc_gen, desc_gen) = try_join!(
+            changeset_fetcher.get_generation_number(ctx, anc_hash),
+            changeset_fetcher.get_generation_number(ctx, desc_hash),
+        )?;
+        if anc_gen > desc_gen {
+            return Ok(None);
+        }
+        let trace = SkiplistTraversalTrace::new();
+        let frontier = process_frontier(
+            ctx,
+            changeset_fetcher,
+            &self.skip_list_edges.load(),
+            NodeFrontier::new(hashmap! {desc_gen => hashset!{desc_hash}}),
+            anc_gen,
+            &Some(&trace),
+        )
+        .await?;
+        match frontier.get_all_changesets_for_gen_num(anc_gen) {
+            Some(cs_ids) if cs_ids.contains(&anc_hash) => {}
+            _ => return Ok(None),
+        }
+
+        // Walk the traversal trace from the ancestor towards the descendant, remembering
+        // for each visited changeset which edge led to it.
+        let mut edge_to: HashMap<ChangesetId, ChangesetId> = HashMap::new();
+        let mut stack = vec![anc_hash];
+        while let Some(cs_id) = stack.pop() {
+            if cs_id == desc_hash {
+                break;
+            }
+            if let Some(entries) = trace.inner().get(&cs_id) {
+                for (child, _is_merge) in entries.iter() {
+                    if *child != anc_hash && !edge_to.contains_key(child) {
+                        edge_to.insert(*child, cs_id);
+                        stack.push(*child);
+                    }
+                }
+            }
+        }
+
+        // Follow the edges back from the descendant. Skip edges only span changesets with
+        // a single parent, so they are expanded by walking the parents.
+        let mut path = vec![desc_hash];
+        let mut curr = desc_hash;
+        while curr != anc_hash {
+            let edge_end = *edge_to
+                .get(&curr)
+                .ok_or(ErrorKind::ProgrammingError("incomplete traversal trace"))?;
+            loop {
+                let parents = get_parents(ctx, changeset_fetcher, curr).await?;
+                if parents.contains(&edge_end) {
+                    break;
+                }
+                match parents.as_slice() {
+                    [parent] => {
+                        path.push(*parent);
+                        curr = *parent;
+                    }
+                    _ => {
+                        return Err(ErrorKind::ProgrammingError(
+                            "skip edge spans a changeset without a single parent",
+                        )
+                        .into());
+                    }
+                }
+                if fetch_generation(ctx, changeset_fetcher, curr).await? <= anc_gen {
+                    return Err(
+                        ErrorKind::ProgrammingError("skip edge goes past its target").into(),
+                    );
+                }
+            }
+            path.push(edge_end);
+            curr = edge_end;
+        }
+        Ok(Some(path))
+    }
+
+    /// Compute lowest common ancestors of all pairs of `nodes` and store them, so that
+    /// `lca` queries among them become lookups.
+    pub async fn precompute_lca(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        nodes: &[ChangesetId],
+    ) -> Result<(), Error> {
+        let mut pairs = vec![];
+        for (i, node1) in nodes.iter().enumerate() {
+            for node2 in &nodes[i + 1..] {
+                pairs.push(lca_key(*node1, *node2));
+            }
+        }
+        let lcas: Vec<_> = stream::iter(pairs)
+            .map(|(node1, node2)| async move {
+                let lca = self
+                    .lca(ctx.clone(), changeset_fetcher.clone(), node1, node2)
+                    .await?;
+                Ok::<_, Error>(((node1, node2), lca))
+            })
+            .buffer_unordered(self.config.query_concurrency.get())
+            .try_collect()
+            .await?;
+        for (key, lca) in lcas {
+            self.precomputed_lcas.insert(key, lca);
+        }
+        Ok(())
+    }
+
+    /// Returns lowest common ancestors of two changesets if they were precomputed
+    /// by `precompute_lca`.
+    pub fn get_precomputed_lca(
+        &self,
+        node1: ChangesetId,
+        node2: ChangesetId,
+    ) -> Option<Vec<ChangesetId>> {
+        self.precomputed_lcas
+            .get(&lca_key(node1, node2))
+            .map(|lca| lca.clone())
+    }
+
     /// Find all merge commits on the path between two nodes.
     /// where there might be more than one such ancestor, this function is guaranteed to
     /// return all the common ancestors with highest generation number.
@@ -1136,6 +2100,7 @@ mod test {
     use std::sync::atomic::AtomicUsize;
     use std::sync::atomic::Ordering;
     use std::sync::Arc;
+    use std::sync::Mutex;
 
     use async_trait::async_trait;
     use blobrepo::BlobRepo;
@@ -1157,6 +2122,7 @@ mod test {
     use fixtures::TestRepoFixture;
     use fixtures::UnsharedMergeEven;
     use futures::compat::Future01CompatExt;
+    use futures::compat::Stream01CompatExt;
     use futures::stream::iter;
     use futures::stream::StreamExt;
     use futures::stream::TryStreamExt;
@@ -1167,13 +2133,20 @@ mod test {
     use futures_old::stream::Stream;
     use futures_util::future::FutureExt;
     use futures_util::future::TryFutureExt;
+    use quickcheck::quickcheck;
+    use quickcheck::Arbitrary;
+    use quickcheck::Gen;
     use revset::AncestorsNodeStream;
     use test_helpers::string_to_bonsai;
     use test_helpers::test_branch_wide_reachability;
+    use test_helpers::test_linear_heads_of_set;
     use test_helpers::test_linear_reachability;
+    use test_helpers::test_merge_uneven_heads_of_set;
     use test_helpers::test_merge_uneven_reachability;
+    use test_helpers::test_merge_uneven_roots_of_set;
 
     use super::*;
+    use crate::edge_mapping::estimated_node_size;
 
     #[tokio::test]
     async fn simple_init() {
@@ -1219,6 +2192,42 @@ mod test {
         }
     }
 
+    #[fbinit::test]
+    async fn test_add_node_with_progress(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Arc::new(Linear::getrepo(fb).await);
+        let master_node =
+            string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+
+        for report_every in [1, 3] {
+            let sli = SkiplistIndex::new();
+            let reported = Arc::new(Mutex::new(vec![]));
+            let progress = BuildProgress::new(
+                NonZeroUsize::new(report_every).unwrap(),
+                Arc::new({
+                    let reported = reported.clone();
+                    move |indexed| reported.lock().unwrap().push(indexed)
+                }),
+            );
+            sli.add_node_with_progress(
+                &ctx,
+                &repo.changeset_fetcher_arc(),
+                master_node,
+                100,
+                progress,
+            )
+            .await
+            .unwrap();
+
+            // Progress is reported along the way, not only once the build is over.
+            let expected: Vec<_> = (1..=sli.indexed_node_count())
+                .filter(|indexed| indexed % report_every == 0)
+                .collect();
+            assert!(expected.len() > 1);
+            assert_eq!(*reported.lock().unwrap(), expected);
+        }
+    }
+
     #[fbinit::test]
     async fn test_skip_edges_reach_end_in_linear(fb: FacebookInit) {
         let ctx = CoreContext::test_mock(fb);
@@ -1473,6 +2482,24 @@ mod test {
         test_merge_uneven_reachability(fb, sli_constructor).await;
     }
 
+    #[fbinit::test]
+    async fn linear_heads_of_set(fb: FacebookInit) {
+        let sli_constructor = SkiplistIndex::new;
+        test_linear_heads_of_set(fb, sli_constructor).await;
+    }
+
+    #[fbinit::test]
+    async fn merge_uneven_heads_of_set(fb: FacebookInit) {
+        let sli_constructor = SkiplistIndex::new;
+        test_merge_uneven_heads_of_set(fb, sli_constructor).await;
+    }
+
+    #[fbinit::test]
+    async fn merge_uneven_roots_of_set(fb: FacebookInit) {
+        let sli_constructor = SkiplistIndex::new;
+        test_merge_uneven_roots_of_set(fb, sli_constructor).await;
+    }
+
     #[fbinit::test]
     async fn branch_wide_reachability(fb: FacebookInit) {
         let sli_constructor = SkiplistIndex::new;
@@ -1520,6 +2547,98 @@ mod test {
         }
     }
 
+    #[fbinit::test]
+    async fn test_query_reachability_impossible_by_generation(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let sli = SkiplistIndex::new();
+        let get_parents_count = Arc::new(AtomicUsize::new(0));
+        let get_gen_number_count = Arc::new(AtomicUsize::new(0));
+        let cs_fetcher: ArcChangesetFetcher = Arc::new(CountingChangesetFetcher::new(
+            repo.changeset_fetcher_arc(),
+            get_parents_count.clone(),
+            get_gen_number_count.clone(),
+        ));
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let root = string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+
+        assert!(
+            !sli.query_reachability(&ctx, &cs_fetcher, root, head)
+                .await
+                .unwrap()
+        );
+        assert_eq!(get_parents_count.load(Ordering::Relaxed), 0);
+        // Only the generation numbers of the two changesets were fetched.
+        assert_eq!(get_gen_number_count.load(Ordering::Relaxed), 2);
+        assert_eq!(sli.indexed_node_count(), 0);
+    }
+
+    #[fbinit::test]
+    async fn test_ancestors_stream(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = MergeUneven::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let sli = SkiplistIndex::new();
+        let merge = string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        // Only part of the graph is indexed, so both index and fetcher are used.
+        sli.add_node(&ctx, &fetcher, branch_1_head, 100)
+            .await
+            .unwrap();
+
+        let ancestors: Vec<_> = sli
+            .ancestors_stream(&ctx, &fetcher, merge)
+            .try_collect()
+            .await
+            .unwrap();
+        let expected: HashSet<_> = AncestorsNodeStream::new(ctx.clone(), &fetcher, merge)
+            .compat()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(ancestors.len(), expected.len());
+        assert_eq!(ancestors.iter().cloned().collect::<HashSet<_>>(), expected);
+        assert_eq!(ancestors[0], merge);
+
+        let gens = changesets_with_generation_numbers(&ctx, &fetcher, ancestors)
+            .await
+            .unwrap();
+        assert!(gens.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+
+    #[fbinit::test]
+    async fn test_validate(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = MergeUneven::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let sli = SkiplistIndex::new();
+        let merge = string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        sli.add_node(&ctx, &fetcher, merge, 100).await.unwrap();
+        sli.validate(&ctx, &fetcher).await.unwrap();
+
+        // Point the head of the first branch at itself.
+        let head_gen = fetcher
+            .get_generation_number(&ctx, branch_1_head)
+            .await
+            .unwrap();
+        let graph: DashMap<_, _> = sli.get_all_skip_edges().into_iter().collect();
+        graph.insert(
+            branch_1_head,
+            SkiplistNodeType::SkipEdges(vec![(branch_1_head, head_gen)]),
+        );
+        let corrupted = SkiplistIndex::new_with_skiplist_graph(graph);
+        let err = corrupted.validate(&ctx, &fetcher).await.unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::InvalidSkiplistEntry { commit, .. }) => {
+                assert_eq!(commit, &branch_1_head.to_string())
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+    }
+
     async fn query_reachability_hint_on_self_is_true(
         ctx: CoreContext,
         repo: BlobRepo,
@@ -1672,10 +2791,1093 @@ mod test {
         );
     }
 
-    async fn query_from_indexed_merge_node(ctx: CoreContext, repo: BlobRepo, sli: SkiplistIndex) {
-        let merge_node =
-            string_to_bonsai(&ctx, &repo, "d592490c4386cdb3373dd93af04d563de199b2fb").await;
-        let commit_after_merge =
+    struct CancellingChangesetFetcher {
+        cancel_after_parents: usize,
+        get_parents_count: AtomicUsize,
+        cancellation: CancellationToken,
+        cs_fetcher: ArcChangesetFetcher,
+    }
+
+    #[async_trait]
+    impl ChangesetFetcher for CancellingChangesetFetcher {
+        async fn get_generation_number(
+            &self,
+            ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Generation, Error> {
+            self.cs_fetcher.get_generation_number(ctx, cs_id).await
+        }
+
+        async fn get_parents(
+            &self,
+            ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Vec<ChangesetId>, Error> {
+            if self.get_parents_count.fetch_add(1, Ordering::Relaxed) + 1
+                >= self.cancel_after_parents
+            {
+                self.cancellation.cancel();
+            }
+            self.cs_fetcher.get_parents(ctx, cs_id).await
+        }
+    }
+
+    /// Changeset fetcher for a graph where `head` is a merge of `width` commits, which all
+    /// have `root` as their only parent.
+    struct WideMergeChangesetFetcher {
+        head: ChangesetId,
+        middle: Vec<ChangesetId>,
+        root: ChangesetId,
+    }
+
+    impl WideMergeChangesetFetcher {
+        fn new(width: u8) -> Self {
+            let cs_id = |byte| ChangesetId::from_bytes([byte; 32]).unwrap();
+            Self {
+                head: cs_id(0),
+                middle: (1..=width).map(cs_id).collect(),
+                root: cs_id(u8::MAX),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ChangesetFetcher for WideMergeChangesetFetcher {
+        async fn get_generation_number(
+            &self,
+            _ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Generation, Error> {
+            let gen = if cs_id == self.head {
+                3
+            } else if cs_id == self.root {
+                1
+            } else {
+                2
+            };
+            Ok(Generation::new(gen))
+        }
+
+        async fn get_parents(
+            &self,
+            _ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Vec<ChangesetId>, Error> {
+            if cs_id == self.head {
+                Ok(self.middle.clone())
+            } else if cs_id == self.root {
+                Ok(vec![])
+            } else {
+                Ok(vec![self.root])
+            }
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_query_reachability_too_expensive(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let fetcher = WideMergeChangesetFetcher::new(10);
+        let (head, root) = (fetcher.head, fetcher.root);
+        let fetcher: ArcChangesetFetcher = Arc::new(fetcher);
+
+        let sli = SkiplistIndex::new();
+        assert!(
+            sli.query_reachability(&ctx, &fetcher, head, root)
+                .await
+                .unwrap()
+        );
+
+        let sli = SkiplistIndex::with_config(ReachabilityConfig {
+            max_parent_fetches_per_query: NonZeroUsize::new(5),
+            ..ReachabilityConfig::default()
+        });
+        let err = sli
+            .query_reachability(&ctx, &fetcher, head, root)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::QueryTooExpensive(5))
+        ));
+    }
+
+    #[fbinit::test]
+    async fn test_query_reachability_in_window(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let cs_fetcher = repo.changeset_fetcher_arc();
+        let sli = SkiplistIndex::new();
+
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let middle =
+            string_to_bonsai(&ctx, &repo, "cb15ca4a43a59acff5388cea9648c162afde8372").await;
+        let root = string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+        let head_gen = cs_fetcher.get_generation_number(&ctx, head).await.unwrap();
+        let middle_gen = cs_fetcher
+            .get_generation_number(&ctx, middle)
+            .await
+            .unwrap();
+
+        let in_window = |desc, anc, lo, hi| {
+            sli.query_reachability_in_window(&ctx, &cs_fetcher, desc, anc, lo, hi)
+        };
+        assert!(in_window(head, middle, middle_gen, head_gen).await.unwrap());
+        // The root is reachable, but the path to it goes below the window.
+        assert!(
+            sli.query_reachability(&ctx, &cs_fetcher, head, root)
+                .await
+                .unwrap()
+        );
+        assert!(!in_window(head, root, middle_gen, head_gen).await.unwrap());
+        assert!(
+            !in_window(head, root, Generation::new(1), middle_gen)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_query_reachability_cancelled_mid_walk(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let sli = SkiplistIndex::new();
+        let cancellation = CancellationToken::new();
+        let cs_fetcher: ArcChangesetFetcher = Arc::new(CancellingChangesetFetcher {
+            cancel_after_parents: 2,
+            get_parents_count: AtomicUsize::new(0),
+            cancellation: cancellation.clone(),
+            cs_fetcher: repo.changeset_fetcher_arc(),
+        });
+
+        let src_node =
+            string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let dst_node =
+            string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+
+        let err = sli
+            .query_reachability_with_cancellation(
+                &ctx,
+                &cs_fetcher,
+                src_node,
+                dst_node,
+                &cancellation,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::Cancelled)
+        ));
+
+        // Without cancellation the same query succeeds.
+        assert!(
+            sli.query_reachability_with_cancellation(
+                &ctx,
+                &repo.changeset_fetcher_arc(),
+                src_node,
+                dst_node,
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap()
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_to_dot_linear(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let sli = SkiplistIndex::new();
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        sli.add_node(&ctx, &repo.changeset_fetcher_arc(), head, 100)
+            .await
+            .unwrap();
+
+        let mut out = vec![];
+        sli.to_dot(&mut out, &[head]).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        assert!(dot.starts_with("digraph skiplist {"));
+        assert!(dot.trim_end().ends_with('}'));
+        assert!(dot.contains(&format!("\"{}\" ->", head)));
+        assert!(dot.contains("skip 0 +1"));
+
+        // Roots that are not indexed are still emitted, but without edges.
+        let mut out = vec![];
+        SkiplistIndex::new().to_dot(&mut out, &[head]).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        assert!(dot.contains("style=dashed"));
+        assert!(!dot.contains("->"));
+    }
+
+    #[fbinit::test]
+    async fn test_merge_indexes(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = MergeUneven::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        let branch_2_head =
+            string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+        let merge_node =
+            string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+
+        let sli_1 = SkiplistIndex::new();
+        sli_1
+            .add_node(&ctx, &fetcher, branch_1_head, 100)
+            .await
+            .unwrap();
+        let sli_2 = SkiplistIndex::new();
+        sli_2
+            .add_node(&ctx, &fetcher, branch_2_head, 100)
+            .await
+            .unwrap();
+        assert!(!sli_1.is_node_indexed(branch_2_head));
+        assert!(!sli_2.is_node_indexed(branch_1_head));
+
+        let merged = sli_1.merge(sli_2).unwrap();
+        assert!(merged.is_node_indexed(root));
+        assert!(merged.is_node_indexed(branch_1_head));
+        assert!(merged.is_node_indexed(branch_2_head));
+
+        for (desc, anc, expected) in [
+            (merge_node, branch_1_head, true),
+            (merge_node, branch_2_head, true),
+            (branch_1_head, root, true),
+            (branch_2_head, root, true),
+            (branch_2_head, branch_1_head, false),
+            (branch_1_head, branch_2_head, false),
+        ] {
+            assert_eq!(
+                merged
+                    .query_reachability(&ctx, &fetcher, desc, anc)
+                    .await
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_merge_conflicting_indexes(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let other = string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+
+        let sli_1 = SkiplistIndex::new_with_skiplist_graph(
+            vec![(
+                head,
+                SkiplistNodeType::ParentEdges(vec![(other, Generation::new(1))]),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let sli_2 = SkiplistIndex::new_with_skiplist_graph(
+            vec![(
+                head,
+                SkiplistNodeType::ParentEdges(vec![(other, Generation::new(2))]),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let err = sli_1.merge(sli_2).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::ConflictingSkiplistEntries(_))
+        ));
+    }
+
+    /// Changeset fetcher that makes `cycle_from` report `cycle_to` as its only parent.
+    struct CyclicChangesetFetcher {
+        cycle_from: ChangesetId,
+        cycle_to: ChangesetId,
+        cs_fetcher: ArcChangesetFetcher,
+    }
+
+    #[async_trait]
+    impl ChangesetFetcher for CyclicChangesetFetcher {
+        async fn get_generation_number(
+            &self,
+            ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Generation, Error> {
+            self.cs_fetcher.get_generation_number(ctx, cs_id).await
+        }
+
+        async fn get_parents(
+            &self,
+            ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Vec<ChangesetId>, Error> {
+            if cs_id == self.cycle_from {
+                Ok(vec![self.cycle_to])
+            } else {
+                self.cs_fetcher.get_parents(ctx, cs_id).await
+            }
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_cycle_detection(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let second =
+            string_to_bonsai(&ctx, &repo, "3e0e761030db6e479a7fb58b12881883f9f8c63f").await;
+        let root = string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+        let cs_fetcher: ArcChangesetFetcher = Arc::new(CyclicChangesetFetcher {
+            cycle_from: second,
+            cycle_to: head,
+            cs_fetcher: repo.changeset_fetcher_arc(),
+        });
+        let is_cycle = |err: Error| {
+            matches!(
+                err.downcast_ref::<ErrorKind>(),
+                Some(ErrorKind::GraphCycle { commit }) if *commit == second.to_string()
+            )
+        };
+
+        let sli = SkiplistIndex::new();
+        let err = sli
+            .query_reachability(&ctx, &cs_fetcher, head, root)
+            .await
+            .unwrap_err();
+        assert!(is_cycle(err));
+
+        let err = sli
+            .add_node(&ctx, &cs_fetcher, head, 100)
+            .await
+            .unwrap_err();
+        assert!(is_cycle(err));
+    }
+
+    #[fbinit::test]
+    async fn test_preload(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = MergeUneven::getrepo(fb).await;
+        let get_parents_count = Arc::new(AtomicUsize::new(0));
+        let get_gen_number_count = Arc::new(AtomicUsize::new(0));
+        let cs_fetcher: ArcChangesetFetcher = Arc::new(CountingChangesetFetcher::new(
+            repo.changeset_fetcher_arc(),
+            get_parents_count.clone(),
+            get_gen_number_count,
+        ));
+        let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        let branch_2_head =
+            string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+
+        let queries = |sli: SkiplistIndex| {
+            cloned!(ctx, cs_fetcher, get_parents_count);
+            async move {
+                let before = get_parents_count.load(Ordering::Relaxed);
+                for head in [branch_1_head, branch_2_head] {
+                    assert!(
+                        sli.query_reachability(&ctx, &cs_fetcher, head, root)
+                            .await
+                            .unwrap()
+                    );
+                }
+                get_parents_count.load(Ordering::Relaxed) - before
+            }
+        };
+
+        let cold = SkiplistIndex::new();
+        assert!(queries(cold).await > 0);
+
+        let warm = SkiplistIndex::new();
+        warm.preload(&ctx, &cs_fetcher, &[branch_1_head, branch_2_head])
+            .await
+            .unwrap();
+        assert!(warm.is_node_indexed(root));
+        assert!(warm.is_node_indexed(branch_1_head));
+        assert!(warm.is_node_indexed(branch_2_head));
+        assert_eq!(queries(warm).await, 0);
+    }
+
+    async fn test_query_frontier_reachability(
+        ctx: CoreContext,
+        repo: BlobRepo,
+        sli: SkiplistIndex,
+    ) {
+        let fetcher = repo.changeset_fetcher_arc();
+        let frontier = |cs_ids: Vec<ChangesetId>| {
+            cloned!(ctx, fetcher);
+            async move {
+                changesets_with_generation_numbers(&ctx, &fetcher, cs_ids)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .collect::<NodeFrontier>()
+            }
+        };
+        let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        let branch_1_root =
+            string_to_bonsai(&ctx, &repo, "3cda5c78aa35f0f5b09780d971197b51cad4613a").await;
+        let branch_2_head =
+            string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+        let branch_2_mid =
+            string_to_bonsai(&ctx, &repo, "795b8133cf375f6d68d27c6c23db24cd5d0cd00f").await;
+
+        // Overlapping frontiers: the shared node is its own witness.
+        let res = sli
+            .query_frontier_reachability(
+                &ctx,
+                &fetcher,
+                &frontier(vec![branch_1_head, branch_2_head]).await,
+                &frontier(vec![branch_2_head]).await,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, Some((branch_2_head, branch_2_head)));
+
+        // Disjoint branches don't reach each other.
+        let res = sli
+            .query_frontier_reachability(
+                &ctx,
+                &fetcher,
+                &frontier(vec![branch_1_head]).await,
+                &frontier(vec![branch_2_head, branch_2_mid]).await,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, None);
+
+        // Ancestors strictly dominated by the descendants.
+        let res = sli
+            .query_frontier_reachability(
+                &ctx,
+                &fetcher,
+                &frontier(vec![branch_1_head, branch_2_mid]).await,
+                &frontier(vec![branch_1_root, root]).await,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, Some((branch_1_root, branch_1_head)));
+
+        // Nothing is reachable from an empty frontier.
+        let res = sli
+            .query_frontier_reachability(
+                &ctx,
+                &fetcher,
+                &NodeFrontier::default(),
+                &frontier(vec![root]).await,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, None);
+    }
+
+    skiplist_test!(test_query_frontier_reachability, MergeUneven);
+
+    async fn test_query_reachability_any(ctx: CoreContext, repo: BlobRepo, sli: SkiplistIndex) {
+        let fetcher = repo.changeset_fetcher_arc();
+        let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        let branch_1_root =
+            string_to_bonsai(&ctx, &repo, "3cda5c78aa35f0f5b09780d971197b51cad4613a").await;
+        let branch_2_head =
+            string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+        let branch_2_mid =
+            string_to_bonsai(&ctx, &repo, "795b8133cf375f6d68d27c6c23db24cd5d0cd00f").await;
+        let merge = string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+
+        // One reachable ancestor among unreachable ones is enough.
+        assert!(
+            sli.query_reachability_any(
+                &ctx,
+                &fetcher,
+                branch_1_head,
+                &[branch_2_head, branch_2_mid, branch_1_root],
+            )
+            .await
+            .unwrap()
+        );
+        assert!(
+            sli.query_reachability_any(&ctx, &fetcher, branch_2_head, &[merge, branch_1_head, root])
+                .await
+                .unwrap()
+        );
+        // A node is reachable from itself.
+        assert!(
+            sli.query_reachability_any(&ctx, &fetcher, branch_2_mid, &[branch_2_mid])
+                .await
+                .unwrap()
+        );
+
+        assert!(
+            !sli.query_reachability_any(
+                &ctx,
+                &fetcher,
+                branch_1_head,
+                &[branch_2_head, branch_2_mid, merge],
+            )
+            .await
+            .unwrap()
+        );
+        assert!(
+            !sli.query_reachability_any(&ctx, &fetcher, merge, &[])
+                .await
+                .unwrap()
+        );
+    }
+
+    skiplist_test!(test_query_reachability_any, MergeUneven);
+
+    #[fbinit::test]
+    async fn test_level_histogram(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = MergeUneven::getrepo(fb).await;
+        let sli = SkiplistIndex::new();
+        assert!(sli.level_histogram().is_empty());
+
+        let merge_node =
+            string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+        sli.add_node(&ctx, &repo.changeset_fetcher_arc(), merge_node, 100)
+            .await
+            .unwrap();
+
+        let histogram = sli.level_histogram();
+        assert_eq!(histogram.iter().sum::<usize>(), sli.indexed_node_count());
+        // The root and the merge node only have parent edges.
+        assert!(histogram[0] >= 2);
+        // Long linear branches get skip edges beyond the parent.
+        assert!(histogram.len() > 1);
+    }
+
+    #[fbinit::test]
+    async fn test_coverage(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = MergeUneven::getrepo(fb).await;
+        let sli = SkiplistIndex::new();
+        let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        let branch_2_head =
+            string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+        assert!(sli.covered_heads().is_empty());
+
+        sli.add_node(&ctx, &repo.changeset_fetcher_arc(), branch_1_head, 100)
+            .await
+            .unwrap();
+        assert!(sli.contains(&branch_1_head));
+        assert!(sli.contains(&root));
+        assert!(!sli.contains(&branch_2_head));
+        assert_eq!(sli.covered_heads(), vec![branch_1_head]);
+
+        sli.add_node(&ctx, &repo.changeset_fetcher_arc(), branch_2_head, 100)
+            .await
+            .unwrap();
+        assert!(sli.contains(&branch_2_head));
+        let mut expected = vec![branch_1_head, branch_2_head];
+        expected.sort();
+        assert_eq!(sli.covered_heads(), expected);
+    }
+
+    #[fbinit::test]
+    async fn test_missing_coverage(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let sli = SkiplistIndex::new();
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let head_parent =
+            string_to_bonsai(&ctx, &repo, "0ed509bf086fadcb8a8a5384dc3b550729b0fc17").await;
+
+        assert_eq!(
+            sli.missing_coverage(&ctx, &fetcher, vec![head])
+                .await
+                .unwrap()
+                .len(),
+            8
+        );
+
+        sli.add_node(&ctx, &fetcher, head_parent, 100)
+            .await
+            .unwrap();
+        assert_eq!(
+            sli.missing_coverage(&ctx, &fetcher, vec![head])
+                .await
+                .unwrap(),
+            vec![head]
+        );
+
+        sli.add_node(&ctx, &fetcher, head, 100).await.unwrap();
+        assert!(
+            sli.missing_coverage(&ctx, &fetcher, vec![head])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_nearest_indexed_ancestor(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let sli = SkiplistIndex::new();
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let middle =
+            string_to_bonsai(&ctx, &repo, "cb15ca4a43a59acff5388cea9648c162afde8372").await;
+
+        assert_eq!(
+            sli.nearest_indexed_ancestor(&ctx, &fetcher, head)
+                .await
+                .unwrap(),
+            None
+        );
+
+        sli.add_node(&ctx, &fetcher, middle, 100).await.unwrap();
+        assert!(!sli.is_node_indexed(head));
+        assert_eq!(
+            sli.nearest_indexed_ancestor(&ctx, &fetcher, head)
+                .await
+                .unwrap(),
+            Some(middle)
+        );
+        assert_eq!(
+            sli.nearest_indexed_ancestor(&ctx, &fetcher, middle)
+                .await
+                .unwrap(),
+            Some(middle)
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_approx_ancestor_count_linear(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let sli = SkiplistIndex::new();
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let middle =
+            string_to_bonsai(&ctx, &repo, "cb15ca4a43a59acff5388cea9648c162afde8372").await;
+        let root = string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+
+        // Unindexed nodes fall back to the generation number.
+        assert_eq!(
+            sli.approx_ancestor_count(&ctx, &fetcher, head)
+                .await
+                .unwrap(),
+            8
+        );
+
+        sli.add_node(&ctx, &fetcher, head, 100).await.unwrap();
+        for (node, expected) in [(head, 8), (middle, 5), (root, 1)] {
+            assert_eq!(
+                sli.approx_ancestor_count(&ctx, &fetcher, node)
+                    .await
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_commits_at_generation(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let sli = SkiplistIndex::new();
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        assert!(sli.commits_at_generation(Generation::new(1)).is_empty());
+
+        sli.add_node(&ctx, &fetcher, head, 100).await.unwrap();
+        for gen in 1..=8 {
+            let gen = Generation::new(gen);
+            let commits = sli.commits_at_generation(gen);
+            // The linear fixture has exactly one commit per generation.
+            assert_eq!(commits.len(), 1);
+            for cs_id in commits {
+                assert_eq!(
+                    fetcher.get_generation_number(&ctx, cs_id).await.unwrap(),
+                    gen
+                );
+            }
+        }
+        assert_eq!(sli.commits_at_generation(Generation::new(8)), vec![head]);
+        assert!(sli.commits_at_generation(Generation::new(9)).is_empty());
+    }
+
+    /// Fetcher that tracks the highest number of concurrent generation number requests.
+    struct ConcurrencyTrackingChangesetFetcher {
+        inner: ArcChangesetFetcher,
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ChangesetFetcher for ConcurrencyTrackingChangesetFetcher {
+        async fn get_generation_number(
+            &self,
+            ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Generation, Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+            let res = self.inner.get_generation_number(ctx, cs_id).await;
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            res
+        }
+
+        async fn get_parents(
+            &self,
+            ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Vec<ChangesetId>, Error> {
+            self.inner.get_parents(ctx, cs_id).await
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_config_fetch_batch_size(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let nodes = vec![
+            string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await,
+            string_to_bonsai(&ctx, &repo, "0ed509bf086fadcb8a8a5384dc3b550729b0fc17").await,
+            string_to_bonsai(&ctx, &repo, "eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b").await,
+            string_to_bonsai(&ctx, &repo, "cb15ca4a43a59acff5388cea9648c162afde8372").await,
+        ];
+
+        let max_in_flight = |config: ReachabilityConfig| {
+            cloned!(ctx, nodes);
+            let inner = repo.changeset_fetcher_arc();
+            async move {
+                let max_in_flight = Arc::new(AtomicUsize::new(0));
+                let fetcher: ArcChangesetFetcher = Arc::new(ConcurrencyTrackingChangesetFetcher {
+                    inner,
+                    in_flight: Arc::new(AtomicUsize::new(0)),
+                    max_in_flight: max_in_flight.clone(),
+                });
+                let sli = SkiplistIndex::with_config(config);
+                assert_eq!(sli.config(), &config);
+                sli.preload(&ctx, &fetcher, &nodes).await.unwrap();
+                assert_eq!(sli.indexed_node_count(), 8);
+                max_in_flight.load(Ordering::Relaxed)
+            }
+        };
+
+        let one = NonZeroUsize::new(1).unwrap();
+        let sequential = ReachabilityConfig {
+            build_concurrency: one,
+            query_concurrency: one,
+            fetch_batch_size: one,
+            max_parent_fetches_per_query: None,
+        };
+        assert_eq!(max_in_flight(sequential).await, 1);
+        assert!(max_in_flight(ReachabilityConfig::default()).await > 1);
+    }
+
+    /// Random commit graph, where commit `i` has up to two parents chosen among commits `0..i`,
+    /// which guarantees that the graph is acyclic.
+    #[derive(Clone, Debug)]
+    struct ArbitraryDag {
+        parents: Vec<Vec<usize>>,
+        // Commits that get indexed before querying.
+        indexed: Vec<usize>,
+    }
+
+    impl Arbitrary for ArbitraryDag {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let size = usize::arbitrary(g) % 30 + 1;
+            let mut parents = vec![];
+            for i in 0..size {
+                let mut commit_parents = vec![];
+                if i > 0 {
+                    for _ in 0..(usize::arbitrary(g) % 3) {
+                        let p = usize::arbitrary(g) % i;
+                        if !commit_parents.contains(&p) {
+                            commit_parents.push(p);
+                        }
+                    }
+                }
+                parents.push(commit_parents);
+            }
+            let indexed = (0..size).filter(|_| bool::arbitrary(g)).collect();
+            ArbitraryDag { parents, indexed }
+        }
+    }
+
+    impl ArbitraryDag {
+        fn cs_id(i: usize) -> ChangesetId {
+            ChangesetId::from_bytes([i as u8; 32]).unwrap()
+        }
+
+        /// Reference implementation of reachability: plain walk over the parents.
+        fn is_reachable(&self, desc: usize, anc: usize) -> bool {
+            let mut visited = HashSet::new();
+            let mut stack = vec![desc];
+            while let Some(i) = stack.pop() {
+                if i == anc {
+                    return true;
+                }
+                if visited.insert(i) {
+                    stack.extend(self.parents[i].iter().cloned());
+                }
+            }
+            false
+        }
+    }
+
+    struct DagChangesetFetcher {
+        parents: HashMap<ChangesetId, Vec<ChangesetId>>,
+        generations: HashMap<ChangesetId, Generation>,
+    }
+
+    impl DagChangesetFetcher {
+        fn new(dag: &ArbitraryDag) -> Self {
+            let mut parents = HashMap::new();
+            let mut generations = HashMap::new();
+            for (i, commit_parents) in dag.parents.iter().enumerate() {
+                let gen = commit_parents
+                    .iter()
+                    .map(|p| generations[&ArbitraryDag::cs_id(*p)])
+                    .max()
+                    .map_or(FIRST_GENERATION, |gen: Generation| gen.add(1));
+                generations.insert(ArbitraryDag::cs_id(i), gen);
+                parents.insert(
+                    ArbitraryDag::cs_id(i),
+                    commit_parents
+                        .iter()
+                        .map(|p| ArbitraryDag::cs_id(*p))
+                        .collect(),
+                );
+            }
+            Self {
+                parents,
+                generations,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ChangesetFetcher for DagChangesetFetcher {
+        async fn get_generation_number(
+            &self,
+            _ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Generation, Error> {
+            self.generations
+                .get(&cs_id)
+                .cloned()
+                .ok_or_else(|| anyhow::format_err!("{} not found", cs_id))
+        }
+
+        async fn get_parents(
+            &self,
+            _ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Vec<ChangesetId>, Error> {
+            self.parents
+                .get(&cs_id)
+                .cloned()
+                .ok_or_else(|| anyhow::format_err!("{} not found", cs_id))
+        }
+    }
+
+    #[test]
+    fn quickcheck_skiplist_matches_reference_walk() {
+        #[tokio::main(flavor = "current_thread")]
+        async fn prop(fb: FacebookInit, dag: ArbitraryDag) -> bool {
+            let ctx = CoreContext::test_mock(fb);
+            let fetcher: ArcChangesetFetcher = Arc::new(DagChangesetFetcher::new(&dag));
+            let sli = SkiplistIndex::with_skip_edge_count(4);
+            for i in &dag.indexed {
+                sli.add_node(&ctx, &fetcher, ArbitraryDag::cs_id(*i), 10)
+                    .await
+                    .unwrap();
+            }
+            for desc in 0..dag.parents.len() {
+                for anc in 0..dag.parents.len() {
+                    let res = sli
+                        .query_reachability(
+                            &ctx,
+                            &fetcher,
+                            ArbitraryDag::cs_id(desc),
+                            ArbitraryDag::cs_id(anc),
+                        )
+                        .await
+                        .unwrap();
+                    if res != dag.is_reachable(desc, anc) {
+                        return false;
+                    }
+                }
+            }
+            true
+        }
+
+        quickcheck(prop as fn(FacebookInit, ArbitraryDag) -> bool)
+    }
+
+    #[fbinit::test]
+    async fn test_reachability_index_trait_object(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let index: Arc<dyn ReachabilityIndex> = Arc::new(SkiplistIndex::new());
+        let head = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+        let root = string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+
+        assert!(
+            index
+                .query_reachability(&ctx, &fetcher, head, root)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !index
+                .query_reachability(&ctx, &fetcher, root, head)
+                .await
+                .unwrap()
+        );
+
+        let cancellation = CancellationToken::new();
+        assert!(
+            index
+                .query_reachability_with_cancellation(&ctx, &fetcher, head, root, &cancellation)
+                .await
+                .unwrap()
+        );
+        cancellation.cancel();
+        let err = index
+            .query_reachability_with_cancellation(&ctx, &fetcher, head, root, &cancellation)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::Cancelled)
+        ));
+    }
+
+    #[fbinit::test]
+    async fn test_memory_budget(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let mut nodes = vec![];
+        for hash in [
+            "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157",
+            "0ed509bf086fadcb8a8a5384dc3b550729b0fc17",
+            "eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b",
+            "cb15ca4a43a59acff5388cea9648c162afde8372",
+            "d0a361e9022d226ae52f689667bd7d212a19cfe0",
+            "607314ef579bd2407752361ba1b0c1729d08b281",
+            "3e0e761030db6e479a7fb58b12881883f9f8c63f",
+            "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536",
+        ] {
+            nodes.push(string_to_bonsai(&ctx, &repo, hash).await);
+        }
+
+        // Enough for just a few nodes.
+        let edge = (nodes[0], Generation::new(1));
+        let budget = 3 * estimated_node_size(&SkiplistNodeType::SkipEdges(vec![edge; 4]));
+        let sli = SkiplistIndex::with_memory_budget(budget);
+        sli.add_node(&ctx, &fetcher, nodes[0], 100).await.unwrap();
+        assert!(sli.indexed_node_count() < nodes.len());
+        assert!(sli.indexed_node_count() > 0);
+
+        // Queries still return the right answers while nodes are evicted and re-added.
+        for (i, desc) in nodes.iter().enumerate() {
+            sli.add_node(&ctx, &fetcher, *desc, 100).await.unwrap();
+            assert!(sli.is_node_indexed(*desc));
+            for (j, anc) in nodes.iter().enumerate() {
+                assert_eq!(
+                    sli.query_reachability(&ctx, &fetcher, *desc, *anc)
+                        .await
+                        .unwrap(),
+                    i <= j,
+                );
+            }
+        }
+        assert!(sli.indexed_node_count() < nodes.len());
+    }
+
+    #[fbinit::test]
+    async fn test_memory_budget_evicts_least_recently_used(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = Linear::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let mut nodes = vec![];
+        for hash in [
+            "eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b",
+            "d0a361e9022d226ae52f689667bd7d212a19cfe0",
+            "3e0e761030db6e479a7fb58b12881883f9f8c63f",
+        ] {
+            nodes.push(string_to_bonsai(&ctx, &repo, hash).await);
+        }
+
+        // Indexed alone, every node has a single skip edge to its parent. The budget fits
+        // two of them.
+        let edge = (nodes[0], Generation::new(1));
+        let budget = 2 * estimated_node_size(&SkiplistNodeType::SkipEdges(vec![edge]));
+        let sli = SkiplistIndex::with_memory_budget(budget);
+        sli.add_node(&ctx, &fetcher, nodes[0], 1).await.unwrap();
+        sli.add_node(&ctx, &fetcher, nodes[1], 1).await.unwrap();
+        assert_eq!(sli.indexed_node_count(), 2);
+
+        // Querying the oldest node makes it the most recently used one.
+        assert!(sli.get_furthest_edges(nodes[0]).is_some());
+        sli.add_node(&ctx, &fetcher, nodes[2], 1).await.unwrap();
+        assert!(sli.is_node_indexed(nodes[0]));
+        assert!(!sli.is_node_indexed(nodes[1]));
+        assert!(sli.is_node_indexed(nodes[2]));
+    }
+
+    #[fbinit::test]
+    async fn test_build_with_concurrency_is_deterministic(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = BranchWide::getrepo(fb).await;
+        let fetcher = repo.changeset_fetcher_arc();
+        let heads = repo
+            .bookmarks()
+            .get_heads_maybe_stale(ctx.clone())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        let build = |concurrency: usize| {
+            cloned!(ctx, fetcher, heads);
+            async move {
+                let sli = SkiplistIndex::new();
+                for head in heads {
+                    sli.add_node_with_concurrency(
+                        &ctx,
+                        &fetcher,
+                        head,
+                        100,
+                        NonZeroUsize::new(concurrency).unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                }
+                let mut serialized: Vec<_> = sli
+                    .get_all_skip_edges()
+                    .into_iter()
+                    .map(|(cs_id, node)| (cs_id, node.serialize()))
+                    .collect();
+                serialized.sort();
+                serialized
+            }
+        };
+
+        let sequential = build(1).await;
+        assert!(!sequential.is_empty());
+        assert_eq!(sequential, build(1).await);
+        assert_eq!(sequential, build(16).await);
+    }
+
+    async fn query_from_indexed_merge_node(ctx: CoreContext, repo: BlobRepo, sli: SkiplistIndex) {
+        let merge_node =
+            string_to_bonsai(&ctx, &repo, "d592490c4386cdb3373dd93af04d563de199b2fb").await;
+        let commit_after_merge =
             string_to_bonsai(&ctx, &repo, "7fe9947f101acb4acf7d945e69f0d6ce76a81113").await;
         let cs_fetcher = repo.changeset_fetcher_arc();
         // Indexing starting from a merge node
@@ -2126,11 +4328,26 @@ mod test {
             None
         };
         let lca = sli
-            .lca(ctx, repo.changeset_fetcher_arc(), b1.clone(), b2.clone())
+            .lca(ctx.clone(), repo.changeset_fetcher_arc(), b1, b2)
             .await
             .unwrap();
 
         assert_eq!(lca, expected.into_iter().collect::<Vec<_>>());
+
+        let lca_gen = sli
+            .lca_generation(ctx.clone(), repo.changeset_fetcher_arc(), b1, b2)
+            .await
+            .unwrap();
+        let expected_gen = match lca.first() {
+            Some(lca) => Some(
+                repo.changeset_fetcher_arc()
+                    .get_generation_number(&ctx, *lca)
+                    .await
+                    .unwrap(),
+            ),
+            None => None,
+        };
+        assert_eq!(lca_gen, expected_gen);
     }
 
     async fn test_find_merge(
@@ -2253,6 +4470,110 @@ mod test {
         .await;
     }
 
+    async fn test_precompute_lca(ctx: CoreContext, repo: BlobRepo, sli: SkiplistIndex) {
+        let fetcher = repo.changeset_fetcher_arc();
+        let mut nodes = vec![];
+        for hash in [
+            "4f7f3fd428bec1a48f9314414b063c706d9c1aed",
+            "16839021e338500b3cf7c9b871c8a07351697d68",
+            "b65231269f651cfe784fd1d97ef02a049a37b8a0",
+            "15c40d0abc36d47fb51c8eaec51ac7aad31f669c",
+        ] {
+            nodes.push(string_to_bonsai(&ctx, &repo, hash).await);
+        }
+        let outside =
+            string_to_bonsai(&ctx, &repo, "3cda5c78aa35f0f5b09780d971197b51cad4613a").await;
+        assert_eq!(sli.get_precomputed_lca(nodes[0], nodes[1]), None);
+
+        sli.precompute_lca(&ctx, &fetcher, &nodes).await.unwrap();
+
+        let on_demand = SkiplistIndex::new();
+        for node1 in &nodes {
+            for node2 in &nodes {
+                if node1 == node2 {
+                    continue;
+                }
+                let expected = on_demand
+                    .lca(ctx.clone(), fetcher.clone(), *node1, *node2)
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    sli.get_precomputed_lca(*node1, *node2),
+                    Some(expected.clone())
+                );
+                assert_eq!(
+                    sli.lca(ctx.clone(), fetcher.clone(), *node1, *node2)
+                        .await
+                        .unwrap(),
+                    expected
+                );
+            }
+        }
+
+        // Pairs outside of the precomputed set are computed on demand.
+        assert_eq!(sli.get_precomputed_lca(nodes[0], outside), None);
+        assert_eq!(
+            sli.lca(ctx.clone(), fetcher.clone(), nodes[0], outside)
+                .await
+                .unwrap(),
+            on_demand
+                .lca(ctx.clone(), fetcher.clone(), nodes[0], outside)
+                .await
+                .unwrap()
+        );
+    }
+
+    skiplist_test!(test_precompute_lca, BranchEven);
+
+    async fn test_query_reachability_with_path(
+        ctx: CoreContext,
+        repo: BlobRepo,
+        sli: SkiplistIndex,
+    ) {
+        let fetcher = repo.changeset_fetcher_arc();
+        let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+        let branch_1_head =
+            string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+        let branch_2_head =
+            string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+        let merge_node =
+            string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+
+        for (desc, anc) in [
+            (merge_node, root),
+            (merge_node, branch_1_head),
+            (branch_2_head, root),
+            (root, root),
+        ] {
+            let path = sli
+                .query_reachability_with_path(&ctx, &fetcher, desc, anc)
+                .await
+                .unwrap()
+                .expect("ancestor should be reachable");
+            assert_eq!(path.first(), Some(&desc));
+            assert_eq!(path.last(), Some(&anc));
+            for pair in path.windows(2) {
+                let parents = fetcher.get_parents(&ctx, pair[0]).await.unwrap();
+                assert!(parents.contains(&pair[1]));
+            }
+        }
+
+        assert_eq!(
+            sli.query_reachability_with_path(&ctx, &fetcher, branch_1_head, branch_2_head)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            sli.query_reachability_with_path(&ctx, &fetcher, root, merge_node)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    skiplist_test!(test_query_reachability_with_path, MergeUneven);
+
     async fn test_lca_branch_uneven(ctx: CoreContext, repo: BlobRepo, sli: SkiplistIndex) {
         test_lca(
             ctx,
@@ -2377,6 +4698,19 @@ mod test {
         .await;
     }
 
+    async fn test_find_merges_uneven(ctx: CoreContext, repo: BlobRepo, sli: SkiplistIndex) {
+        // The ancestor is on the short branch, only the merge joins it with the long one.
+        test_find_merge(
+            ctx,
+            repo,
+            sli,
+            "3cda5c78aa35f0f5b09780d971197b51cad4613a",
+            "d35b1875cdd1ed2c687e86f1604b9d7e989450cb",
+            Some("d35b1875cdd1ed2c687e86f1604b9d7e989450cb"),
+        )
+        .await;
+    }
+
     #[fbinit::test]
     async fn test_index_update(fb: FacebookInit) {
         // This test was created to show the problem we had with skiplists not being correctly
@@ -2456,4 +4790,5 @@ mod test {
     skiplist_test!(test_lca_unshared_merge_even_empty_result, UnsharedMergeEven);
     skiplist_test!(test_find_merges_negative, Linear);
     skiplist_test!(test_find_merges_positive, MergeEven);
+    skiplist_test!(test_find_merges_uneven, MergeUneven);
 }
@@ -9,6 +9,8 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use anyhow::Error;
@@ -63,6 +65,47 @@ pub async fn update_sparse_skiplist(
     index: &mut HashMap<ChangesetId, SkiplistNodeType>,
     max_skip: NonZeroU64,
     cs_fetcher: &ArcChangesetFetcher,
+) -> Result<(), Error> {
+    update_sparse_skiplist_with_progress(ctx, heads, index, max_skip, cs_fetcher, None).await
+}
+
+/// Callback that receives the number of changesets visited so far.
+pub type ProgressCallback = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// Calls the progress callback every time another `report_every` changesets were visited.
+#[derive(Clone)]
+pub struct BuildProgress {
+    report_every: NonZeroUsize,
+    callback: ProgressCallback,
+    visited: usize,
+}
+
+impl BuildProgress {
+    pub fn new(report_every: NonZeroUsize, callback: ProgressCallback) -> Self {
+        Self {
+            report_every,
+            callback,
+            visited: 0,
+        }
+    }
+
+    pub(crate) fn add_visited(&mut self, count: usize) {
+        let before = self.visited / self.report_every.get();
+        self.visited += count;
+        if self.visited / self.report_every.get() > before {
+            (self.callback)(self.visited);
+        }
+    }
+}
+
+/// Same as `update_sparse_skiplist`, but reports the progress of the build.
+pub async fn update_sparse_skiplist_with_progress(
+    ctx: &CoreContext,
+    heads: Vec<ChangesetId>,
+    index: &mut HashMap<ChangesetId, SkiplistNodeType>,
+    max_skip: NonZeroU64,
+    cs_fetcher: &ArcChangesetFetcher,
+    mut progress: Option<BuildProgress>,
 ) -> Result<(), Error> {
     let heads_with_gens = fetch_generations(ctx, cs_fetcher, heads.clone()).await?;
 
@@ -73,8 +116,15 @@ pub async fn update_sparse_skiplist(
     // but at least it shouldn't be worse than other ordering.
     while let Some((gen, cs_ids)) = node_frontier.remove_max_gen() {
         for cs_id in cs_ids {
-            let new_cs_ids =
-                index_changeset(ctx, (cs_id, gen), index, max_skip, cs_fetcher).await?;
+            let new_cs_ids = index_changeset(
+                ctx,
+                (cs_id, gen),
+                index,
+                max_skip,
+                cs_fetcher,
+                &mut progress,
+            )
+            .await?;
             node_frontier.extend(new_cs_ids);
         }
     }
@@ -95,13 +145,14 @@ pub async fn update_sparse_skiplist(
 /// While traversing the graph new nodes are added to the index if there's >= max_skip
 /// commits between them
 /// If this function found a merge then parents of this merge commits are returned,
-/// and they should be indexed later.
+/// and they should be indexed later. Every changeset visited is reported to `progress`.
 async fn index_changeset(
     ctx: &CoreContext,
     mut edge_start: (ChangesetId, Generation),
     index: &mut HashMap<ChangesetId, SkiplistNodeType>,
     max_skip: NonZeroU64,
     cs_fetcher: &ArcChangesetFetcher,
+    progress: &mut Option<BuildProgress>,
 ) -> Result<Vec<(ChangesetId, Generation)>, Error> {
     if index.contains_key(&edge_start.0) {
         return Ok(vec![]);
@@ -166,6 +217,9 @@ async fn index_changeset(
         }
 
         let parents = fetch_parents_and_generations(ctx, cs_fetcher, edge_end.0).await?;
+        if let Some(progress) = progress.as_mut() {
+            progress.add_visited(1);
+        }
         match parents.as_slice() {
             [] => {
                 return Ok(vec![]);
@@ -644,6 +698,61 @@ mod test {
         Ok(())
     }
 
+    #[fbinit::test]
+    async fn test_build_skiplist_progress(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let ctx = &ctx;
+        let repo = Linear::getrepo(fb).await;
+
+        let cs_fetcher = repo.changeset_fetcher_arc();
+        let master_cs_id = resolve_cs_id(ctx, &repo, "master").await?;
+        let mut index = HashMap::new();
+
+        let reported = Arc::new(std::sync::Mutex::new(vec![]));
+        let progress = BuildProgress::new(
+            NonZeroUsize::new(1).unwrap(),
+            Arc::new({
+                let reported = reported.clone();
+                move |visited| reported.lock().unwrap().push(visited)
+            }),
+        );
+        let max_skip = NonZeroU64::new(2).unwrap();
+        update_sparse_skiplist_with_progress(
+            ctx,
+            vec![master_cs_id],
+            &mut index,
+            max_skip,
+            &cs_fetcher,
+            Some(progress),
+        )
+        .await?;
+        validate_index(master_cs_id, &index, max_skip).await?;
+
+        // 11 commits total, every one of them is reported as it is visited.
+        assert_eq!(*reported.lock().unwrap(), (1..=11).collect::<Vec<_>>());
+
+        let reported = Arc::new(std::sync::Mutex::new(vec![]));
+        let progress = BuildProgress::new(
+            NonZeroUsize::new(4).unwrap(),
+            Arc::new({
+                let reported = reported.clone();
+                move |visited| reported.lock().unwrap().push(visited)
+            }),
+        );
+        update_sparse_skiplist_with_progress(
+            ctx,
+            vec![master_cs_id],
+            &mut HashMap::new(),
+            max_skip,
+            &cs_fetcher,
+            Some(progress),
+        )
+        .await?;
+        assert_eq!(*reported.lock().unwrap(), vec![4, 8]);
+
+        Ok(())
+    }
+
     async fn validate_index(
         start: ChangesetId,
         index: &HashMap<ChangesetId, SkiplistNodeType>,
@@ -0,0 +1,203 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::Mutex;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use changeset_fetcher::ArcChangesetFetcher;
+use context::CoreContext;
+use lru_cache::LruCache;
+use mononoke_types::ChangesetId;
+use mononoke_types::Generation;
+
+use crate::LeastCommonAncestorsHint;
+use crate::NodeFrontier;
+
+type FrontierKey = (Vec<(ChangesetId, Generation)>, Generation);
+
+/// LeastCommonAncestorsHint that memoizes the results of the wrapped hint in bounded
+/// LRU caches. The commit graph is append-only, so cached results never go stale.
+pub struct CachingLcaHint<H> {
+    inner: H,
+    lca_hints: Mutex<LruCache<FrontierKey, NodeFrontier>>,
+    is_ancestor: Mutex<LruCache<(ChangesetId, ChangesetId), bool>>,
+}
+
+impl<H: LeastCommonAncestorsHint> CachingLcaHint<H> {
+    pub fn new(inner: H, capacity: usize) -> Self {
+        Self {
+            inner,
+            lca_hints: Mutex::new(LruCache::new(capacity)),
+            is_ancestor: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+}
+
+fn frontier_key(node_frontier: &NodeFrontier, gen: Generation) -> FrontierKey {
+    let mut nodes: Vec<_> = node_frontier
+        .iter()
+        .map(|(cs_id, gen)| (*cs_id, gen))
+        .collect();
+    nodes.sort();
+    (nodes, gen)
+}
+
+#[async_trait]
+impl<H: LeastCommonAncestorsHint> LeastCommonAncestorsHint for CachingLcaHint<H> {
+    async fn lca_hint(
+        &self,
+        ctx: &CoreContext,
+        repo: &ArcChangesetFetcher,
+        node_frontier: NodeFrontier,
+        gen: Generation,
+    ) -> Result<NodeFrontier, Error> {
+        let key = frontier_key(&node_frontier, gen);
+        if let Some(frontier) = self.lca_hints.lock().unwrap().get_mut(&key) {
+            return Ok(frontier.clone());
+        }
+        let frontier = self.inner.lca_hint(ctx, repo, node_frontier, gen).await?;
+        self.lca_hints.lock().unwrap().insert(key, frontier.clone());
+        Ok(frontier)
+    }
+
+    async fn is_ancestor(
+        &self,
+        ctx: &CoreContext,
+        repo: &ArcChangesetFetcher,
+        ancestor: ChangesetId,
+        descendant: ChangesetId,
+    ) -> Result<bool, Error> {
+        let key = (ancestor, descendant);
+        if let Some(res) = self.is_ancestor.lock().unwrap().get_mut(&key) {
+            return Ok(*res);
+        }
+        let res = self
+            .inner
+            .is_ancestor(ctx, repo, ancestor, descendant)
+            .await?;
+        self.is_ancestor.lock().unwrap().insert(key, res);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use anyhow::format_err;
+    use changeset_fetcher::ChangesetFetcher;
+    use fbinit::FacebookInit;
+    use maplit::hashmap;
+    use maplit::hashset;
+    use mononoke_types_mocks::changesetid::ONES_CSID;
+    use mononoke_types_mocks::changesetid::THREES_CSID;
+    use mononoke_types_mocks::changesetid::TWOS_CSID;
+
+    use super::*;
+
+    struct NoChangesetFetcher;
+
+    #[async_trait]
+    impl ChangesetFetcher for NoChangesetFetcher {
+        async fn get_generation_number(
+            &self,
+            _ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Generation, Error> {
+            Err(format_err!("{} not found", cs_id))
+        }
+
+        async fn get_parents(
+            &self,
+            _ctx: &CoreContext,
+            cs_id: ChangesetId,
+        ) -> Result<Vec<ChangesetId>, Error> {
+            Err(format_err!("{} not found", cs_id))
+        }
+    }
+
+    /// Linear history ONES <- TWOS <- THREES, counting the calls it receives.
+    #[derive(Default)]
+    struct CountingLcaHint {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl LeastCommonAncestorsHint for CountingLcaHint {
+        async fn lca_hint(
+            &self,
+            _ctx: &CoreContext,
+            _repo: &ArcChangesetFetcher,
+            node_frontier: NodeFrontier,
+            gen: Generation,
+        ) -> Result<NodeFrontier, Error> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let linear: HashMap<_, _> = hashmap! {
+                Generation::new(1) => ONES_CSID,
+                Generation::new(2) => TWOS_CSID,
+                Generation::new(3) => THREES_CSID,
+            };
+            let max_gen = node_frontier.max_gen().map_or(gen, |max| max.min(gen));
+            Ok(NodeFrontier::new(
+                hashmap! {max_gen => hashset! {linear[&max_gen]}},
+            ))
+        }
+
+        async fn is_ancestor(
+            &self,
+            _ctx: &CoreContext,
+            _repo: &ArcChangesetFetcher,
+            ancestor: ChangesetId,
+            descendant: ChangesetId,
+        ) -> Result<bool, Error> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(ancestor < descendant)
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_caching_lca_hint(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo: ArcChangesetFetcher = Arc::new(NoChangesetFetcher);
+        let hint = CachingLcaHint::new(CountingLcaHint::default(), 2);
+        let frontier = NodeFrontier::new(hashmap! {Generation::new(3) => hashset! {THREES_CSID}});
+
+        let first = hint
+            .lca_hint(&ctx, &repo, frontier.clone(), Generation::new(1))
+            .await?;
+        let second = hint
+            .lca_hint(&ctx, &repo, frontier.clone(), Generation::new(1))
+            .await?;
+        assert_eq!(first, second);
+        assert_eq!(hint.inner().calls.load(Ordering::Relaxed), 1);
+
+        assert!(hint.is_ancestor(&ctx, &repo, ONES_CSID, TWOS_CSID).await?);
+        assert!(hint.is_ancestor(&ctx, &repo, ONES_CSID, TWOS_CSID).await?);
+        assert_eq!(hint.inner().calls.load(Ordering::Relaxed), 2);
+
+        // Different generation is a different query.
+        hint.lca_hint(&ctx, &repo, frontier.clone(), Generation::new(2))
+            .await?;
+        assert_eq!(hint.inner().calls.load(Ordering::Relaxed), 3);
+
+        // The cache is bounded, so the oldest entry gets evicted.
+        hint.lca_hint(&ctx, &repo, frontier.clone(), Generation::new(3))
+            .await?;
+        hint.lca_hint(&ctx, &repo, frontier, Generation::new(1))
+            .await?;
+        assert_eq!(hint.inner().calls.load(Ordering::Relaxed), 5);
+        Ok(())
+    }
+}
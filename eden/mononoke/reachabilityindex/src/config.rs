@@ -0,0 +1,36 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::num::NonZeroUsize;
+
+use nonzero_ext::nonzero;
+
+/// Tuning knobs shared by the reachability indexes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReachabilityConfig {
+    /// Maximum number of concurrent changeset fetcher requests while building an index.
+    pub build_concurrency: NonZeroUsize,
+    /// Maximum number of queries run concurrently by operations that answer
+    /// several queries at once.
+    pub query_concurrency: NonZeroUsize,
+    /// Maximum number of generation numbers fetched concurrently for a list of changesets.
+    pub fetch_batch_size: NonZeroUsize,
+    /// Maximum number of changesets whose parents a single reachability query may fetch
+    /// before failing, or None for no limit.
+    pub max_parent_fetches_per_query: Option<NonZeroUsize>,
+}
+
+impl Default for ReachabilityConfig {
+    fn default() -> Self {
+        Self {
+            build_concurrency: nonzero!(100_usize),
+            query_concurrency: nonzero!(10_usize),
+            fetch_batch_size: nonzero!(10_usize),
+            max_parent_fetches_per_query: None,
+        }
+    }
+}
@@ -59,4 +59,14 @@ pub enum ErrorKind {
     UknownSkiplistThriftEncoding,
     #[error("Programming error: an unforssen state reached: {0}")]
     ProgrammingError(&'static str),
+    #[error("reachability query was cancelled")]
+    Cancelled,
+    #[error("conflicting skiplist entries for {0}")]
+    ConflictingSkiplistEntries(String),
+    #[error("cycle in commit graph: {commit} is its own ancestor")]
+    GraphCycle { commit: String },
+    #[error("invalid skiplist entry for {commit}: {reason}")]
+    InvalidSkiplistEntry { commit: String, reason: String },
+    #[error("reachability query too expensive: it needs more than {0} parent fetches")]
+    QueryTooExpensive(usize),
 }
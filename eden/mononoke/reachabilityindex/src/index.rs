@@ -18,8 +18,11 @@ use maplit::hashmap;
 use maplit::hashset;
 use mononoke_types::ChangesetId;
 use mononoke_types::Generation;
+use tokio_util::sync::CancellationToken;
 use uniqueheap::UniqueHeap;
 
+use crate::errors::ErrorKind;
+
 #[derive(Clone, Debug)]
 pub struct NodeFrontier {
     gen_map: HashMap<Generation, HashSet<ChangesetId>>,
@@ -80,13 +83,18 @@ impl NodeFrontier {
         }
     }
 
+    /// Creates a frontier containing just `node` at generation `gen`.
+    pub fn new_single(node: ChangesetId, gen: Generation) -> Self {
+        Self::new(hashmap! {gen => hashset!{node}})
+    }
+
     pub async fn new_from_single_node(
         ctx: &CoreContext,
         changeset_fetcher: ArcChangesetFetcher,
         node: ChangesetId,
     ) -> Result<Self, Error> {
         let gen = changeset_fetcher.get_generation_number(ctx, node).await?;
-        Ok(Self::new(hashmap! {gen => hashset!{node}}))
+        Ok(Self::new_single(node, gen))
     }
 
     pub fn get(&self, gen: &Generation) -> Option<&HashSet<ChangesetId>> {
@@ -167,6 +175,120 @@ pub trait ReachabilityIndex: Send + Sync {
         src: ChangesetId,
         dst: ChangesetId,
     ) -> Result<bool, Error>;
+
+    /// Same as `query_reachability`, but fails with `ErrorKind::Cancelled` once
+    /// `cancellation` is triggered. Implementations that can check for cancellation
+    /// while walking the graph should override this.
+    async fn query_reachability_with_cancellation(
+        &self,
+        ctx: &CoreContext,
+        repo: &ArcChangesetFetcher,
+        src: ChangesetId,
+        dst: ChangesetId,
+        cancellation: &CancellationToken,
+    ) -> Result<bool, Error> {
+        tokio::select! {
+            res = self.query_reachability(ctx, repo, src, dst) => res,
+            _ = cancellation.cancelled() => Err(ErrorKind::Cancelled.into()),
+        }
+    }
+
+    /// Returns the heads of `cs_ids`: the changesets that aren't ancestors of any other
+    /// changeset of `cs_ids`. Duplicates are ignored, and the heads come in the order of
+    /// `cs_ids`.
+    ///
+    /// A changeset can only be an ancestor of changesets of a higher generation, so the
+    /// changesets are visited by decreasing generation, and each is only checked against the
+    /// heads found so far that have a higher generation.
+    async fn heads_of_set(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        cs_ids: Vec<ChangesetId>,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        let (mut by_gen, order) = with_generations(ctx, changeset_fetcher, cs_ids).await?;
+        by_gen.sort_by(|(_, gen1), (_, gen2)| gen2.cmp(gen1));
+
+        let mut heads: Vec<(ChangesetId, Generation)> = vec![];
+        for (cs_id, gen) in by_gen {
+            let mut is_head = true;
+            for (head, head_gen) in heads.iter() {
+                if *head_gen > gen
+                    && self
+                        .query_reachability(ctx, changeset_fetcher, *head, cs_id)
+                        .await?
+                {
+                    is_head = false;
+                    break;
+                }
+            }
+            if is_head {
+                heads.push((cs_id, gen));
+            }
+        }
+
+        let mut heads: Vec<_> = heads.into_iter().map(|(cs_id, _)| cs_id).collect();
+        heads.sort_by_key(|cs_id| order[cs_id]);
+        Ok(heads)
+    }
+
+    /// Returns the roots of `cs_ids`: the changesets that aren't descendants of any other
+    /// changeset of `cs_ids`. Duplicates are ignored, and the roots come in the order of
+    /// `cs_ids`.
+    ///
+    /// This is `heads_of_set` the other way around: the changesets are visited by increasing
+    /// generation, and each is only checked against the roots found so far that have a lower
+    /// generation.
+    async fn roots_of_set(
+        &self,
+        ctx: &CoreContext,
+        changeset_fetcher: &ArcChangesetFetcher,
+        cs_ids: Vec<ChangesetId>,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        let (mut by_gen, order) = with_generations(ctx, changeset_fetcher, cs_ids).await?;
+        by_gen.sort_by_key(|(_, gen)| *gen);
+
+        let mut roots: Vec<(ChangesetId, Generation)> = vec![];
+        for (cs_id, gen) in by_gen {
+            let mut is_root = true;
+            for (root, root_gen) in roots.iter() {
+                if *root_gen < gen
+                    && self
+                        .query_reachability(ctx, changeset_fetcher, cs_id, *root)
+                        .await?
+                {
+                    is_root = false;
+                    break;
+                }
+            }
+            if is_root {
+                roots.push((cs_id, gen));
+            }
+        }
+
+        let mut roots: Vec<_> = roots.into_iter().map(|(cs_id, _)| cs_id).collect();
+        roots.sort_by_key(|cs_id| order[cs_id]);
+        Ok(roots)
+    }
+}
+
+/// Fetches the generation of every changeset of `cs_ids`, skipping duplicates. Also returns
+/// the position of each changeset in `cs_ids`, to restore their order.
+async fn with_generations(
+    ctx: &CoreContext,
+    changeset_fetcher: &ArcChangesetFetcher,
+    cs_ids: Vec<ChangesetId>,
+) -> Result<(Vec<(ChangesetId, Generation)>, HashMap<ChangesetId, usize>), Error> {
+    let mut order = HashMap::new();
+    let mut by_gen = vec![];
+    for cs_id in cs_ids {
+        if let hash_map::Entry::Vacant(entry) = order.entry(cs_id) {
+            entry.insert(by_gen.len());
+            let gen = changeset_fetcher.get_generation_number(ctx, cs_id).await?;
+            by_gen.push((cs_id, gen));
+        }
+    }
+    Ok((by_gen, order))
 }
 
 /// Trait for any method supporting computing an "LCA hint"
@@ -196,3 +318,34 @@ pub trait LeastCommonAncestorsHint: Send + Sync {
         descendant: ChangesetId,
     ) -> Result<bool, Error>;
 }
+
+#[cfg(test)]
+mod test {
+    use mononoke_types_mocks::changesetid::ONES_CSID;
+    use mononoke_types_mocks::changesetid::THREES_CSID;
+    use mononoke_types_mocks::changesetid::TWOS_CSID;
+
+    use super::*;
+
+    #[test]
+    fn test_node_frontier_construction() {
+        let single = NodeFrontier::new_single(ONES_CSID, Generation::new(3));
+        assert_eq!(single.len(), 1);
+        assert_eq!(single.max_gen(), Some(Generation::new(3)));
+
+        let frontier = NodeFrontier::from_iter(vec![
+            (ONES_CSID, Generation::new(3)),
+            (TWOS_CSID, Generation::new(7)),
+            (THREES_CSID, Generation::new(7)),
+        ]);
+        // `len` counts distinct generations.
+        assert_eq!(frontier.len(), 2);
+        assert_eq!(frontier.max_gen(), Some(Generation::new(7)));
+        assert_eq!(
+            frontier.get(&Generation::new(7)),
+            Some(&hashset! {TWOS_CSID, THREES_CSID})
+        );
+
+        assert_eq!(NodeFrontier::from_iter(vec![]).max_gen(), None);
+    }
+}
@@ -11,6 +11,12 @@
 pub mod errors;
 pub use crate::errors::ErrorKind;
 
+mod caching;
+pub use crate::caching::CachingLcaHint;
+
+mod config;
+pub use crate::config::ReachabilityConfig;
+
 mod index;
 pub use crate::index::LeastCommonAncestorsHint;
 pub use crate::index::NodeFrontier;
@@ -133,6 +133,146 @@ pub async fn test_merge_uneven_reachability<T: ReachabilityIndex + 'static>(
     }
 }
 
+pub async fn test_linear_heads_of_set<T: ReachabilityIndex + 'static>(
+    fb: FacebookInit,
+    index_creator: fn() -> T,
+) {
+    let ctx = CoreContext::test_mock(fb);
+    let repo = Arc::new(Linear::getrepo(fb).await);
+    let index = index_creator();
+    let tip = string_to_bonsai(&ctx, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").await;
+    let middle = string_to_bonsai(&ctx, &repo, "cb15ca4a43a59acff5388cea9648c162afde8372").await;
+    let root = string_to_bonsai(&ctx, &repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").await;
+
+    let heads = index
+        .heads_of_set(
+            &ctx,
+            &repo.changeset_fetcher_arc(),
+            vec![middle, root, tip, middle],
+        )
+        .await
+        .unwrap();
+    assert_eq!(heads, vec![tip]);
+
+    let heads = index
+        .heads_of_set(&ctx, &repo.changeset_fetcher_arc(), vec![root, middle])
+        .await
+        .unwrap();
+    assert_eq!(heads, vec![middle]);
+}
+
+pub async fn test_merge_uneven_heads_of_set<T: ReachabilityIndex + 'static>(
+    fb: FacebookInit,
+    index_creator: fn() -> T,
+) {
+    let ctx = CoreContext::test_mock(fb);
+    let repo = Arc::new(MergeUneven::getrepo(fb).await);
+    let index = index_creator();
+    let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+    let branch_1_base =
+        string_to_bonsai(&ctx, &repo, "3cda5c78aa35f0f5b09780d971197b51cad4613a").await;
+    let branch_1_tip =
+        string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+    let branch_2_middle =
+        string_to_bonsai(&ctx, &repo, "795b8133cf375f6d68d27c6c23db24cd5d0cd00f").await;
+    let branch_2_tip =
+        string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+    let merge = string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+
+    // Without the merge, the tip of each branch is a head.
+    let heads = index
+        .heads_of_set(
+            &ctx,
+            &repo.changeset_fetcher_arc(),
+            vec![
+                root,
+                branch_2_tip,
+                branch_1_base,
+                branch_2_middle,
+                branch_1_tip,
+            ],
+        )
+        .await
+        .unwrap();
+    assert_eq!(heads, vec![branch_2_tip, branch_1_tip]);
+
+    // A commit of the shorter branch has a lower generation than the tip of the longer one,
+    // but isn't its ancestor.
+    let heads = index
+        .heads_of_set(
+            &ctx,
+            &repo.changeset_fetcher_arc(),
+            vec![branch_1_base, branch_2_tip],
+        )
+        .await
+        .unwrap();
+    assert_eq!(heads, vec![branch_1_base, branch_2_tip]);
+
+    // The merge is a descendant of everything else.
+    let heads = index
+        .heads_of_set(
+            &ctx,
+            &repo.changeset_fetcher_arc(),
+            vec![branch_1_tip, merge, root, branch_2_tip],
+        )
+        .await
+        .unwrap();
+    assert_eq!(heads, vec![merge]);
+}
+
+pub async fn test_merge_uneven_roots_of_set<T: ReachabilityIndex + 'static>(
+    fb: FacebookInit,
+    index_creator: fn() -> T,
+) {
+    let ctx = CoreContext::test_mock(fb);
+    let repo = Arc::new(MergeUneven::getrepo(fb).await);
+    let index = index_creator();
+    let root = string_to_bonsai(&ctx, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await;
+    let branch_1_base =
+        string_to_bonsai(&ctx, &repo, "3cda5c78aa35f0f5b09780d971197b51cad4613a").await;
+    let branch_1_tip =
+        string_to_bonsai(&ctx, &repo, "16839021e338500b3cf7c9b871c8a07351697d68").await;
+    let branch_2_middle =
+        string_to_bonsai(&ctx, &repo, "795b8133cf375f6d68d27c6c23db24cd5d0cd00f").await;
+    let branch_2_tip =
+        string_to_bonsai(&ctx, &repo, "264f01429683b3dd8042cb3979e8bf37007118bc").await;
+    let merge = string_to_bonsai(&ctx, &repo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await;
+
+    // The tip of the short branch has a lower generation than the middle of the long one, but
+    // both are roots. The merge descends from both of them.
+    let roots = index
+        .roots_of_set(
+            &ctx,
+            &repo.changeset_fetcher_arc(),
+            vec![merge, branch_2_middle, branch_2_tip, branch_1_tip, merge],
+        )
+        .await
+        .unwrap();
+    assert_eq!(roots, vec![branch_2_middle, branch_1_tip]);
+
+    // Each branch has its own root.
+    let roots = index
+        .roots_of_set(
+            &ctx,
+            &repo.changeset_fetcher_arc(),
+            vec![branch_1_tip, branch_2_tip, branch_1_base, branch_2_middle],
+        )
+        .await
+        .unwrap();
+    assert_eq!(roots, vec![branch_1_base, branch_2_middle]);
+
+    // Everything descends from the root of the repo.
+    let roots = index
+        .roots_of_set(
+            &ctx,
+            &repo.changeset_fetcher_arc(),
+            vec![merge, branch_1_base, root, branch_2_middle],
+        )
+        .await
+        .unwrap();
+    assert_eq!(roots, vec![root]);
+}
+
 pub async fn test_branch_wide_reachability<T: ReachabilityIndex + 'static>(
     fb: FacebookInit,
     index_creator: fn() -> T,
@@ -53,6 +53,11 @@ impl SqlConstruct for SegmentedChangelogSqlConnections {
 
 impl SqlConstructFromMetadataDatabaseConfig for SegmentedChangelogSqlConnections {}
 
+/// Segmented Changelog for the repos that don't have one, see `DisabledSegmentedChangelog`.
+pub fn new_disabled_segmented_changelog() -> Arc<dyn SegmentedChangelog + Send + Sync> {
+    Arc::new(DisabledSegmentedChangelog::new())
+}
+
 pub fn new_test_segmented_changelog(
     ctx: CoreContext,
     repo_id: RepositoryId,
@@ -61,7 +66,7 @@ pub fn new_test_segmented_changelog(
     bookmarks: ArcBookmarks,
 ) -> Result<Arc<dyn SegmentedChangelog + Send + Sync>> {
     if !config.enabled {
-        return Ok(Arc::new(DisabledSegmentedChangelog::new()));
+        return Ok(new_disabled_segmented_changelog());
     }
     let seed_heads = seedheads_from_config(&ctx, config, JobType::Server)
         .context("finding segmented changelog heads")?;
@@ -127,7 +132,7 @@ pub async fn new_server_segmented_changelog<'a>(
     cache_pool: Option<cachelib::VolatileLruCachePool>,
 ) -> Result<Arc<dyn SegmentedChangelog + Send + Sync>> {
     if !config.enabled {
-        return Ok(Arc::new(DisabledSegmentedChangelog::new()));
+        return Ok(new_disabled_segmented_changelog());
     }
     if config.skip_dag_load_at_startup {
         let repo_id = repo_identity.id();
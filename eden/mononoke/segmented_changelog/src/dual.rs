@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use context::CoreContext;
+use mercurial_types::HgChangesetId;
+use mononoke_types::ChangesetId;
+use slog::debug;
+
+use crate::owned::OwnedSegmentedChangelog;
+use crate::CloneData;
+use crate::Location;
+use crate::SegmentedChangelog;
+
+/// Serves a repo while it moves from one Segmented Changelog version to the next.
+///
+/// Every query goes to the new dag first. The old dag answers the queries the new one can't
+/// answer yet, those where the new dag fails or doesn't know the commits involved. Once the
+/// new dag has caught up, the old one is never consulted.
+pub struct DualSegmentedChangelog {
+    old: OwnedSegmentedChangelog,
+    new: OwnedSegmentedChangelog,
+}
+
+impl DualSegmentedChangelog {
+    pub fn new(old: OwnedSegmentedChangelog, new: OwnedSegmentedChangelog) -> Self {
+        Self { old, new }
+    }
+}
+
+/// Runs `$call` against the new dag, and against the old dag if that failed.
+macro_rules! new_then_old {
+    ($self:ident, $ctx:ident, $sc:ident => $call:expr) => {{
+        let $sc = &$self.new;
+        match $call.await {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                debug!(
+                    $ctx.logger(),
+                    "new segmented changelog failed, falling back to the old one: {:?}", err
+                );
+                let $sc = &$self.old;
+                $call.await
+            }
+        }
+    }};
+}
+
+#[async_trait]
+impl SegmentedChangelog for DualSegmentedChangelog {
+    async fn location_to_many_changeset_ids(
+        &self,
+        ctx: &CoreContext,
+        location: Location<ChangesetId>,
+        count: u64,
+    ) -> Result<Vec<ChangesetId>> {
+        new_then_old!(self, ctx, sc => sc.location_to_many_changeset_ids(ctx, location, count))
+    }
+
+    async fn many_changeset_ids_to_locations(
+        &self,
+        ctx: &CoreContext,
+        master_heads: Vec<ChangesetId>,
+        cs_ids: Vec<ChangesetId>,
+    ) -> Result<HashMap<ChangesetId, Result<Location<ChangesetId>>>> {
+        let mut locations = new_then_old!(self, ctx, sc => sc.many_changeset_ids_to_locations(
+            ctx,
+            master_heads.clone(),
+            cs_ids.clone(),
+        ))?;
+        // Commits the new dag failed to locate might still be known to the old dag.
+        let missing: Vec<_> = cs_ids
+            .into_iter()
+            .filter(|cs_id| !matches!(locations.get(cs_id), Some(Ok(_))))
+            .collect();
+        if missing.is_empty() {
+            return Ok(locations);
+        }
+        if let Ok(old_locations) = self
+            .old
+            .many_changeset_ids_to_locations(ctx, master_heads, missing)
+            .await
+        {
+            for (cs_id, location) in old_locations {
+                if location.is_ok() {
+                    locations.insert(cs_id, location);
+                }
+            }
+        }
+        Ok(locations)
+    }
+
+    async fn clone_data(
+        &self,
+        ctx: &CoreContext,
+    ) -> Result<(CloneData<ChangesetId>, HashMap<ChangesetId, HgChangesetId>)> {
+        new_then_old!(self, ctx, sc => sc.clone_data(ctx))
+    }
+
+    async fn pull_data(
+        &self,
+        ctx: &CoreContext,
+        common: Vec<ChangesetId>,
+        missing: Vec<ChangesetId>,
+    ) -> Result<CloneData<ChangesetId>> {
+        new_then_old!(self, ctx, sc => sc.pull_data(ctx, common.clone(), missing.clone()))
+    }
+
+    async fn clone_data_since(
+        &self,
+        ctx: &CoreContext,
+        since: ChangesetId,
+    ) -> Result<CloneData<ChangesetId>> {
+        new_then_old!(self, ctx, sc => sc.clone_data_since(ctx, since))
+    }
+
+    async fn disabled(&self, _ctx: &CoreContext) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn is_ancestor(
+        &self,
+        ctx: &CoreContext,
+        ancestor: ChangesetId,
+        descendant: ChangesetId,
+    ) -> Result<Option<bool>> {
+        match self.new.is_ancestor(ctx, ancestor, descendant).await {
+            Ok(Some(res)) => Ok(Some(res)),
+            _ => self.old.is_ancestor(ctx, ancestor, descendant).await,
+        }
+    }
+
+    async fn first_parent_ancestor(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+        n: u64,
+    ) -> Result<Option<ChangesetId>> {
+        new_then_old!(self, ctx, sc => sc.first_parent_ancestor(ctx, cs_id, n))
+    }
+
+    async fn ancestors_within(
+        &self,
+        ctx: &CoreContext,
+        head: ChangesetId,
+        max_distance: u64,
+    ) -> Result<Vec<ChangesetId>> {
+        new_then_old!(self, ctx, sc => sc.ancestors_within(ctx, head, max_distance))
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Segmented Changelog queries for clients that only know hg changeset ids. The ids are
+//! converted to bonsai through the bonsai-hg mapping of the repo on the way in and back on
+//! the way out.
+
+use anyhow::Context;
+use anyhow::Result;
+use bonsai_hg_mapping::BonsaiHgMapping;
+use context::CoreContext;
+use mercurial_types::HgChangesetId;
+use mononoke_types::ChangesetId;
+use thiserror::Error;
+
+use crate::Location;
+use crate::SegmentedChangelog;
+
+/// An hg changeset id has no bonsai counterpart in the repo.
+#[derive(Debug, Error)]
+#[error("hg changeset {hg_cs_id} is not a known changeset")]
+pub struct UnknownHgChangeset {
+    pub hg_cs_id: HgChangesetId,
+}
+
+async fn to_bonsai(
+    ctx: &CoreContext,
+    bonsai_hg_mapping: &dyn BonsaiHgMapping,
+    hg_cs_id: HgChangesetId,
+) -> Result<Option<ChangesetId>> {
+    bonsai_hg_mapping
+        .get_bonsai_from_hg(ctx, hg_cs_id)
+        .await
+        .with_context(|| format!("error converting {} from hg to bonsai", hg_cs_id))
+}
+
+async fn to_hg(
+    ctx: &CoreContext,
+    bonsai_hg_mapping: &dyn BonsaiHgMapping,
+    cs_id: ChangesetId,
+) -> Result<HgChangesetId> {
+    bonsai_hg_mapping
+        .get_hg_from_bonsai(ctx, cs_id)
+        .await
+        .with_context(|| format!("error converting {} from bonsai to hg", cs_id))?
+        .with_context(|| format!("no hg changeset for {}", cs_id))
+}
+
+/// Same as `SegmentedChangelog::location_to_changeset_id`, with hg changeset ids. Fails with
+/// `UnknownHgChangeset` if the descendant of `location` is not a changeset of the repo.
+pub async fn location_to_hg_changeset_id(
+    ctx: &CoreContext,
+    sc: &dyn SegmentedChangelog,
+    bonsai_hg_mapping: &dyn BonsaiHgMapping,
+    location: Location<HgChangesetId>,
+) -> Result<HgChangesetId> {
+    let hg_cs_id = location.descendant;
+    let descendant = to_bonsai(ctx, bonsai_hg_mapping, hg_cs_id)
+        .await?
+        .ok_or(UnknownHgChangeset { hg_cs_id })?;
+    let location = location.with_descendant(descendant);
+    let cs_id = sc.location_to_changeset_id(ctx, location).await?;
+    to_hg(ctx, bonsai_hg_mapping, cs_id).await
+}
+
+/// Same as `SegmentedChangelog::changeset_id_to_location`, with hg changeset ids. Returns None
+/// if `hg_cs_id` is not a changeset of the repo, and fails with `UnknownHgChangeset` if one of
+/// `master_heads` isn't.
+pub async fn hg_changeset_id_to_location(
+    ctx: &CoreContext,
+    sc: &dyn SegmentedChangelog,
+    bonsai_hg_mapping: &dyn BonsaiHgMapping,
+    master_heads: Vec<HgChangesetId>,
+    hg_cs_id: HgChangesetId,
+) -> Result<Option<Location<HgChangesetId>>> {
+    let mut heads = Vec::with_capacity(master_heads.len());
+    for hg_head in master_heads {
+        let head = to_bonsai(ctx, bonsai_hg_mapping, hg_head)
+            .await?
+            .ok_or(UnknownHgChangeset { hg_cs_id: hg_head })?;
+        heads.push(head);
+    }
+    let cs_id = match to_bonsai(ctx, bonsai_hg_mapping, hg_cs_id).await? {
+        Some(cs_id) => cs_id,
+        None => return Ok(None),
+    };
+    match sc.changeset_id_to_location(ctx, heads, cs_id).await? {
+        Some(location) => {
+            let location = location
+                .and_then_descendant(|cs_id| to_hg(ctx, bonsai_hg_mapping, cs_id))
+                .await?;
+            Ok(Some(location))
+        }
+        None => Ok(None),
+    }
+}
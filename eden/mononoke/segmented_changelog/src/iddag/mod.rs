@@ -8,3 +8,5 @@
 mod save_store;
 
 pub use self::save_store::IdDagSaveStore;
+pub use self::save_store::IdDagStat;
+pub use self::save_store::VersionMismatch;
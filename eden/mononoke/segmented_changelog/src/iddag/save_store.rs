@@ -14,11 +14,27 @@ use blobstore::Blobstore;
 use blobstore::BlobstoreBytes;
 use context::CoreContext;
 use mononoke_types::RepositoryId;
+use thiserror::Error;
 
 use crate::logging::log_new_iddag_version;
 use crate::types::IdDagVersion;
 use crate::InProcessIdDag;
 
+/// The blob stored for an iddag version doesn't hash to that version.
+#[derive(Debug, Error)]
+#[error("iddag blob for version {expected} hashes to version {actual}, the blob is corrupted")]
+pub struct VersionMismatch {
+    pub expected: IdDagVersion,
+    pub actual: IdDagVersion,
+}
+
+/// What can be learned about a saved iddag without loading it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdDagStat {
+    /// Size of the serialized iddag, in bytes.
+    pub size: u64,
+}
+
 pub struct IdDagSaveStore {
     repo_id: RepositoryId,
     blobstore: Arc<dyn Blobstore>,
@@ -49,8 +65,18 @@ impl IdDagSaveStore {
             Some(b) => b,
         };
 
+        let bytes = bytes.into_raw_bytes();
+        let actual = IdDagVersion::from_serialized_bytes(&bytes);
+        if actual != iddag_version {
+            let err = VersionMismatch {
+                expected: iddag_version,
+                actual,
+            };
+            return Err(err.into());
+        }
+
         let deserialization = move || {
-            let iddag: InProcessIdDag = mincode::deserialize(&bytes.into_raw_bytes())?;
+            let iddag: InProcessIdDag = mincode::deserialize(&bytes)?;
             anyhow::Ok(iddag)
         };
         // In tests we can't offload deserialization to spawn blocking because
@@ -65,6 +91,29 @@ impl IdDagSaveStore {
         Ok(Some(iddag))
     }
 
+    /// Returns None if `iddag_version` was never saved. The blob is a plain serialized
+    /// iddag without a header, so it is fetched but never deserialized. Counting the
+    /// segments would need a full deserialization, `find` does that.
+    pub async fn stat<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        iddag_version: IdDagVersion,
+    ) -> Result<Option<IdDagStat>> {
+        let bytes_opt = self
+            .blobstore
+            .get(ctx, &self.key(iddag_version))
+            .await
+            .with_context(|| {
+                format!(
+                    "fetching prebuilt segmented changelog iddag version {}",
+                    iddag_version.0
+                )
+            })?;
+        Ok(bytes_opt.map(|bytes| IdDagStat {
+            size: bytes.len() as u64,
+        }))
+    }
+
     pub async fn load<'a>(
         &'a self,
         ctx: &'a CoreContext,
@@ -98,7 +147,7 @@ impl IdDagSaveStore {
         Ok(iddag_version)
     }
 
-    fn key(&self, iddag_version: IdDagVersion) -> String {
+    pub(crate) fn key(&self, iddag_version: IdDagVersion) -> String {
         format!("segmented_changelog_iddag.blake2.{}", iddag_version.0)
     }
 }
@@ -38,11 +38,19 @@ use crate::idmap::IdMap;
 use crate::types::IdMapVersion;
 use crate::DagId;
 
+/// Part of every memcache key of the idmap, along with the idmap version. Bump it when the
+/// format of the cached entries changes.
+const MC_CODEVER: u32 = 0;
+
+/// An IdMap caching the entries of `idmap` in cachelib and memcache. The cache keys contain
+/// the repo id and the idmap version, and memcache keys are also prefixed with `MC_CODEVER`,
+/// so the caches can be shared by all repos and idmap versions.
 #[derive(Clone)]
 pub struct CachedIdMap {
     idmap: Arc<dyn IdMap>,
     cache_handlers: CacheHandlers,
     repo_id: RepositoryId,
+    version: IdMapVersion,
     keygen: KeyGen,
 }
 
@@ -92,14 +100,15 @@ impl CachedIdMap {
         repo_id: RepositoryId,
         version: IdMapVersion,
     ) -> Self {
-        let codever = 0; // bump when logic changes
+        let key_prefix = "scm.mononoke.segmented_changelog.idmap";
         let sitever = version.0 as u32;
 
-        let keygen = KeyGen::new("scm.mononoke.segmented_changelog.idmap", codever, sitever);
+        let keygen = KeyGen::new(key_prefix, MC_CODEVER, sitever);
         Self {
             idmap,
             cache_handlers,
             repo_id,
+            version,
             keygen,
         }
     }
@@ -240,7 +249,7 @@ impl EntityStore<ChangesetIdWrapper> for ChangesetIdCacheRequest<'_> {
 impl KeyedEntityStore<DagId, ChangesetIdWrapper> for ChangesetIdCacheRequest<'_> {
     fn get_cache_key(&self, dag_id: &DagId) -> String {
         let (_, bag) = self;
-        format!("{}.dag_id.{}", bag.repo_id, dag_id)
+        format!("{}.{}.dag_id.{}", bag.repo_id, bag.version, dag_id)
     }
 
     async fn get_from_db(
@@ -318,7 +327,7 @@ impl EntityStore<DagIdWrapper> for DagIdCacheRequest<'_> {
 impl KeyedEntityStore<ChangesetId, DagIdWrapper> for DagIdCacheRequest<'_> {
     fn get_cache_key(&self, cs_id: &ChangesetId) -> String {
         let (_, bag, _) = self;
-        format!("{}.cs.{}", bag.repo_id, cs_id)
+        format!("{}.{}.cs.{}", bag.repo_id, bag.version, cs_id)
     }
 
     async fn get_from_db(
@@ -354,6 +363,7 @@ impl KeyedEntityStore<ChangesetId, DagIdWrapper> for DagIdCacheRequest<'_> {
 mod tests {
     use fbinit::FacebookInit;
     use mononoke_types_mocks::changesetid::ONES_CSID;
+    use mononoke_types_mocks::changesetid::THREES_CSID;
     use mononoke_types_mocks::changesetid::TWOS_CSID;
     use sql_construct::SqlConstruct;
     use sql_ext::replication::NoReplicaLagMonitor;
@@ -432,4 +442,54 @@ mod tests {
 
         Ok(())
     }
+
+    #[fbinit::test]
+    async fn test_no_key_colisions_between_versions(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+
+        let cache_handlers = CacheHandlers::mock();
+        let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+        let new_cached_idmap = |repo_id, idmap_version| {
+            let sql_idmap = SqlIdMap::new(
+                conns.0.clone(),
+                Arc::new(NoReplicaLagMonitor()),
+                repo_id,
+                idmap_version,
+            );
+            CachedIdMap::new(
+                Arc::new(sql_idmap),
+                cache_handlers.clone(),
+                repo_id,
+                idmap_version,
+            )
+        };
+        // Two versions of the same repo, and the same version of another repo.
+        let idmap1 = new_cached_idmap(RepositoryId::new(1), IdMapVersion(1));
+        let idmap2 = new_cached_idmap(RepositoryId::new(1), IdMapVersion(2));
+        let other_repo_idmap = new_cached_idmap(RepositoryId::new(2), IdMapVersion(1));
+
+        idmap1.insert(&ctx, DagId(0), ONES_CSID).await?;
+        idmap2.insert(&ctx, DagId(0), TWOS_CSID).await?;
+        other_repo_idmap.insert(&ctx, DagId(0), THREES_CSID).await?;
+
+        // Fetch twice, the second time from the caches filled by the first.
+        for _ in 0..2 {
+            assert_eq!(idmap1.get_changeset_id(&ctx, DagId(0)).await?, ONES_CSID);
+            assert_eq!(idmap2.get_changeset_id(&ctx, DagId(0)).await?, TWOS_CSID);
+            assert_eq!(
+                other_repo_idmap.get_changeset_id(&ctx, DagId(0)).await?,
+                THREES_CSID
+            );
+            assert_eq!(idmap1.find_dag_id(&ctx, TWOS_CSID).await?, None);
+            assert_eq!(idmap1.find_dag_id(&ctx, THREES_CSID).await?, None);
+            assert_eq!(other_repo_idmap.find_dag_id(&ctx, ONES_CSID).await?, None);
+            assert_eq!(idmap2.get_dag_id(&ctx, TWOS_CSID).await?, DagId(0));
+            assert_eq!(
+                other_repo_idmap.get_dag_id(&ctx, THREES_CSID).await?,
+                DagId(0)
+            );
+        }
+
+        Ok(())
+    }
 }
@@ -19,10 +19,13 @@ use anyhow::Context;
 use anyhow::Result;
 use async_trait::async_trait;
 use context::CoreContext;
+use mononoke_types::hash::Blake2;
+use mononoke_types::hash::Context as HashContext;
 use mononoke_types::ChangesetId;
 use mononoke_types::RepositoryId;
 use sql_ext::replication::ReplicaLagMonitor;
 use sql_ext::SqlConnections;
+use thiserror::Error;
 
 pub use self::cache::CacheHandlers;
 pub use self::cache::CachedIdMap;
@@ -35,8 +38,34 @@ pub use self::sql::SqlIdMap;
 use crate::types::IdMapVersion;
 use crate::DagId;
 use crate::DagIdSet;
+use crate::Group;
 use crate::InProcessIdDag;
 
+/// Number of entries fetched at once by `IdMap::content_hash`.
+const CONTENT_HASH_BATCH_SIZE: u64 = 10_000;
+
+/// A mapping passed to `IdMap::insert_many_checked` conflicts with an existing entry, or with
+/// an earlier mapping of the same batch.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum IdMapConflict {
+    #[error(
+        "IdMap conflict inserting ({dag_id}, {cs_id}): {dag_id} is already mapped to {existing}"
+    )]
+    DagId {
+        dag_id: DagId,
+        cs_id: ChangesetId,
+        existing: ChangesetId,
+    },
+    #[error(
+        "IdMap conflict inserting ({dag_id}, {cs_id}): {cs_id} is already mapped to {existing}"
+    )]
+    ChangesetId {
+        dag_id: DagId,
+        cs_id: ChangesetId,
+        existing: DagId,
+    },
+}
+
 #[async_trait]
 #[auto_impl::auto_impl(&, Arc)]
 pub trait IdMap: Send + Sync {
@@ -78,6 +107,65 @@ pub trait IdMap: Send + Sync {
         self.insert_many(ctx, vec![(dag_id, cs_id)]).await
     }
 
+    /// Like `insert_many`, but checks the mappings against the existing entries and against
+    /// each other first. Fails with `IdMapConflict` when a dag id or a changeset is already
+    /// mapped to something else. Mappings that are already present, or repeated in the batch,
+    /// are only inserted once.
+    async fn insert_many_checked(
+        &self,
+        ctx: &CoreContext,
+        mappings: Vec<(DagId, ChangesetId)>,
+    ) -> Result<()> {
+        let dag_ids = mappings.iter().map(|(dag_id, _)| *dag_id).collect();
+        let cs_ids = mappings.iter().map(|(_, cs_id)| *cs_id).collect();
+        let existing_cs_ids = self.find_many_changeset_ids(ctx, dag_ids).await?;
+        let existing_dag_ids = self.find_many_dag_ids(ctx, cs_ids).await?;
+
+        let mut to_insert = Vec::with_capacity(mappings.len());
+        let mut batch_cs_ids = HashMap::with_capacity(mappings.len());
+        let mut batch_dag_ids = HashMap::with_capacity(mappings.len());
+        for (dag_id, cs_id) in mappings {
+            let mapped_cs_id = existing_cs_ids
+                .get(&dag_id)
+                .or_else(|| batch_cs_ids.get(&dag_id))
+                .copied();
+            let mapped_dag_id = existing_dag_ids
+                .get(&cs_id)
+                .or_else(|| batch_dag_ids.get(&cs_id))
+                .copied();
+            match (mapped_cs_id, mapped_dag_id) {
+                (Some(existing), _) if existing != cs_id => {
+                    return Err(IdMapConflict::DagId {
+                        dag_id,
+                        cs_id,
+                        existing,
+                    }
+                    .into());
+                }
+                (_, Some(existing)) if existing != dag_id => {
+                    return Err(IdMapConflict::ChangesetId {
+                        dag_id,
+                        cs_id,
+                        existing,
+                    }
+                    .into());
+                }
+                (None, None) => {
+                    batch_cs_ids.insert(dag_id, cs_id);
+                    batch_dag_ids.insert(cs_id, dag_id);
+                    to_insert.push((dag_id, cs_id));
+                }
+                _ => {}
+            }
+        }
+        if to_insert.is_empty() {
+            return Ok(());
+        }
+        self.insert_many(ctx, to_insert).await
+    }
+
+    /// Returns `None` when `dag_id` is not in the IdMap. Errors are reserved for failures of
+    /// the underlying storage.
     async fn find_changeset_id(
         &self,
         ctx: &CoreContext,
@@ -89,6 +177,8 @@ pub trait IdMap: Send + Sync {
             .remove(&dag_id))
     }
 
+    /// Returns `None` when `cs_id` is not in the IdMap. Errors are reserved for failures of
+    /// the underlying storage.
     async fn find_dag_id(&self, ctx: &CoreContext, cs_id: ChangesetId) -> Result<Option<DagId>> {
         Ok(self
             .find_many_dag_ids(ctx, vec![cs_id])
@@ -107,17 +197,43 @@ pub trait IdMap: Send + Sync {
             .remove(&cs_id))
     }
 
+    /// Like `find_changeset_id`, but a missing entry is an error.
     async fn get_changeset_id(&self, ctx: &CoreContext, dag_id: DagId) -> Result<ChangesetId> {
         self.find_changeset_id(ctx, dag_id)
             .await?
             .ok_or_else(|| format_err!("Failed to find segmented changelog id {} in IdMap", dag_id))
     }
 
+    /// Like `find_dag_id`, but a missing entry is an error.
     async fn get_dag_id(&self, ctx: &CoreContext, cs_id: ChangesetId) -> Result<DagId> {
         self.find_dag_id(ctx, cs_id)
             .await?
             .ok_or_else(|| format_err!("Failed to find changeset id {} in IdMap", cs_id))
     }
+
+    /// Hashes every entry of the IdMap, in dag id order. Two IdMaps with the same entries
+    /// have the same hash, which makes comparing them cheap.
+    async fn content_hash(&self, ctx: &CoreContext) -> Result<Blake2> {
+        let mut hasher = HashContext::new(b"segmented_changelog_idmap");
+        let last = match self.get_last_entry(ctx).await? {
+            Some((last, _)) => last.0,
+            None => return Ok(hasher.finish()),
+        };
+        let mut start = Group::MASTER.min_id().0;
+        while start <= last {
+            let end = std::cmp::min(start + CONTENT_HASH_BATCH_SIZE - 1, last);
+            let dag_ids = (start..=end).map(DagId).collect();
+            let entries = self.find_many_changeset_ids(ctx, dag_ids).await?;
+            for id in start..=end {
+                if let Some(cs_id) = entries.get(&DagId(id)) {
+                    hasher.update(id.to_be_bytes());
+                    hasher.update(cs_id.blake2().as_ref());
+                }
+            }
+            start = end + 1;
+        }
+        Ok(hasher.finish())
+    }
 }
 
 /// The idmap works in unison with the iddag. The idmap and the iddag need to be in sync for iddag
@@ -498,6 +498,7 @@ mod tests {
 
     use super::*;
     use crate::builder::SegmentedChangelogSqlConnections;
+    use crate::idmap::IdMapConflict;
 
     fn new_sql_idmap() -> Result<SqlIdMap> {
         let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
@@ -529,6 +530,29 @@ mod tests {
         Ok(())
     }
 
+    #[fbinit::test]
+    async fn test_find_and_get(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let idmap = new_sql_idmap()?;
+
+        idmap.insert(&ctx, DagId(0), AS_CSID).await?;
+
+        assert_eq!(idmap.find_dag_id(&ctx, AS_CSID).await?, Some(DagId(0)));
+        assert_eq!(idmap.find_dag_id(&ctx, BS_CSID).await?, None);
+        assert_eq!(
+            idmap.find_changeset_id(&ctx, DagId(0)).await?,
+            Some(AS_CSID)
+        );
+        assert_eq!(idmap.find_changeset_id(&ctx, DagId(1)).await?, None);
+
+        assert_eq!(idmap.get_dag_id(&ctx, AS_CSID).await?, DagId(0));
+        assert!(idmap.get_dag_id(&ctx, BS_CSID).await.is_err());
+        assert_eq!(idmap.get_changeset_id(&ctx, DagId(0)).await?, AS_CSID);
+        assert!(idmap.get_changeset_id(&ctx, DagId(1)).await.is_err());
+
+        Ok(())
+    }
+
     #[fbinit::test]
     async fn test_insert_many(fb: FacebookInit) -> Result<()> {
         let ctx = CoreContext::test_mock(fb);
@@ -583,6 +607,85 @@ mod tests {
         Ok(())
     }
 
+    #[fbinit::test]
+    async fn test_insert_many_checked(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let idmap = new_sql_idmap()?;
+
+        idmap
+            .insert_many_checked(&ctx, vec![(DagId(0), AS_CSID), (DagId(1), ONES_CSID)])
+            .await?;
+        // Entries that are already present are accepted.
+        idmap
+            .insert_many_checked(&ctx, vec![(DagId(1), ONES_CSID), (DagId(2), TWOS_CSID)])
+            .await?;
+        assert_eq!(idmap.get_changeset_id(&ctx, DagId(2)).await?, TWOS_CSID);
+
+        // The dag id is already mapped to another changeset.
+        let err = idmap
+            .insert_many_checked(&ctx, vec![(DagId(3), THREES_CSID), (DagId(1), FIVES_CSID)])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<IdMapConflict>(),
+            Some(&IdMapConflict::DagId {
+                dag_id: DagId(1),
+                cs_id: FIVES_CSID,
+                existing: ONES_CSID,
+            })
+        );
+        // The changeset is already mapped to another dag id.
+        let err = idmap
+            .insert_many_checked(&ctx, vec![(DagId(4), TWOS_CSID)])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<IdMapConflict>(),
+            Some(&IdMapConflict::ChangesetId {
+                dag_id: DagId(4),
+                cs_id: TWOS_CSID,
+                existing: DagId(2),
+            })
+        );
+        // The batch maps a dag id to two changesets.
+        let err = idmap
+            .insert_many_checked(&ctx, vec![(DagId(3), THREES_CSID), (DagId(3), FOURS_CSID)])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<IdMapConflict>(),
+            Some(&IdMapConflict::DagId {
+                dag_id: DagId(3),
+                cs_id: FOURS_CSID,
+                existing: THREES_CSID,
+            })
+        );
+        // The batch maps a changeset to two dag ids.
+        let err = idmap
+            .insert_many_checked(&ctx, vec![(DagId(3), THREES_CSID), (DagId(4), THREES_CSID)])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<IdMapConflict>(),
+            Some(&IdMapConflict::ChangesetId {
+                dag_id: DagId(4),
+                cs_id: THREES_CSID,
+                existing: DagId(3),
+            })
+        );
+        // Nothing was inserted by the failed calls.
+        assert_eq!(idmap.find_changeset_id(&ctx, DagId(3)).await?, None);
+        assert_eq!(idmap.find_changeset_id(&ctx, DagId(4)).await?, None);
+
+        // A mapping repeated in the batch is inserted once.
+        idmap
+            .insert_many_checked(&ctx, vec![(DagId(3), THREES_CSID), (DagId(3), THREES_CSID)])
+            .await?;
+        assert_eq!(idmap.get_changeset_id(&ctx, DagId(3)).await?, THREES_CSID);
+
+        Ok(())
+    }
+
     #[fbinit::test]
     async fn test_find_many_changeset_ids(fb: FacebookInit) -> Result<()> {
         let ctx = CoreContext::test_mock(fb);
@@ -11,15 +11,17 @@
 //! in a given repository. It provides algorithms over the commit graph.
 use std::collections::HashMap;
 
-use anyhow::format_err;
 use anyhow::Result;
 use async_trait::async_trait;
 use context::CoreContext;
 use mononoke_types::ChangesetId;
+use thiserror::Error;
 
 mod builder;
 mod clone_hints;
 mod copy;
+mod dual;
+mod hg;
 mod iddag;
 mod idmap;
 mod logging;
@@ -55,25 +57,39 @@ pub use segmented_changelog_types::SegmentedChangelog;
 pub use segmented_changelog_types::SegmentedChangelogArc;
 pub use segmented_changelog_types::SegmentedChangelogRef;
 
+pub use crate::builder::new_disabled_segmented_changelog;
 pub use crate::builder::new_server_segmented_changelog;
 pub use crate::builder::new_server_segmented_changelog_manager;
 pub use crate::builder::new_test_segmented_changelog;
 pub use crate::builder::SegmentedChangelogSqlConnections;
 pub use crate::clone_hints::CloneHints;
 pub use crate::copy::copy_segmented_changelog;
+pub use crate::dual::DualSegmentedChangelog;
+pub use crate::hg::hg_changeset_id_to_location;
+pub use crate::hg::location_to_hg_changeset_id;
+pub use crate::hg::UnknownHgChangeset;
 // public for benchmarking
 pub use crate::idmap::ConcurrentMemIdMap;
 pub use crate::idmap::IdMap;
+pub use crate::idmap::IdMapConflict;
 pub use crate::manager::ArcSegmentedChangelogManager;
 pub use crate::manager::SegmentedChangelogManager;
 pub use crate::tailer::OperationMode;
+pub use crate::tailer::SeedEstimate;
 pub use crate::tailer::SegmentedChangelogTailer;
+pub use crate::tailer::UnknownHead;
 pub use crate::update::seedheads_from_config;
 pub use crate::update::JobType;
 pub use crate::update::SeedHead;
 
-// TODO(T74420661): use `thiserror` to represent error case
+/// Returned by every query of a `DisabledSegmentedChangelog`.
+#[derive(Debug, Error)]
+#[error("Segmented Changelog is not enabled for this repo")]
+pub struct SegmentedChangelogDisabled;
 
+/// Stands in for the Segmented Changelog of repos that don't have one, either because it is
+/// disabled or because it was never built. Queries fail with `SegmentedChangelogDisabled`,
+/// except `is_ancestor` which is inconclusive.
 pub struct DisabledSegmentedChangelog;
 
 impl DisabledSegmentedChangelog {
@@ -90,19 +106,14 @@ impl SegmentedChangelog for DisabledSegmentedChangelog {
         _location: Location<ChangesetId>,
         _count: u64,
     ) -> Result<Vec<ChangesetId>> {
-        // TODO(T74420661): use `thiserror` to represent error case
-        Err(format_err!(
-            "Segmented Changelog is not enabled for this repo",
-        ))
+        Err(SegmentedChangelogDisabled.into())
     }
 
     async fn clone_data(
         &self,
         _ctx: &CoreContext,
     ) -> Result<(CloneData<ChangesetId>, HashMap<ChangesetId, HgChangesetId>)> {
-        Err(format_err!(
-            "Segmented Changelog is not enabled for this repo",
-        ))
+        Err(SegmentedChangelogDisabled.into())
     }
 
     async fn pull_data(
@@ -111,9 +122,15 @@ impl SegmentedChangelog for DisabledSegmentedChangelog {
         _common: Vec<ChangesetId>,
         _missing: Vec<ChangesetId>,
     ) -> Result<CloneData<ChangesetId>> {
-        Err(format_err!(
-            "Segmented Changelog is not enabled for this repo",
-        ))
+        Err(SegmentedChangelogDisabled.into())
+    }
+
+    async fn clone_data_since(
+        &self,
+        _ctx: &CoreContext,
+        _since: ChangesetId,
+    ) -> Result<CloneData<ChangesetId>> {
+        Err(SegmentedChangelogDisabled.into())
     }
 
     async fn many_changeset_ids_to_locations(
@@ -122,9 +139,7 @@ impl SegmentedChangelog for DisabledSegmentedChangelog {
         _master_heads: Vec<ChangesetId>,
         _cs_ids: Vec<ChangesetId>,
     ) -> Result<HashMap<ChangesetId, Result<Location<ChangesetId>>>> {
-        Err(format_err!(
-            "Segmented Changelog is not enabled for this repo",
-        ))
+        Err(SegmentedChangelogDisabled.into())
     }
 
     async fn disabled(&self, _ctx: &CoreContext) -> Result<bool> {
@@ -140,6 +155,24 @@ impl SegmentedChangelog for DisabledSegmentedChangelog {
         // None means inconclusive result, it can be returned safely
         Ok(None)
     }
+
+    async fn first_parent_ancestor(
+        &self,
+        _ctx: &CoreContext,
+        _cs_id: ChangesetId,
+        _n: u64,
+    ) -> Result<Option<ChangesetId>> {
+        Err(SegmentedChangelogDisabled.into())
+    }
+
+    async fn ancestors_within(
+        &self,
+        _ctx: &CoreContext,
+        _head: ChangesetId,
+        _max_distance: u64,
+    ) -> Result<Vec<ChangesetId>> {
+        Err(SegmentedChangelogDisabled.into())
+    }
 }
 
 #[macro_export]
@@ -181,6 +214,15 @@ macro_rules! segmented_changelog_delegate {
                 delegate.pull_data($ctx, common, missing).await
             }
 
+            async fn clone_data_since(
+                &$self,
+                $ctx: &CoreContext,
+                since: ChangesetId,
+            ) -> Result<CloneData<ChangesetId>> {
+                let delegate = $delegate;
+                delegate.clone_data_since($ctx, since).await
+            }
+
             async fn many_changeset_ids_to_locations(
                 &$self,
                 $ctx: &CoreContext,
@@ -208,6 +250,26 @@ macro_rules! segmented_changelog_delegate {
                 delegate.is_ancestor($ctx, ancestor, descendant).await
             }
 
+            async fn first_parent_ancestor(
+                &$self,
+                $ctx: &CoreContext,
+                cs_id: ChangesetId,
+                n: u64,
+            ) -> Result<Option<ChangesetId>> {
+                let delegate = $delegate;
+                delegate.first_parent_ancestor($ctx, cs_id, n).await
+            }
+
+            async fn ancestors_within(
+                &$self,
+                $ctx: &CoreContext,
+                head: ChangesetId,
+                max_distance: u64,
+            ) -> Result<Vec<ChangesetId>> {
+                let delegate = $delegate;
+                delegate.ancestors_within($ctx, head, max_distance).await
+            }
+
             async fn build_up_to_heads(&$self, $ctx: &CoreContext, heads: &[ChangesetId]) -> Result<bool> {
                 let delegate = $delegate;
                 delegate.build_up_to_heads($ctx, heads).await
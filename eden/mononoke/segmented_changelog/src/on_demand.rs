@@ -8,8 +8,10 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context;
+use anyhow::Error;
 use anyhow::Result;
 use async_trait::async_trait;
 use bookmarks::Bookmarks;
@@ -39,7 +41,9 @@ use tokio::sync::Notify;
 use tokio::sync::RwLock;
 
 use crate::dag::ops::DagAddHeads;
+use crate::dag::IdDagStore;
 use crate::dag::VertexListWithOptions;
+use crate::idmap::cs_id_from_vertex_name;
 use crate::idmap::IdMap;
 use crate::parents::FetchParents;
 use crate::read_only::ReadOnlySegmentedChangelog;
@@ -50,6 +54,7 @@ use crate::update::SeedHead;
 use crate::update::ServerNameDag;
 use crate::CloneData;
 use crate::CloneHints;
+use crate::DagId;
 use crate::InProcessIdDag;
 use crate::Location;
 use crate::MismatchedHeadsError;
@@ -60,6 +65,7 @@ define_stats! {
     location_to_changeset_id: timeseries(Sum),
     changeset_id_to_location: timeseries(Sum),
     missing_notification_handle: timeseries(Sum),
+    compaction: timeseries(Sum),
 }
 
 mod need_update {
@@ -105,6 +111,15 @@ mod actual_update {
     }
 }
 
+/// Bookkeeping for the compactions of the iddag.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Compactions {
+    /// How many times the iddag was compacted.
+    pub(crate) count: u64,
+    /// The segment count of the iddag right after the last compaction.
+    pub(crate) segments_after_last: u64,
+}
+
 pub struct OnDemandUpdateSegmentedChangelog {
     repo_id: RepositoryId,
     namedag: Arc<RwLock<ServerNameDag>>,
@@ -112,6 +127,9 @@ pub struct OnDemandUpdateSegmentedChangelog {
     bookmarks: Arc<dyn Bookmarks>,
     seed_heads: Vec<SeedHead>,
     clone_hints: Option<CloneHints>,
+    clone_segment_chunk_size: Option<u64>,
+    max_segments: Option<u64>,
+    compactions: Arc<Mutex<Compactions>>,
     ongoing_update: Arc<Mutex<Option<TryShared<BoxFuture<'static, Result<()>>>>>>,
 }
 
@@ -135,10 +153,41 @@ impl OnDemandUpdateSegmentedChangelog {
             bookmarks,
             seed_heads,
             clone_hints,
+            clone_segment_chunk_size: None,
+            max_segments: None,
+            compactions: Arc::new(Mutex::new(Compactions::default())),
             ongoing_update: Arc::new(Mutex::new(None)),
         })
     }
 
+    /// Sends the flat segments of `clone_data` in chunks of at most `chunk_size` commits.
+    pub fn with_clone_segment_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.clone_segment_chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Compacts the iddag when updates added more than `max_segments` segments since the last
+    /// compaction, counting every level. The segments added by many small updates are replaced
+    /// by the segments of an iddag built in one go from the same flat segments. The ids don't
+    /// change, so the idmap is unaffected.
+    ///
+    /// Counting from the last compaction rather than from zero means that an iddag whose
+    /// compacted form is already above `max_segments` isn't rebuilt on every update.
+    pub fn with_max_segments(mut self, max_segments: u64) -> Self {
+        self.max_segments = Some(max_segments);
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn iddag(&self) -> InProcessIdDag {
+        self.namedag.read().await.dag().clone()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn compactions(&self) -> Compactions {
+        *self.compactions.lock()
+    }
+
     pub fn with_periodic_update_to_master_bookmark(
         self: Arc<Self>,
         ctx: &CoreContext,
@@ -186,12 +235,22 @@ impl OnDemandUpdateSegmentedChangelog {
                     heads,
                     self.repo_id,
                     self.namedag,
-                    self.changeset_fetcher
+                    self.changeset_fetcher,
+                    self.max_segments,
+                    self.compactions
                 );
                 let task_ongoing_update = self.ongoing_update.clone();
                 let update_task = async move {
-                    let result =
-                        the_actual_update(ctx, repo_id, namedag, changeset_fetcher, &heads).await;
+                    let result = the_actual_update(
+                        ctx,
+                        repo_id,
+                        namedag,
+                        changeset_fetcher,
+                        max_segments,
+                        compactions,
+                        &heads,
+                    )
+                    .await;
                     let mut ongoing_update = task_ongoing_update.lock();
                     *ongoing_update = None;
                     result
@@ -246,6 +305,50 @@ impl OnDemandUpdateSegmentedChangelog {
         ret.map(|_| ())
     }
 
+    /// Grows the dag to include `cs_id` and its ancestors, whether or not they are ancestors of
+    /// the configured heads. Only the ancestors that the dag is missing are added.
+    pub async fn extend_to(&self, ctx: &CoreContext, cs_id: ChangesetId) -> Result<()> {
+        if self.are_heads_assigned(ctx, &[cs_id]).await? {
+            return Ok(());
+        }
+        let seed_heads = [SeedHead::from(&cs_id)];
+        let vertex_list =
+            vertexlist_from_seedheads(ctx, &seed_heads, self.bookmarks.as_ref()).await?;
+        self.build_up_to_vertex_list(ctx, &vertex_list).await
+    }
+
+    /// Returns how many commits the dag is missing to reach the seed heads. For every head,
+    /// this counts its ancestors until the first one that is in the dag, so the count is exact
+    /// for linear history and approximate across merges. The largest count is returned.
+    ///
+    /// At most `max_commits` ancestors are visited per head, so a lag of `max_commits` means
+    /// the dag is at least that far behind.
+    pub async fn head_lag(&self, ctx: &CoreContext, max_commits: u64) -> Result<u64> {
+        let vertex_list =
+            vertexlist_from_seedheads(ctx, &self.seed_heads, self.bookmarks.as_ref()).await?;
+        let id_map = self.namedag.read().await.map().clone_idmap();
+        let mut lag = 0;
+        for head in vertex_list.vertexes() {
+            let cs_id = cs_id_from_vertex_name(&head);
+            let ancestors =
+                AncestorsNodeStream::new(ctx.clone(), &self.changeset_fetcher, cs_id).compat();
+            let missing = ancestors
+                .take(max_commits as usize)
+                .try_take_while(|cs_id| {
+                    cloned!(ctx, id_map);
+                    let cs_id = *cs_id;
+                    async move {
+                        let dag_id = id_map.find_dag_id(&ctx, cs_id).await?;
+                        Ok::<_, Error>(dag_id.is_none())
+                    }
+                })
+                .try_fold(0, |count, _| async move { Ok::<_, Error>(count + 1) })
+                .await?;
+            lag = std::cmp::max(lag, missing);
+        }
+        Ok(lag)
+    }
+
     async fn build_up_to_bookmark(&self, ctx: &CoreContext) -> Result<()> {
         let vertex_list =
             vertexlist_from_seedheads(ctx, &self.seed_heads, self.bookmarks.as_ref()).await?;
@@ -329,6 +432,8 @@ async fn the_actual_update(
     repo_id: RepositoryId,
     namedag: Arc<RwLock<ServerNameDag>>,
     changeset_fetcher: ArcChangesetFetcher,
+    max_segments: Option<u64>,
+    compactions: Arc<Mutex<Compactions>>,
     heads: &VertexListWithOptions,
 ) -> Result<()> {
     let monitored = async {
@@ -337,6 +442,19 @@ async fn the_actual_update(
 
         namedag.add_heads(&parent_fetcher, heads).await?;
         namedag.map().flush_writes().await?;
+        if let Some(max_segments) = max_segments {
+            let segments_after_last = compactions.lock().segments_after_last;
+            if segment_count(namedag.dag())?.saturating_sub(segments_after_last) > max_segments {
+                STATS::compaction.add_value(1);
+                let iddag = compact_iddag(namedag.dag())?;
+                let segments = segment_count(&iddag)?;
+                let idmap = namedag.map().clone_idmap();
+                *namedag = server_namedag(ctx.clone(), iddag, idmap)?;
+                let mut compactions = compactions.lock();
+                compactions.count += 1;
+                compactions.segments_after_last = segments;
+            }
+        }
         Ok(())
     };
     actual_update::STATS::count.add_value(1);
@@ -361,6 +479,23 @@ async fn the_actual_update(
     ret
 }
 
+/// Counts the segments of `iddag`, over all levels.
+pub(crate) fn segment_count(iddag: &InProcessIdDag) -> Result<u64> {
+    let mut count = 0;
+    for level in 0..=iddag.max_level()? {
+        count += iddag.iter_segments_ascending(DagId::MIN, level)?.count() as u64;
+    }
+    Ok(count)
+}
+
+/// Builds an iddag with the same commits and ids as `iddag`, from its flat segments.
+pub(crate) fn compact_iddag(iddag: &InProcessIdDag) -> Result<InProcessIdDag> {
+    let flat_segments = iddag.idset_to_flat_segments(iddag.all()?)?;
+    let mut compacted = InProcessIdDag::new_in_process();
+    compacted.build_segments_from_prepared_flat_segments(&flat_segments)?;
+    Ok(compacted)
+}
+
 #[async_trait]
 impl SegmentedChangelog for OnDemandUpdateSegmentedChangelog {
     async fn location_to_many_changeset_ids(
@@ -404,7 +539,8 @@ impl SegmentedChangelog for OnDemandUpdateSegmentedChangelog {
         ctx: &CoreContext,
     ) -> Result<(CloneData<ChangesetId>, HashMap<ChangesetId, HgChangesetId>)> {
         let namedag = self.namedag.read().await;
-        let read_dag = ReadOnlySegmentedChangelog::new(namedag.dag(), namedag.map().clone_idmap());
+        let read_dag = ReadOnlySegmentedChangelog::new(namedag.dag(), namedag.map().clone_idmap())
+            .with_clone_segment_chunk_size(self.clone_segment_chunk_size);
         let hints = if let (Some(clone_hints), Some(idmap_version)) = (
             self.clone_hints.as_ref(),
             namedag.map().as_inner().idmap_version(),
@@ -431,6 +567,17 @@ impl SegmentedChangelog for OnDemandUpdateSegmentedChangelog {
         read_dag.pull_data(ctx, common, missing).await
     }
 
+    async fn clone_data_since(
+        &self,
+        ctx: &CoreContext,
+        since: ChangesetId,
+    ) -> Result<CloneData<ChangesetId>> {
+        let namedag = self.namedag.read().await;
+        let read_dag = ReadOnlySegmentedChangelog::new(namedag.dag(), namedag.map().clone_idmap())
+            .with_clone_segment_chunk_size(self.clone_segment_chunk_size);
+        read_dag.clone_data_since(ctx, since).await
+    }
+
     async fn disabled(&self, _ctx: &CoreContext) -> Result<bool> {
         Ok(false)
     }
@@ -445,6 +592,28 @@ impl SegmentedChangelog for OnDemandUpdateSegmentedChangelog {
         read_dag.is_ancestor(ctx, ancestor, descendant).await
     }
 
+    async fn first_parent_ancestor(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+        n: u64,
+    ) -> Result<Option<ChangesetId>> {
+        let namedag = self.namedag.read().await;
+        let read_dag = ReadOnlySegmentedChangelog::new(namedag.dag(), namedag.map().clone_idmap());
+        read_dag.first_parent_ancestor(ctx, cs_id, n).await
+    }
+
+    async fn ancestors_within(
+        &self,
+        ctx: &CoreContext,
+        head: ChangesetId,
+        max_distance: u64,
+    ) -> Result<Vec<ChangesetId>> {
+        let namedag = self.namedag.read().await;
+        let read_dag = ReadOnlySegmentedChangelog::new(namedag.dag(), namedag.map().clone_idmap());
+        read_dag.ancestors_within(ctx, head, max_distance).await
+    }
+
     async fn build_up_to_heads(&self, ctx: &CoreContext, heads: &[ChangesetId]) -> Result<bool> {
         if !self.are_heads_assigned(ctx, heads).await? {
             self.build_up_to_client_heads(ctx, heads).await?;
@@ -465,6 +634,7 @@ impl SegmentedChangelog for OnDemandUpdateSegmentedChangelog {
 
 pub struct PeriodicUpdateSegmentedChangelog {
     on_demand_update_sc: Arc<OnDemandUpdateSegmentedChangelog>,
+    last_update_time: Arc<Mutex<Option<Instant>>>,
     _handle: ControlledHandle,
     #[allow(dead_code)] // useful for testing
     notify: Arc<Notify>,
@@ -477,10 +647,12 @@ impl PeriodicUpdateSegmentedChangelog {
         period: Duration,
     ) -> Self {
         let notify = Arc::new(Notify::new());
+        let last_update_time = Arc::new(Mutex::new(None));
         let _handle = spawn_controlled({
             let ctx = ctx.clone();
             let my_dag = Arc::clone(&on_demand_update_sc);
             let notify = Arc::clone(&notify);
+            let last_update_time = Arc::clone(&last_update_time);
             async move {
                 // jitter is here so not all repos try to update at the same time
                 let jitter = rand::thread_rng().gen_range(Duration::from_secs(0)..period);
@@ -491,12 +663,13 @@ impl PeriodicUpdateSegmentedChangelog {
                 let mut interval = tokio::time::interval(period);
                 loop {
                     let _ = interval.tick().await;
-                    if let Err(err) = my_dag.build_up_to_bookmark(&ctx).await {
-                        slog::error!(
+                    match my_dag.build_up_to_bookmark(&ctx).await {
+                        Ok(()) => *last_update_time.lock() = Some(Instant::now()),
+                        Err(err) => slog::error!(
                             ctx.logger(),
                             "failed to update segmented changelog dag: {:?}",
                             err
-                        );
+                        ),
                     }
                     notify.notify_waiters();
                 }
@@ -504,11 +677,24 @@ impl PeriodicUpdateSegmentedChangelog {
         });
         Self {
             on_demand_update_sc,
+            last_update_time,
             _handle,
             notify,
         }
     }
 
+    /// Returns when the dag was last successfully updated to the seed heads, or None if no
+    /// update has succeeded yet.
+    pub fn last_update_time(&self) -> Option<Instant> {
+        *self.last_update_time.lock()
+    }
+
+    /// Returns how many commits the dag is behind the seed heads, see
+    /// `OnDemandUpdateSegmentedChangelog::head_lag`.
+    pub async fn head_lag(&self, ctx: &CoreContext, max_commits: u64) -> Result<u64> {
+        self.on_demand_update_sc.head_lag(ctx, max_commits).await
+    }
+
     #[cfg(test)]
     pub async fn wait_for_update(&self) {
         self.notify.notified().await;
@@ -6,8 +6,10 @@
  */
 
 use std::collections::HashMap;
+use std::io::Write;
 use std::sync::Arc;
 
+use anyhow::format_err;
 use anyhow::Result;
 use async_trait::async_trait;
 use context::CoreContext;
@@ -17,22 +19,77 @@ use crate::idmap::IdMap;
 use crate::read_only::ReadOnlySegmentedChangelog;
 use crate::segmented_changelog_delegate;
 use crate::CloneData;
+use crate::DagId;
 use crate::InProcessIdDag;
 use crate::Location;
 use crate::SegmentedChangelog;
 
+/// Number of commits resolved through the idmap at once by `export_commit_graph`.
+const EXPORT_BATCH_SIZE: usize = 10_000;
+
 // We call it owned because the iddag is owned.
 pub struct OwnedSegmentedChangelog {
     pub(crate) iddag: InProcessIdDag,
     pub(crate) idmap: Arc<dyn IdMap>,
+    clone_segment_chunk_size: Option<u64>,
 }
 
 impl OwnedSegmentedChangelog {
     pub fn new(iddag: InProcessIdDag, idmap: Arc<dyn IdMap>) -> Self {
-        Self { iddag, idmap }
+        Self {
+            iddag,
+            idmap,
+            clone_segment_chunk_size: None,
+        }
+    }
+
+    /// Sends the flat segments of `clone_data` in chunks of at most `chunk_size` commits.
+    pub fn with_clone_segment_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.clone_segment_chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Writes the commit graph to `writer`, one line per commit: the commit followed by its
+    /// parents, separated by spaces. Parents come before their children. Returns the number
+    /// of commits written.
+    pub async fn export_commit_graph(
+        &self,
+        ctx: &CoreContext,
+        writer: &mut dyn Write,
+    ) -> Result<u64> {
+        let all = self.iddag.all()?;
+        let ids: Vec<DagId> = all.iter_asc().collect();
+        let mut count = 0;
+        for batch in ids.chunks(EXPORT_BATCH_SIZE) {
+            let mut edges = Vec::with_capacity(batch.len());
+            let mut to_resolve = batch.to_vec();
+            for id in batch {
+                let parents = self.iddag.parent_ids(*id)?;
+                to_resolve.extend(parents.iter().cloned());
+                edges.push((*id, parents));
+            }
+            to_resolve.sort_unstable();
+            to_resolve.dedup();
+            let cs_ids = self.idmap.find_many_changeset_ids(ctx, to_resolve).await?;
+            let resolve = |id: &DagId| {
+                cs_ids
+                    .get(id)
+                    .ok_or_else(|| format_err!("{} is missing from the idmap", id))
+            };
+            for (id, parents) in edges {
+                write!(writer, "{}", resolve(&id)?)?;
+                for parent in parents {
+                    write!(writer, " {}", resolve(&parent)?)?;
+                }
+                writeln!(writer)?;
+                count += 1;
+            }
+        }
+        Ok(count)
     }
 }
 
 segmented_changelog_delegate!(OwnedSegmentedChangelog, |&self, ctx: &CoreContext| {
     ReadOnlySegmentedChangelog::new(&self.iddag, self.idmap.clone())
+        .with_clone_segment_chunk_size(self.clone_segment_chunk_size)
 });
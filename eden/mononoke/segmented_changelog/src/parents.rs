@@ -16,6 +16,11 @@ use crate::dag::VertexName;
 use crate::idmap::cs_id_from_vertex_name;
 use crate::idmap::vertex_name_from_cs_id;
 
+/// Fetches the parents of the commits added to the dag, in the order they have in the commit.
+///
+/// The dag assigns ids by walking the parents in that order, so building the dag of the same
+/// heads always assigns the same ids. Location walks follow the first parent at merges, their
+/// answers don't change when the dag is rebuilt either.
 pub struct FetchParents {
     ctx: CoreContext,
     changeset_fetcher: ArcChangesetFetcher,
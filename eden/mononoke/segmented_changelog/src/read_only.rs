@@ -6,6 +6,7 @@
  */
 
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::sync::Arc;
 
@@ -25,9 +26,11 @@ use crate::CloneData;
 use crate::DagId;
 use crate::DagIdSet;
 use crate::FirstAncestorConstraint;
+use crate::FlatSegment;
 use crate::Group;
 use crate::InProcessIdDag;
 use crate::Location;
+use crate::PreparedFlatSegments;
 use crate::SegmentedChangelog;
 
 const IDMAP_CHANGESET_FETCH_BATCH: usize = 500;
@@ -40,6 +43,7 @@ define_stats! {
 pub struct ReadOnlySegmentedChangelog<'a> {
     pub(crate) iddag: &'a InProcessIdDag,
     pub(crate) idmap: Arc<dyn IdMap>,
+    pub(crate) clone_segment_chunk_size: Option<u64>,
 }
 
 #[async_trait]
@@ -197,6 +201,66 @@ impl<'a> SegmentedChangelog for ReadOnlySegmentedChangelog<'a> {
         Ok(pull_data)
     }
 
+    async fn clone_data_since(
+        &self,
+        ctx: &CoreContext,
+        since: ChangesetId,
+    ) -> Result<CloneData<ChangesetId>> {
+        let since_id = self.idmap.get_dag_id(ctx, since).await?;
+        let master_group = self.iddag.master_group()?;
+        let max = match master_group.max() {
+            Some(max) if max >= since_id => max,
+            _ => bail!("{} is not in the master group", since),
+        };
+        let shallow_id_set = master_group.intersection(&DagIdSet::from_spans(vec![since_id..=max]));
+        let mut flat_segments = self
+            .iddag
+            .idset_to_flat_segments(shallow_id_set.clone())
+            .context("error during flat segment retrieval")?;
+        // The client follows the first parent of merges, so a merge can't keep its second
+        // parent alone, as the client would then take it for the first one.
+        let cut_merge = flat_segments.segments.iter().find(|segment| {
+            segment.parents.len() > 1
+                && !shallow_id_set.contains(segment.parents[0])
+                && segment.parents.iter().any(|p| shallow_id_set.contains(*p))
+        });
+        if let Some(segment) = cut_merge {
+            let merge = self.idmap.get_changeset_id(ctx, segment.low).await?;
+            bail!(
+                "cannot cut history at {}: the first parent of merge {} is below it, but not \
+                 its other parents",
+                since,
+                merge
+            );
+        }
+        // The client doesn't know about the parents outside of the shallow range, the segments
+        // having them become roots.
+        flat_segments.segments = flat_segments
+            .segments
+            .into_iter()
+            .map(|mut segment| {
+                segment.parents.retain(|p| shallow_id_set.contains(*p));
+                segment
+            })
+            .collect();
+        if let Some(chunk_size) = self.clone_segment_chunk_size {
+            flat_segments = chunk_flat_segments(flat_segments, chunk_size);
+        }
+
+        let ids = flat_segments.parents_head_and_roots().into_iter().collect();
+        let idmap = self
+            .idmap
+            .find_many_changeset_ids(ctx, ids)
+            .await
+            .context("error retrieving mappings for parents_head_and_roots")?;
+
+        let clone_data = CloneData {
+            flat_segments,
+            idmap: idmap.into_iter().collect(),
+        };
+        Ok(clone_data)
+    }
+
     /// Test if `ancestor` is an ancestor of `descendant`.
     /// Returns None in case segmented changelog doesn't know about either of those commit.
     async fn is_ancestor(
@@ -229,6 +293,61 @@ impl<'a> SegmentedChangelog for ReadOnlySegmentedChangelog<'a> {
         Ok(Some(self.iddag.is_ancestor(*ancestor_id, *descendant_id)?))
     }
 
+    async fn first_parent_ancestor(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+        n: u64,
+    ) -> Result<Option<ChangesetId>> {
+        let dag_id = self.idmap.get_dag_id(ctx, cs_id).await?;
+        match self.iddag.try_first_ancestor_nth(dag_id, n)? {
+            Some(ancestor_id) => Ok(Some(self.idmap.get_changeset_id(ctx, ancestor_id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn ancestors_within(
+        &self,
+        ctx: &CoreContext,
+        head: ChangesetId,
+        max_distance: u64,
+    ) -> Result<Vec<ChangesetId>> {
+        let head_id = self.idmap.get_dag_id(ctx, head).await?;
+        // Breadth-first, so that every commit is reached through its shortest path from head.
+        let mut reached = BTreeSet::new();
+        reached.insert(head_id);
+        let mut frontier = vec![head_id];
+        for _ in 0..max_distance {
+            let mut next = vec![];
+            for dag_id in frontier {
+                for parent in self.iddag.parent_ids(dag_id)? {
+                    if reached.insert(parent) {
+                        next.push(parent);
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+
+        // Ancestors have lower ids than their descendants.
+        let dag_ids: Vec<DagId> = reached.into_iter().rev().collect();
+        let mut cs_ids = self
+            .idmap
+            .find_many_changeset_ids(ctx, dag_ids.clone())
+            .await?;
+        dag_ids
+            .into_iter()
+            .map(|dag_id| {
+                cs_ids.remove(&dag_id).ok_or_else(|| {
+                    format_err!("Failed to find segmented changelog id {} in IdMap", dag_id)
+                })
+            })
+            .collect()
+    }
+
     async fn disabled(&self, _ctx: &CoreContext) -> Result<bool> {
         Ok(false)
     }
@@ -236,7 +355,19 @@ impl<'a> SegmentedChangelog for ReadOnlySegmentedChangelog<'a> {
 
 impl<'a> ReadOnlySegmentedChangelog<'a> {
     pub fn new(iddag: &'a InProcessIdDag, idmap: Arc<dyn IdMap>) -> Self {
-        Self { iddag, idmap }
+        Self {
+            iddag,
+            idmap,
+            clone_segment_chunk_size: None,
+        }
+    }
+
+    /// Splits the flat segments sent in `clone_data` into segments of at most `chunk_size`
+    /// commits. Smaller segments take more memory on the client but are faster to rebuild a dag
+    /// from. `None` sends the segments of the iddag as they are.
+    pub fn with_clone_segment_chunk_size(mut self, chunk_size: Option<u64>) -> Self {
+        self.clone_segment_chunk_size = chunk_size;
+        self
     }
 
     pub(crate) async fn known_location_to_many_changeset_ids(
@@ -281,17 +412,25 @@ impl<'a> ReadOnlySegmentedChangelog<'a> {
         mut hints: HashMap<DagId, (ChangesetId, HgChangesetId)>,
     ) -> Result<(CloneData<ChangesetId>, HashMap<ChangesetId, HgChangesetId>)> {
         let group = Group::MASTER;
-        let flat_segments = self
+        let mut flat_segments = self
             .iddag
             .flat_segments(group)
             .context("error during flat segment retrieval")?;
+        if let Some(chunk_size) = self.clone_segment_chunk_size {
+            flat_segments = chunk_flat_segments(flat_segments, chunk_size);
+        }
         let (idmap, hints) = {
-            let universal_ids: Vec<_> = self
+            let mut universal_ids: BTreeSet<_> = self
                 .iddag
                 .universal_ids()
                 .context("error computing universal ids")?
                 .into_iter()
                 .collect();
+            if self.clone_segment_chunk_size.is_some() {
+                // The ends of the chunks are sent along with the usual universal ids, so that
+                // the client can name every segment boundary.
+                universal_ids.extend(flat_segments.parents_head_and_roots());
+            }
             let mut to_fetch: Vec<_> =
                 Vec::with_capacity(universal_ids.len().saturating_sub(hints.len()));
 
@@ -325,3 +464,28 @@ impl<'a> ReadOnlySegmentedChangelog<'a> {
         Ok((clone_data, hints))
     }
 }
+
+/// Splits the segments of more than `chunk_size` ids, every chunk having the previous one as its
+/// only parent. A dag built from the result is the same as one built from `segments`.
+fn chunk_flat_segments(segments: PreparedFlatSegments, chunk_size: u64) -> PreparedFlatSegments {
+    let chunk_size = chunk_size.max(1);
+    let mut chunked = PreparedFlatSegments::default();
+    for segment in segments.segments {
+        let mut low = segment.low.0;
+        let mut parents = segment.parents;
+        loop {
+            let high = segment.high.0.min(low.saturating_add(chunk_size - 1));
+            chunked.segments.insert(FlatSegment {
+                low: DagId(low),
+                high: DagId(high),
+                parents,
+            });
+            if high == segment.high.0 {
+                break;
+            }
+            parents = vec![DagId(high)];
+            low = high + 1;
+        }
+    }
+    chunked
+}
@@ -33,6 +33,7 @@ use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 use futures_stats::TimedFutureExt;
 use metaconfig_types::MetadataDatabaseConfig;
+use mononoke_types::ChangesetId;
 use mononoke_types::Generation;
 use mononoke_types::RepositoryId;
 use phases::PhasesArc;
@@ -44,13 +45,17 @@ use sql_ext::facebook::MysqlOptions;
 use sql_ext::replication::NoReplicaLagMonitor;
 use sql_ext::replication::ReplicaLagMonitor;
 use stats::prelude::*;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
 use tunables::tunables;
 
 use crate::dag::ops::DagAddHeads;
 use crate::dag::DagAlgorithm;
+use crate::dag::VertexListWithOptions;
 use crate::iddag::IdDagSaveStore;
 use crate::idmap::cs_id_from_vertex_name;
 use crate::idmap::CacheHandlers;
+use crate::idmap::ConcurrentMemIdMap;
 use crate::idmap::IdMapFactory;
 use crate::owned::OwnedSegmentedChangelog;
 use crate::parents::FetchParents;
@@ -59,6 +64,7 @@ use crate::types::SegmentedChangelogVersion;
 use crate::update::server_namedag;
 use crate::update::vertexlist_from_seedheads;
 use crate::update::SeedHead;
+use crate::update::ServerNameDag;
 use crate::version_store::SegmentedChangelogVersionStore;
 use crate::CloneHints;
 use crate::InProcessIdDag;
@@ -82,6 +88,31 @@ define_stats! {
 
 const DEFAULT_LOG_SAMPLING_RATE: usize = 5000;
 
+/// After failed updates, `run_forever` waits at most this many periods before trying again.
+const MAX_BACKOFF_PERIODS: u32 = 32;
+
+/// Approximate size of an idmap row: the repo id, the idmap version, the dag id and the
+/// changeset id.
+const IDMAP_ENTRY_BYTES: u64 = 4 + 4 + 8 + 32;
+
+/// What seeding the repo would produce, as computed by `SegmentedChangelogTailer::dry_run`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeedEstimate {
+    /// Number of commits assigned an id.
+    pub vertexes: u64,
+    /// Number of flat segments covering the commits.
+    pub segments: u64,
+    /// Approximate size of the idmap entries written.
+    pub idmap_bytes: u64,
+}
+
+/// A head to build the dag up to is not a changeset of the repo.
+#[derive(Debug, Error)]
+#[error("segmented changelog head {cs_id} is not a known changeset")]
+pub struct UnknownHead {
+    pub cs_id: ChangesetId,
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum OperationMode {
     ContinousIncrementalUpdate(Duration),
@@ -226,42 +257,9 @@ impl SegmentedChangelogTailer {
             }
             debug!(ctx.logger(), "woke up to update");
 
-            STATS::count.add_value(1);
-            STATS::count_per_repo.add_value(1, (self.repo_id.id(),));
-
-            let (stats, update_result) = self
-                .once(ctx, mode == OperationMode::ForceReseed)
-                .timed()
+            let _ = self
+                .update_and_log(ctx, mode == OperationMode::ForceReseed)
                 .await;
-
-            STATS::duration_ms.add_value(stats.completion_time.as_millis() as i64);
-            STATS::duration_ms_per_repo.add_value(
-                stats.completion_time.as_millis() as i64,
-                (self.repo_id.id(),),
-            );
-
-            let mut scuba = ctx.scuba().clone();
-            scuba.add_future_stats(&stats);
-            scuba.add("repo_id", self.repo_id.id());
-            scuba.add("success", update_result.is_ok());
-
-            let msg = match update_result {
-                Ok(_) => {
-                    STATS::success.add_value(1);
-                    STATS::success_per_repo.add_value(1, (self.repo_id.id(),));
-                    None
-                }
-                Err(err) => {
-                    STATS::failure.add_value(1);
-                    STATS::failure_per_repo.add_value(1, (self.repo_id.id(),));
-                    error!(
-                        ctx.logger(),
-                        "failed to incrementally update segmented changelog: {:?}", err
-                    );
-                    Some(format!("{:?}", err))
-                }
-            };
-            scuba.log_with_msg("segmented_changelog_tailer_update", msg);
             if mode == OperationMode::SingleIncrementalUpdate || mode == OperationMode::ForceReseed
             {
                 break;
@@ -269,6 +267,72 @@ impl SegmentedChangelogTailer {
         }
     }
 
+    /// Runs an incremental update every `period` until `stop` is cancelled. An update that is
+    /// running when `stop` is cancelled completes before returning.
+    ///
+    /// The first update runs right away, and seeds the repo if it has no segmented changelog
+    /// yet, so this is all a job maintaining the segmented changelog of a repo needs to run.
+    ///
+    /// Failed updates don't stop the loop. Instead the wait before the next update doubles,
+    /// up to `MAX_BACKOFF_PERIODS` periods, until an update succeeds again.
+    pub async fn run_forever(&self, ctx: &CoreContext, period: Duration, stop: CancellationToken) {
+        STATS::success.add_value(0);
+        STATS::success_per_repo.add_value(0, (self.repo_id.id(),));
+
+        let mut wait = Duration::ZERO;
+        loop {
+            tokio::select! {
+                _ = stop.cancelled() => break,
+                _ = tokio::time::sleep(wait) => {}
+            }
+            debug!(ctx.logger(), "woke up to update");
+
+            wait = match self.update_and_log(ctx, false).await {
+                Ok(()) => period,
+                Err(_) => (wait * 2).clamp(period, period * MAX_BACKOFF_PERIODS),
+            };
+        }
+        info!(ctx.logger(), "segmented changelog tailer stopped");
+    }
+
+    async fn update_and_log(&self, ctx: &CoreContext, force_reseed: bool) -> Result<()> {
+        STATS::count.add_value(1);
+        STATS::count_per_repo.add_value(1, (self.repo_id.id(),));
+
+        let (stats, update_result) = self.once(ctx, force_reseed).timed().await;
+
+        STATS::duration_ms.add_value(stats.completion_time.as_millis() as i64);
+        STATS::duration_ms_per_repo.add_value(
+            stats.completion_time.as_millis() as i64,
+            (self.repo_id.id(),),
+        );
+
+        let mut scuba = ctx.scuba().clone();
+        scuba.add_future_stats(&stats);
+        scuba.add("repo_id", self.repo_id.id());
+        scuba.add("success", update_result.is_ok());
+
+        let (result, msg) = match update_result {
+            Ok(_) => {
+                STATS::success.add_value(1);
+                STATS::success_per_repo.add_value(1, (self.repo_id.id(),));
+                (Ok(()), None)
+            }
+            Err(err) => {
+                STATS::failure.add_value(1);
+                STATS::failure_per_repo.add_value(1, (self.repo_id.id(),));
+                error!(
+                    ctx.logger(),
+                    "failed to incrementally update segmented changelog: {:?}", err
+                );
+                let msg = format!("{:?}", err);
+                (Err(err), Some(msg))
+            }
+        };
+        scuba.log_with_msg("segmented_changelog_tailer_update", msg);
+        result
+    }
+
     pub async fn once(
         &self,
         ctx: &CoreContext,
@@ -321,78 +385,12 @@ impl SegmentedChangelogTailer {
 
         let mut namedag = server_namedag(ctx.clone(), iddag, idmap)?;
 
-        let heads =
-            vertexlist_from_seedheads(ctx, &self.seed_heads, self.bookmarks.as_ref()).await?;
+        let heads = self.heads(ctx).await?;
 
-        let head_commits: Vec<_> = namedag
-            .heads(namedag.master_group().await?)
-            .await?
-            .iter()
-            .await?
-            .map_ok(|name| cs_id_from_vertex_name(&name))
-            .try_collect()
-            .await?;
-
-        let changeset_fetcher = {
-            let namedag_max_gen = stream::iter(head_commits.iter().map(Ok::<_, Error>))
-                .try_fold(0, {
-                    let fetcher = &self.changeset_fetcher;
-                    move |max, cs_id| async move {
-                        let gen = fetcher.get_generation_number(ctx, *cs_id).await?;
-                        Ok(max.max(gen.value()))
-                    }
-                })
-                .await?;
-            let heads_min_gen = stream::iter(
-                heads
-                    .vertexes()
-                    .iter()
-                    .map(|name| Ok::<_, Error>(cs_id_from_vertex_name(name))),
-            )
-            .try_fold(Generation::max_gen().value(), {
-                let fetcher = &self.changeset_fetcher;
-                move |min, cs_id| async move {
-                    let gen = fetcher.get_generation_number(ctx, cs_id).await?;
-                    Ok(min.min(gen.value()))
-                }
-            })
+        let changeset_fetcher = self
+            .changeset_fetcher_for_update(ctx, &namedag, &heads)
             .await?;
 
-            if heads_min_gen.saturating_sub(namedag_max_gen) > 1000 {
-                let repo_bounds = self
-                    .bulk_fetch
-                    .get_repo_bounds_after_commits(ctx, head_commits)
-                    .await?;
-                info!(ctx.logger(), "prefetching changeset entries",);
-                let mut counter = 0usize;
-                // This has the potential to cause OOM by fetching a large
-                // chunk of the repo
-                let missing = self
-                    .bulk_fetch
-                    .fetch_bounded(ctx, Direction::NewestFirst, Some(repo_bounds))
-                    .map(|res| {
-                        counter += 1;
-                        let sampling_rate =
-                            tunables().get_segmented_changelog_tailer_log_sampling_rate();
-                        let sampling_rate = if sampling_rate <= 0 {
-                            DEFAULT_LOG_SAMPLING_RATE
-                        } else {
-                            sampling_rate as usize
-                        };
-                        if counter % sampling_rate == 0 {
-                            info!(
-                                ctx.logger(),
-                                "fetched {} changeset entries in total", counter,
-                            );
-                        }
-                        res
-                    });
-                Arc::new(self.changeset_fetcher.clone_with_extension(missing).await?)
-            } else {
-                self.changeset_fetcher.clone()
-            }
-        };
-
         let parent_fetcher = FetchParents::new(ctx.clone(), changeset_fetcher);
 
         info!(ctx.logger(), "starting the actual update");
@@ -464,4 +462,127 @@ impl SegmentedChangelogTailer {
         let owned = OwnedSegmentedChangelog::new(iddag, idmap);
         Ok(owned)
     }
+
+    /// Estimates the cost of reseeding the repo, by building the dag up to the seed heads in
+    /// memory. Nothing is written to the idmap, the iddag store or the version store.
+    pub async fn dry_run(&self, ctx: &CoreContext) -> Result<SeedEstimate> {
+        info!(ctx.logger(), "starting segmented changelog seeding dry run");
+
+        let idmap = Arc::new(ConcurrentMemIdMap::new());
+        let mut namedag = server_namedag(ctx.clone(), InProcessIdDag::new_in_process(), idmap)?;
+
+        let heads = self.heads(ctx).await?;
+        let changeset_fetcher = self
+            .changeset_fetcher_for_update(ctx, &namedag, &heads)
+            .await?;
+        let parent_fetcher = FetchParents::new(ctx.clone(), changeset_fetcher);
+        namedag.add_heads(&parent_fetcher, &heads).await?;
+
+        let (_, iddag) = namedag.into_idmap_dag();
+        let all = iddag.all()?;
+        let vertexes = all.count();
+        let segments = iddag.idset_to_flat_segments(all)?.segments.len() as u64;
+        let estimate = SeedEstimate {
+            vertexes,
+            segments,
+            idmap_bytes: vertexes * IDMAP_ENTRY_BYTES,
+        };
+        info!(
+            ctx.logger(),
+            "segmented changelog seeding estimate: {:?}", estimate
+        );
+        Ok(estimate)
+    }
+
+    /// Returns the changeset fetcher to use for adding `heads` to `namedag`. When the heads
+    /// are far ahead of the dag, the missing changeset entries are prefetched in bulk.
+    async fn changeset_fetcher_for_update(
+        &self,
+        ctx: &CoreContext,
+        namedag: &ServerNameDag,
+        heads: &VertexListWithOptions,
+    ) -> Result<Arc<PrefetchedChangesetsFetcher>> {
+        let head_commits: Vec<_> = namedag
+            .heads(namedag.master_group().await?)
+            .await?
+            .iter()
+            .await?
+            .map_ok(|name| cs_id_from_vertex_name(&name))
+            .try_collect()
+            .await?;
+
+        let namedag_max_gen = stream::iter(head_commits.iter().map(Ok::<_, Error>))
+            .try_fold(0, {
+                let fetcher = &self.changeset_fetcher;
+                move |max, cs_id| async move {
+                    let gen = fetcher.get_generation_number(ctx, *cs_id).await?;
+                    Ok(max.max(gen.value()))
+                }
+            })
+            .await?;
+        let heads_min_gen = stream::iter(
+            heads
+                .vertexes()
+                .iter()
+                .map(|name| Ok::<_, Error>(cs_id_from_vertex_name(name))),
+        )
+        .try_fold(Generation::max_gen().value(), {
+            let fetcher = &self.changeset_fetcher;
+            move |min, cs_id| async move {
+                let gen = fetcher.get_generation_number(ctx, cs_id).await?;
+                Ok(min.min(gen.value()))
+            }
+        })
+        .await?;
+
+        if heads_min_gen.saturating_sub(namedag_max_gen) > 1000 {
+            let repo_bounds = self
+                .bulk_fetch
+                .get_repo_bounds_after_commits(ctx, head_commits)
+                .await?;
+            info!(ctx.logger(), "prefetching changeset entries",);
+            let mut counter = 0usize;
+            // This has the potential to cause OOM by fetching a large
+            // chunk of the repo
+            let missing = self
+                .bulk_fetch
+                .fetch_bounded(ctx, Direction::NewestFirst, Some(repo_bounds))
+                .map(|res| {
+                    counter += 1;
+                    let sampling_rate =
+                        tunables().get_segmented_changelog_tailer_log_sampling_rate();
+                    let sampling_rate = if sampling_rate <= 0 {
+                        DEFAULT_LOG_SAMPLING_RATE
+                    } else {
+                        sampling_rate as usize
+                    };
+                    if counter % sampling_rate == 0 {
+                        info!(
+                            ctx.logger(),
+                            "fetched {} changeset entries in total", counter,
+                        );
+                    }
+                    res
+                });
+            Ok(Arc::new(
+                self.changeset_fetcher.clone_with_extension(missing).await?,
+            ))
+        } else {
+            Ok(self.changeset_fetcher.clone())
+        }
+    }
+
+    /// Resolves the seed heads, checking that they are all known changesets.
+    async fn heads(&self, ctx: &CoreContext) -> Result<VertexListWithOptions> {
+        let heads =
+            vertexlist_from_seedheads(ctx, &self.seed_heads, self.bookmarks.as_ref()).await?;
+        for head in heads.vertexes() {
+            let cs_id = cs_id_from_vertex_name(&head);
+            let entry = self.changeset_fetcher.find_cs_entry(ctx, cs_id).await?;
+            if entry.is_none() {
+                return Err(UnknownHead { cs_id }.into());
+            }
+        }
+        Ok(heads)
+    }
 }
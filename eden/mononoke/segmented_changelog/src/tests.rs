@@ -5,6 +5,8 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::HashSet;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -13,6 +15,8 @@ use anyhow::Context;
 use anyhow::Result;
 use blobrepo::AsBlobRepo;
 use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use blobstore::BlobstoreBytes;
 use bonsai_hg_mapping::BonsaiHgMappingArc;
 use bookmarks::BookmarkName;
 use bookmarks::BookmarksArc;
@@ -35,11 +39,13 @@ use fixtures::MergeUneven;
 use fixtures::TestRepoFixture;
 use fixtures::UnsharedMergeEven;
 use futures::compat::Stream01CompatExt;
+use futures::future::join;
 use futures::future::try_join_all;
 use futures::future::FutureExt;
 use futures::stream;
 use futures::StreamExt;
 use maplit::hashmap;
+use mercurial_types::HgChangesetId;
 use mononoke_types::ChangesetId;
 use mononoke_types::RepositoryId;
 use once_cell::sync::Lazy;
@@ -50,32 +56,48 @@ use sql_construct::SqlConstruct;
 use sql_ext::replication::NoReplicaLagMonitor;
 use tests_utils::resolve_cs_id;
 use tests_utils::CreateCommitContext;
+use tokio_util::sync::CancellationToken;
 use tunables::override_tunables;
 use tunables::with_tunables_async;
 
+use crate::builder::new_disabled_segmented_changelog;
 use crate::builder::SegmentedChangelogSqlConnections;
+use crate::dag::IdDagStore;
+use crate::hg_changeset_id_to_location;
 use crate::iddag::IdDagSaveStore;
+use crate::iddag::IdDagStat;
+use crate::iddag::VersionMismatch;
 use crate::idmap::CacheHandlers;
 use crate::idmap::ConcurrentMemIdMap;
 use crate::idmap::IdMap;
 use crate::idmap::IdMapFactory;
 use crate::idmap::SqlIdMap;
+use crate::location_to_hg_changeset_id;
 use crate::manager::SegmentedChangelogManager;
 use crate::manager::SegmentedChangelogType;
+use crate::on_demand::compact_iddag;
+use crate::on_demand::segment_count;
 use crate::on_demand::OnDemandUpdateSegmentedChangelog;
 use crate::owned::OwnedSegmentedChangelog;
 use crate::periodic_reload::PeriodicReloadSegmentedChangelog;
 use crate::tailer::SegmentedChangelogTailer;
+use crate::tailer::UnknownHead;
 use crate::types::IdDagVersion;
 use crate::types::IdMapVersion;
 use crate::types::SegmentedChangelogVersion;
 use crate::version_store::SegmentedChangelogVersionStore;
+use crate::CloneData;
 use crate::CloneHints;
+use crate::DagId;
+use crate::DagIdSet;
+use crate::DualSegmentedChangelog;
 use crate::InProcessIdDag;
 use crate::Location;
 use crate::SeedHead;
 use crate::SegmentedChangelog;
+use crate::SegmentedChangelogDisabled;
 use crate::SegmentedChangelogRef;
+use crate::UnknownHgChangeset;
 
 #[async_trait::async_trait]
 trait SegmentedChangelogExt {
@@ -341,6 +363,77 @@ async fn test_iddag_save_store(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_iddag_save_store_stat(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+    let repo_id = blobrepo.get_repoid();
+
+    let known_cs =
+        resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, known_cs).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    let blobstore = Arc::new(blobrepo.get_blobstore());
+    let iddag_save_store = IdDagSaveStore::new(repo_id, blobstore.clone());
+    let iddag_version = iddag_save_store.save(&ctx, &sc.iddag).await?;
+
+    let blob = blobstore
+        .get(&ctx, &iddag_save_store.key(iddag_version))
+        .await?
+        .context("the saved iddag should be in the blobstore")?;
+    assert_eq!(
+        iddag_save_store.stat(&ctx, iddag_version).await?,
+        Some(IdDagStat {
+            size: blob.len() as u64
+        })
+    );
+    assert_eq!(
+        iddag_save_store
+            .stat(&ctx, IdDagVersion::from_serialized_bytes(b"random"))
+            .await?,
+        None
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_iddag_save_store_corrupted_blob(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+    let repo_id = blobrepo.get_repoid();
+
+    let known_cs =
+        resolve_cs_id(&ctx, &blobrepo, "d0a361e9022d226ae52f689667bd7d212a19cfe0").await?;
+    seed(&ctx, &blobrepo, &conns, known_cs).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    let blobstore = Arc::new(blobrepo.get_blobstore());
+    let iddag_save_store = IdDagSaveStore::new(repo_id, blobstore.clone());
+    let iddag_version = iddag_save_store.save(&ctx, &sc.iddag).await?;
+
+    // Tamper with the saved blob.
+    let tampered = BlobstoreBytes::from_bytes(b"corrupted".to_vec());
+    blobstore
+        .put(&ctx, iddag_save_store.key(iddag_version), tampered)
+        .await?;
+
+    let err = iddag_save_store
+        .load(&ctx, iddag_version)
+        .await
+        .expect_err("loading a tampered blob should fail");
+    let mismatch = err
+        .downcast_ref::<VersionMismatch>()
+        .expect("error should be a version mismatch");
+    assert_eq!(mismatch.expected, iddag_version);
+    assert_ne!(mismatch.actual, iddag_version);
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_build_idmap(fb: FacebookInit) -> Result<()> {
     let ctx = CoreContext::test_mock(fb);
@@ -392,6 +485,109 @@ async fn test_is_ancestor(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_location_stable_across_rebuilds(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = MergeUneven::getrepo(fb).await;
+    let merge = resolve_cs_id(&ctx, &blobrepo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await?;
+    let p2 = resolve_cs_id(&ctx, &blobrepo, "16839021e338500b3cf7c9b871c8a07351697d68").await?;
+
+    let mut builds = vec![];
+    for _ in 0..2 {
+        let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+        seed(&ctx, &blobrepo, &conns, merge).await?;
+        let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+        let mut answers = vec![];
+        for distance in 0..10 {
+            let location = Location::new(merge, distance);
+            answers.push(sc.location_to_changeset_id(&ctx, location).await?);
+        }
+        for distance in 0..4 {
+            let location = Location::new(p2, distance);
+            answers.push(sc.location_to_changeset_id(&ctx, location).await?);
+        }
+        let dag_ids = sc.idmap.find_many_dag_ids(&ctx, answers.clone()).await?;
+        builds.push((answers, dag_ids));
+    }
+    assert_eq!(builds[0], builds[1]);
+
+    // The walk from the merge goes through its first parent.
+    let p1 = resolve_cs_id(&ctx, &blobrepo, "264f01429683b3dd8042cb3979e8bf37007118bc").await?;
+    assert_eq!(builds[0].0[1], p1);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_first_parent_ancestor(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = MergeUneven::getrepo(fb).await;
+    let merge = resolve_cs_id(&ctx, &blobrepo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await?;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+    seed(&ctx, &blobrepo, &conns, merge).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    // The first parent of the merge is the long branch, the second parent is the short one.
+    let expected = [
+        (0, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb"), // message: Merge two branches
+        (1, "264f01429683b3dd8042cb3979e8bf37007118bc"), // message: Add 5
+        (3, "fc2cef43395ff3a7b28159007f63d6529d2f41ca"), // message: Add 3
+        (9, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c"), // message: base
+    ];
+    for (n, hg_cs_id) in expected {
+        let cs_id = resolve_cs_id(&ctx, &blobrepo, hg_cs_id).await?;
+        assert_eq!(sc.first_parent_ancestor(&ctx, merge, n).await?, Some(cs_id));
+    }
+    assert_eq!(sc.first_parent_ancestor(&ctx, merge, 10).await?, None);
+
+    // Starting from the second parent follows the short branch down to the base.
+    let p2 = resolve_cs_id(&ctx, &blobrepo, "16839021e338500b3cf7c9b871c8a07351697d68").await?;
+    let base = resolve_cs_id(&ctx, &blobrepo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await?;
+    assert_eq!(sc.first_parent_ancestor(&ctx, p2, 3).await?, Some(base));
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_ancestors_within(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = MergeUneven::getrepo(fb).await;
+    let merge = resolve_cs_id(&ctx, &blobrepo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await?;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+    seed(&ctx, &blobrepo, &conns, merge).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    assert_eq!(sc.ancestors_within(&ctx, merge, 0).await?, vec![merge]);
+
+    // Both parents of the merge are followed, and both branches are included.
+    let expected = [
+        "d35b1875cdd1ed2c687e86f1604b9d7e989450cb", // merge
+        "264f01429683b3dd8042cb3979e8bf37007118bc", // p1
+        "5d43888a3c972fe68c224f93d41b30e9f888df7c", // p1~1
+        "16839021e338500b3cf7c9b871c8a07351697d68", // p2
+        "1d8a907f7b4bf50c6a09c16361e2205047ecc5e5", // p2~1
+    ];
+    let mut expected_cs_ids = HashSet::new();
+    for hg_cs_id in expected {
+        expected_cs_ids.insert(resolve_cs_id(&ctx, &blobrepo, hg_cs_id).await?);
+    }
+    let ancestors = sc.ancestors_within(&ctx, merge, 2).await?;
+    assert_eq!(ancestors[0], merge);
+    assert_eq!(ancestors.len(), expected_cs_ids.len());
+    assert_eq!(
+        ancestors.into_iter().collect::<HashSet<_>>(),
+        expected_cs_ids
+    );
+
+    // The long branch is 9 commits deep, beyond that there is nothing left to add.
+    let all = sc.ancestors_within(&ctx, merge, 100).await?;
+    assert_eq!(all.len(), 13);
+    assert_eq!(all, sc.ancestors_within(&ctx, merge, 10).await?);
+
+    Ok(())
+}
+
 async fn validate_location_to_changeset_ids(
     ctx: CoreContext,
     blobrepo: BlobRepo,
@@ -456,6 +652,32 @@ async fn test_location_to_changeset_ids(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_location_range(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let head = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, head).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    let range = sc.location_range(&ctx, head, 4, 3).await?;
+    let range_start = range[0];
+    let distances: Vec<_> = range.iter().map(|(distance, _)| *distance).collect();
+    assert_eq!(distances, vec![4, 5, 6]);
+    for (distance, cs_id) in range {
+        let location = Location::new(head, distance);
+        assert_eq!(sc.location_to_changeset_id(&ctx, location).await?, cs_id);
+    }
+    // message: added 7
+    let added_7 =
+        resolve_cs_id(&ctx, &blobrepo, "0ed509bf086fadcb8a8a5384dc3b550729b0fc17").await?;
+    assert_eq!(range_start, (4, added_7));
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_location_to_changeset_id_invalid_req(fb: FacebookInit) -> Result<()> {
     let ctx = CoreContext::test_mock(fb);
@@ -470,12 +692,13 @@ async fn test_location_to_changeset_id_invalid_req(fb: FacebookInit) -> Result<(
             .await
             .is_err()
     );
-    // TODO(T74320664): Ideally LocationToHash should error when asked to go over merge commit.
-    // The parents order is not well defined enough for this not to be ambiguous.
-    assert!(
+    // Going over a merge commit follows its first parent.
+    let first_parent =
+        resolve_cs_id(&ctx, &blobrepo, "33fb49d8a47b29290f5163e30b294339c89505a2").await?;
+    assert_eq!(
         sc.location_to_many_changeset_ids(&ctx, Location::new(known_cs_id, 2u64), 1u64)
-            .await
-            .is_ok()
+            .await?,
+        vec![first_parent]
     );
     let second_commit =
         resolve_cs_id(&ctx, &blobrepo, "1700524113b1a3b1806560341009684b4378660b").await?;
@@ -577,6 +800,48 @@ async fn test_changeset_id_to_location(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_hg_changeset_id_to_location(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+    let bonsai_hg_mapping = blobrepo.bonsai_hg_mapping_arc();
+    let mapping = bonsai_hg_mapping.as_ref();
+
+    let server_master =
+        resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, server_master).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    let master = HgChangesetId::from_str("79a13814c5ce7330173ec04d279bf95ab3f652fb")?;
+    let head = HgChangesetId::from_str("3c15267ebf11807f3d772eb891272b911ec68759")?;
+    let hg_cs_id = HgChangesetId::from_str("0ed509bf086fadcb8a8a5384dc3b550729b0fc17")?;
+
+    for (head, distance) in [(master, 4), (head, 2)] {
+        let location = Location::new(head, distance);
+        assert_eq!(
+            hg_changeset_id_to_location(&ctx, &sc, mapping, vec![head], hg_cs_id).await?,
+            Some(location)
+        );
+        assert_eq!(
+            location_to_hg_changeset_id(&ctx, &sc, mapping, location).await?,
+            hg_cs_id
+        );
+    }
+
+    let unknown = HgChangesetId::from_str("1111111111111111111111111111111111111111")?;
+    assert_eq!(
+        hg_changeset_id_to_location(&ctx, &sc, mapping, vec![master], unknown).await?,
+        None
+    );
+    let err = location_to_hg_changeset_id(&ctx, &sc, mapping, Location::new(unknown, 1))
+        .await
+        .expect_err("the descendant is unknown");
+    assert!(err.is::<UnknownHgChangeset>());
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_changeset_id_to_location_random_hash(fb: FacebookInit) -> Result<()> {
     let ctx = CoreContext::test_mock(fb);
@@ -599,6 +864,76 @@ async fn test_changeset_id_to_location_random_hash(fb: FacebookInit) -> Result<(
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_changeset_id_to_location_multi(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = BranchEven::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    // Graph looks like:
+    // a -> b -> c
+    //  \-> d -> e
+
+    let a = resolve_cs_id(&ctx, &blobrepo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c").await?;
+    let b = resolve_cs_id(&ctx, &blobrepo, "d7542c9db7f4c77dab4b315edd328edf1514952f").await?;
+    let c = resolve_cs_id(&ctx, &blobrepo, "b65231269f651cfe784fd1d97ef02a049a37b8a0").await?;
+    let d = resolve_cs_id(&ctx, &blobrepo, "3cda5c78aa35f0f5b09780d971197b51cad4613a").await?;
+    let e = resolve_cs_id(&ctx, &blobrepo, "1d8a907f7b4bf50c6a09c16361e2205047ecc5e5").await?;
+
+    seed(&ctx, &blobrepo, &conns, c).await?;
+    set_bookmark(
+        fb,
+        &blobrepo,
+        "1d8a907f7b4bf50c6a09c16361e2205047ecc5e5",
+        BOOKMARK_NAME.clone(),
+    )
+    .await;
+    let tailer = new_tailer_for_tailing(&blobrepo, &conns).await?;
+    let _ = tailer.once(&ctx, false).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    // Only reachable from one of the heads.
+    assert_eq!(
+        sc.changeset_id_to_location_multi(&ctx, vec![c, e], d)
+            .await?,
+        Some(Location::new(e, 1))
+    );
+    assert_eq!(
+        sc.changeset_id_to_location_multi(&ctx, vec![c, e], b)
+            .await?,
+        Some(Location::new(c, 1))
+    );
+    // Both heads are at the same distance, the first one wins.
+    assert_eq!(
+        sc.changeset_id_to_location_multi(&ctx, vec![c, e], a)
+            .await?,
+        Some(Location::new(c, 2))
+    );
+    assert_eq!(
+        sc.changeset_id_to_location_multi(&ctx, vec![e, c], a)
+            .await?,
+        Some(Location::new(e, 2))
+    );
+    assert_eq!(
+        sc.changeset_id_to_location_multi(&ctx, vec![c], d).await?,
+        None
+    );
+    // Heads that are not known are skipped.
+    let unknown = mononoke_types_mocks::changesetid::ONES_CSID;
+    assert_eq!(
+        sc.changeset_id_to_location_multi(&ctx, vec![unknown, e], d)
+            .await?,
+        Some(Location::new(e, 1))
+    );
+    assert!(
+        sc.changeset_id_to_location_multi(&ctx, vec![unknown], d)
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_changeset_id_to_location_multiple_heads(fb: FacebookInit) -> Result<()> {
     let ctx = CoreContext::test_mock(fb);
@@ -850,6 +1185,147 @@ async fn test_incremental_update_with_desync_iddag(fb: FacebookInit) -> Result<(
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_extend_to_descendant_of_bookmark(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let bookmark_name = BookmarkName::new("extend_to")?;
+
+    let start_hg_id = "607314ef579bd2407752361ba1b0c1729d08b281"; // commit 4
+    let start_cs = resolve_cs_id(&ctx, &blobrepo, start_hg_id).await?;
+    set_bookmark(fb, &blobrepo, start_hg_id, bookmark_name.clone()).await;
+    let master_cs =
+        resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+
+    let sc = OnDemandUpdateSegmentedChangelog::new(
+        ctx.clone(),
+        blobrepo.get_repoid(),
+        InProcessIdDag::new_in_process(),
+        Arc::new(ConcurrentMemIdMap::new()),
+        blobrepo.changeset_fetcher_arc(),
+        blobrepo.bookmarks_arc(),
+        vec![Some(bookmark_name).into()],
+        None,
+    )?;
+
+    sc.extend_to(&ctx, start_cs).await?;
+    assert_eq!(sc.head(&ctx).await?, start_cs);
+    assert_eq!(sc.is_ancestor(&ctx, start_cs, master_cs).await?, None);
+
+    // The bookmark didn't move, but the descendant is added on top of what the dag has.
+    sc.extend_to(&ctx, master_cs).await?;
+    assert_eq!(sc.head(&ctx).await?, master_cs);
+    assert_eq!(sc.is_ancestor(&ctx, start_cs, master_cs).await?, Some(true));
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_on_demand_update_max_segments(fb: FacebookInit) -> Result<()> {
+    const MAX_SEGMENTS: u64 = 2;
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = MergeUneven::getrepo(fb).await;
+    let merge = resolve_cs_id(&ctx, &blobrepo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await?;
+    let p2 = resolve_cs_id(&ctx, &blobrepo, "16839021e338500b3cf7c9b871c8a07351697d68").await?;
+
+    let new_sc = || {
+        OnDemandUpdateSegmentedChangelog::new(
+            ctx.clone(),
+            blobrepo.get_repoid(),
+            InProcessIdDag::new_in_process(),
+            Arc::new(ConcurrentMemIdMap::new()),
+            blobrepo.changeset_fetcher_arc(),
+            blobrepo.bookmarks_arc(),
+            vec![Some(BOOKMARK_NAME.clone()).into()],
+            None,
+        )
+    };
+    let uncapped = new_sc()?;
+    let capped = new_sc()?.with_max_segments(MAX_SEGMENTS);
+
+    // Extending one commit at a time, from the roots up, leaves many small segments behind.
+    // Even compacted, this dag has more than `MAX_SEGMENTS` segments.
+    let mut commits = vec![];
+    let mut ancestors =
+        AncestorsNodeStream::new(ctx.clone(), &blobrepo.changeset_fetcher_arc(), merge).compat();
+    while let Some(cs_id) = ancestors.next().await {
+        commits.push(cs_id?);
+    }
+    let mut updates_above_cap = 0;
+    for cs_id in commits.into_iter().rev() {
+        uncapped.extend_to(&ctx, cs_id).await?;
+        let before = capped.compactions();
+        capped.extend_to(&ctx, cs_id).await?;
+        let after = capped.compactions();
+
+        // The iddag only grows by the cap between compactions, and a compaction leaves it as
+        // small as it gets.
+        let iddag = capped.iddag().await;
+        let count = segment_count(&iddag)?;
+        assert!(count <= after.segments_after_last + MAX_SEGMENTS);
+        if after.count > before.count {
+            assert_eq!(count, segment_count(&compact_iddag(&iddag)?)?);
+            assert_eq!(count, after.segments_after_last);
+        }
+        if count > MAX_SEGMENTS {
+            updates_above_cap += 1;
+        }
+    }
+
+    // The compacted iddag stays above the cap, yet it isn't compacted on every update.
+    let iddag = capped.iddag().await;
+    assert!(segment_count(&compact_iddag(&iddag)?)? > MAX_SEGMENTS);
+    let compactions = capped.compactions().count;
+    assert!(compactions > 0);
+    assert!(compactions < updates_above_cap);
+
+    for (head, distances) in [(merge, 0..10), (p2, 0..4)] {
+        for distance in distances {
+            let location = Location::new(head, distance);
+            assert_eq!(
+                capped.location_to_changeset_id(&ctx, location).await?,
+                uncapped.location_to_changeset_id(&ctx, location).await?,
+            );
+        }
+    }
+    assert_eq!(capped.is_ancestor(&ctx, p2, merge).await?, Some(true));
+    assert_eq!(capped.is_ancestor(&ctx, merge, p2).await?, Some(false));
+
+    Ok(())
+}
+
+/// Checks that `reconstructed` has the same segments as `original` at every level, and
+/// reports the first segment that differs.
+fn assert_reconstructs_identically(
+    original: &InProcessIdDag,
+    reconstructed: &InProcessIdDag,
+) -> Result<()> {
+    let max_level = original.max_level()?;
+    assert_eq!(reconstructed.max_level()?, max_level, "max levels differ");
+    for level in 0..=max_level {
+        let original_segments = original
+            .iter_segments_ascending(DagId::MIN, level)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let reconstructed_segments = reconstructed
+            .iter_segments_ascending(DagId::MIN, level)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let count = original_segments.len().max(reconstructed_segments.len());
+        for index in 0..count {
+            let expected = original_segments.get(index);
+            let actual = reconstructed_segments.get(index);
+            assert!(
+                expected == actual,
+                "segment {} of level {} differs: original {:?}, reconstructed {:?}",
+                index,
+                level,
+                expected,
+                actual
+            );
+        }
+    }
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_clone_data(fb: FacebookInit) -> Result<()> {
     // In this test we first build a dag from scratch and then we reuse the idmap in an ondemand
@@ -863,10 +1339,14 @@ async fn test_clone_data(fb: FacebookInit) -> Result<()> {
 
     let sc = load_owned(&ctx, &blobrepo, &conns).await?;
 
-    let clone_data = sc.clone_data(&ctx).await?;
+    let (clone_data, _) = sc.clone_data(&ctx).await?;
+    // Send the clone data over the wire before using it.
+    let wire_bytes = clone_data.to_wire_bytes()?;
+    let clone_data = CloneData::<ChangesetId>::from_wire_bytes(&wire_bytes)?;
 
     let mut new_iddag = InProcessIdDag::new_in_process();
-    new_iddag.build_segments_from_prepared_flat_segments(&clone_data.0.flat_segments)?;
+    new_iddag.build_segments_from_prepared_flat_segments(&clone_data.flat_segments)?;
+    assert_reconstructs_identically(&sc.iddag, &new_iddag)?;
     let new_owned = OwnedSegmentedChangelog::new(new_iddag, sc.idmap.clone());
 
     let distance: u64 = 4;
@@ -880,6 +1360,319 @@ async fn test_clone_data(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_export_commit_graph(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let head = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, head).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    let mut exported = vec![];
+    let count = sc.export_commit_graph(&ctx, &mut exported).await?;
+    let exported = String::from_utf8(exported)?;
+    let lines: Vec<Vec<&str>> = exported
+        .lines()
+        .map(|line| line.split(' ').collect())
+        .collect();
+    assert_eq!(count, sc.iddag.all()?.count());
+    assert_eq!(lines.len() as u64, count);
+
+    // The root comes first, then every commit has the previous one as its only parent.
+    assert_eq!(lines[0].len(), 1);
+    for pair in lines.windows(2) {
+        assert_eq!(pair[1].len(), 2);
+        assert_eq!(pair[1][1], pair[0][0]);
+    }
+    assert_eq!(lines[lines.len() - 1][0], head.to_string());
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_idmap_content_hash(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let head = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+
+    let mut hashes = vec![];
+    for _ in 0..2 {
+        let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+        seed(&ctx, &blobrepo, &conns, head).await?;
+        let idmap = load_idmap(&ctx, blobrepo.get_repoid(), &conns).await?;
+        hashes.push(idmap.content_hash(&ctx).await?);
+    }
+    assert_eq!(hashes[0], hashes[1]);
+
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+    let smaller_head =
+        resolve_cs_id(&ctx, &blobrepo, "607314ef579bd2407752361ba1b0c1729d08b281").await?;
+    seed(&ctx, &blobrepo, &conns, smaller_head).await?;
+    let idmap = load_idmap(&ctx, blobrepo.get_repoid(), &conns).await?;
+    assert_ne!(idmap.content_hash(&ctx).await?, hashes[0]);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_disabled_segmented_changelog(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let sc = new_disabled_segmented_changelog();
+    let cs_id = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+
+    fn assert_disabled<T: std::fmt::Debug>(res: Result<T>) {
+        let err = res.expect_err("Segmented Changelog is disabled");
+        assert!(err.is::<SegmentedChangelogDisabled>(), "{:?}", err);
+    }
+    assert_disabled(
+        sc.location_to_many_changeset_ids(&ctx, Location::new(cs_id, 1), 1)
+            .await,
+    );
+    assert_disabled(
+        sc.many_changeset_ids_to_locations(&ctx, vec![cs_id], vec![cs_id])
+            .await,
+    );
+    assert_disabled(sc.clone_data(&ctx).await);
+    assert_disabled(sc.pull_data(&ctx, vec![], vec![cs_id]).await);
+    assert_disabled(sc.clone_data_since(&ctx, cs_id).await);
+    assert_disabled(sc.first_parent_ancestor(&ctx, cs_id, 1).await);
+    assert!(sc.disabled(&ctx).await?);
+    assert_eq!(sc.is_ancestor(&ctx, cs_id, cs_id).await?, None);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_dual_segmented_changelog(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let old_head =
+        resolve_cs_id(&ctx, &blobrepo, "607314ef579bd2407752361ba1b0c1729d08b281").await?;
+    seed(&ctx, &blobrepo, &conns, old_head).await?;
+    let old = load_owned(&ctx, &blobrepo, &conns).await?;
+    let old_parent = old.first_parent_ancestor(&ctx, old_head, 1).await?;
+    assert!(old_parent.is_some());
+
+    let new_head =
+        resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, new_head).await?;
+    let new = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    // The new dag is still empty, every query is answered by the old one.
+    let warming = DualSegmentedChangelog::new(
+        OwnedSegmentedChangelog::new(old.iddag.clone(), old.idmap.clone()),
+        OwnedSegmentedChangelog::new(
+            InProcessIdDag::new_in_process(),
+            Arc::new(ConcurrentMemIdMap::new()),
+        ),
+    );
+    assert_eq!(
+        warming.first_parent_ancestor(&ctx, old_head, 1).await?,
+        old_parent
+    );
+    assert_eq!(
+        warming
+            .location_to_changeset_id(&ctx, Location::new(old_head, 1))
+            .await?,
+        old_parent.unwrap()
+    );
+    assert_eq!(
+        warming
+            .is_ancestor(&ctx, old_parent.unwrap(), old_head)
+            .await?,
+        Some(true)
+    );
+
+    // The new dag covers more than the old one, both ranges are answered.
+    let dual = DualSegmentedChangelog::new(old, new);
+    let distance_4 =
+        resolve_cs_id(&ctx, &blobrepo, "0ed509bf086fadcb8a8a5384dc3b550729b0fc17").await?;
+    assert_eq!(
+        dual.location_to_changeset_id(&ctx, Location::new(new_head, 4))
+            .await?,
+        distance_4
+    );
+    assert_eq!(
+        dual.is_ancestor(&ctx, old_head, new_head).await?,
+        Some(true)
+    );
+    assert_eq!(
+        dual.first_parent_ancestor(&ctx, old_head, 1).await?,
+        old_parent
+    );
+    assert_eq!(
+        dual.changeset_id_to_location(&ctx, vec![new_head], distance_4)
+            .await?,
+        Some(Location::new(new_head, 4))
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_clone_data_chunked_segments(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let head = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, head).await?;
+
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+    let idmap = sc.idmap.clone();
+    let chunked_sc = OwnedSegmentedChangelog::new(sc.iddag.clone(), idmap.clone())
+        .with_clone_segment_chunk_size(2);
+
+    let (clone_data, _) = sc.clone_data(&ctx).await?;
+    let (chunked_clone_data, _) = chunked_sc.clone_data(&ctx).await?;
+    let segments = &clone_data.flat_segments.segments;
+    let chunked_segments = &chunked_clone_data.flat_segments.segments;
+    assert!(chunked_segments.len() > segments.len());
+    assert!(chunked_segments.iter().all(|s| s.high.0 - s.low.0 < 2));
+
+    // Chunks larger than any segment leave the segments as they are.
+    let unchunked_sc = OwnedSegmentedChangelog::new(sc.iddag.clone(), idmap.clone())
+        .with_clone_segment_chunk_size(u64::MAX);
+    let (unchunked_clone_data, _) = unchunked_sc.clone_data(&ctx).await?;
+    assert_eq!(unchunked_clone_data.flat_segments, clone_data.flat_segments);
+
+    let mut iddag = InProcessIdDag::new_in_process();
+    iddag.build_segments_from_prepared_flat_segments(&clone_data.flat_segments)?;
+    let mut chunked_iddag = InProcessIdDag::new_in_process();
+    chunked_iddag.build_segments_from_prepared_flat_segments(&chunked_clone_data.flat_segments)?;
+    assert_eq!(iddag.all()?, chunked_iddag.all()?);
+
+    let rebuilt = OwnedSegmentedChangelog::new(iddag, idmap.clone());
+    let chunked_rebuilt = OwnedSegmentedChangelog::new(chunked_iddag, idmap);
+    for distance in 0..10 {
+        let location = Location::new(head, distance);
+        let expected = rebuilt.location_to_changeset_id(&ctx, location).await?;
+        let answer = chunked_rebuilt
+            .location_to_changeset_id(&ctx, location)
+            .await?;
+        assert_eq!(answer, expected);
+    }
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_clone_data_since(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let head = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, head).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    // message: added 7, 4 commits below the head
+    let since = resolve_cs_id(&ctx, &blobrepo, "0ed509bf086fadcb8a8a5384dc3b550729b0fc17").await?;
+    let since_id = sc.idmap.get_dag_id(&ctx, since).await?;
+    let clone_data = sc.clone_data_since(&ctx, since).await?;
+    let segments = &clone_data.flat_segments.segments;
+    assert!(segments.iter().all(|s| s.low >= since_id));
+    assert!(clone_data.idmap.values().any(|cs_id| *cs_id == head));
+    assert!(clone_data.idmap.values().any(|cs_id| *cs_id == since));
+
+    let mut iddag = InProcessIdDag::new_in_process();
+    iddag.build_segments_from_prepared_flat_segments(&clone_data.flat_segments)?;
+    let shallow = OwnedSegmentedChangelog::new(iddag, sc.idmap.clone());
+    for distance in 0..=4 {
+        let location = Location::new(head, distance);
+        let expected = sc.location_to_changeset_id(&ctx, location).await?;
+        let answer = shallow.location_to_changeset_id(&ctx, location).await?;
+        assert_eq!(answer, expected);
+    }
+    // The parent of `since` is not part of the shallow dag.
+    assert!(
+        shallow
+            .location_to_changeset_id(&ctx, Location::new(head, 5))
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_clone_data_since_merge(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = MergeUneven::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let merge = resolve_cs_id(&ctx, &blobrepo, "d35b1875cdd1ed2c687e86f1604b9d7e989450cb").await?;
+    let p1 = resolve_cs_id(&ctx, &blobrepo, "264f01429683b3dd8042cb3979e8bf37007118bc").await?;
+    let p2 = resolve_cs_id(&ctx, &blobrepo, "16839021e338500b3cf7c9b871c8a07351697d68").await?;
+
+    // Built incrementally, the first parent is assigned before the second parent's branch.
+    seed(&ctx, &blobrepo, &conns, p1).await?;
+    set_bookmark(
+        fb,
+        &blobrepo,
+        "d35b1875cdd1ed2c687e86f1604b9d7e989450cb",
+        BOOKMARK_NAME.clone(),
+    )
+    .await;
+    let tailer = new_tailer_for_tailing(&blobrepo, &conns).await?;
+    let _ = tailer.once(&ctx, false).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    let p1_id = sc.idmap.get_dag_id(&ctx, p1).await?;
+    let p2_id = sc.idmap.get_dag_id(&ctx, p2).await?;
+    assert!(p1_id < p2_id);
+
+    // Right above p1, the shallow range has the merge and its second parent, but not p1.
+    let since = sc.idmap.get_changeset_id(&ctx, DagId(p1_id.0 + 1)).await?;
+    assert!(sc.clone_data_since(&ctx, since).await.is_err());
+
+    // From p1, the merge keeps its first parent.
+    let clone_data = sc.clone_data_since(&ctx, p1).await?;
+    let mut iddag = InProcessIdDag::new_in_process();
+    iddag.build_segments_from_prepared_flat_segments(&clone_data.flat_segments)?;
+    let shallow = OwnedSegmentedChangelog::new(iddag, sc.idmap.clone());
+    assert_eq!(
+        shallow
+            .location_to_changeset_id(&ctx, Location::new(merge, 1))
+            .await?,
+        p1
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_pull_data(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let head = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    seed(&ctx, &blobrepo, &conns, head).await?;
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+
+    // The client already has the history up to commit 4.
+    let common = resolve_cs_id(&ctx, &blobrepo, "607314ef579bd2407752361ba1b0c1729d08b281").await?;
+    let common_id = sc.idmap.get_dag_id(&ctx, common).await?;
+    let head_id = sc.idmap.get_dag_id(&ctx, head).await?;
+
+    let pull_data = sc.pull_data(&ctx, vec![common], vec![head]).await?;
+    let segments = &pull_data.flat_segments.segments;
+    let pulled = DagIdSet::from_spans(segments.iter().map(|s| s.low..=s.high));
+    let missing = DagIdSet::from_spans(vec![DagId(common_id.0 + 1)..=head_id]);
+    assert_eq!(pulled, missing);
+    assert!(segments.iter().any(|s| s.parents == vec![common_id]));
+    assert_eq!(pull_data.idmap.get(&common_id), Some(&common));
+    assert_eq!(pull_data.idmap.get(&head_id), Some(&head));
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_caching(fb: FacebookInit) -> Result<()> {
     let ctx = CoreContext::test_mock(fb);
@@ -973,10 +1766,14 @@ async fn test_periodic_update(fb: FacebookInit) -> Result<()> {
     )?;
     let sc =
         Arc::new(on_demand).with_periodic_update_to_master_bookmark(&ctx, Duration::from_secs(5));
+    assert_eq!(sc.last_update_time(), None);
+    assert!(sc.head_lag(&ctx, 100).await? > 0);
 
     tokio::time::advance(Duration::from_secs(10)).await;
     // wait time is over and the first tick is ready to be scheduled
     sc.wait_for_update().await;
+    let first_update_time = sc.last_update_time().expect("dag should have been updated");
+    assert_eq!(sc.head_lag(&ctx, 100).await?, 0);
 
     // We assume that clone_data will not update the graph in any form.
     assert_eq!(sc.head(&ctx).await?, start_cs);
@@ -984,11 +1781,14 @@ async fn test_periodic_update(fb: FacebookInit) -> Result<()> {
     let new_hg_id = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
     let new_cs = resolve_cs_id(&ctx, &blobrepo, new_hg_id).await?;
     set_bookmark(fb, &blobrepo, new_hg_id, bookmark_name.clone()).await;
+    assert!(sc.head_lag(&ctx, 100).await? > 0);
 
     tokio::time::advance(Duration::from_secs(5)).await;
     // second tick is ready to be scheduled
     sc.wait_for_update().await;
     assert_eq!(sc.head(&ctx).await?, new_cs);
+    assert_eq!(sc.head_lag(&ctx, 100).await?, 0);
+    assert!(sc.last_update_time() >= Some(first_update_time));
 
     Ok(())
 }
@@ -1016,6 +1816,118 @@ async fn test_seeder_tailer_and_load(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_seed_unknown_head(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let unknown = mononoke_types_mocks::changesetid::ONES_CSID;
+    let tailer = new_tailer(&blobrepo, &conns, None, Some(vec![unknown])).await?;
+    let err = tailer
+        .once(&ctx, true)
+        .await
+        .err()
+        .expect("seeding an unknown head should fail");
+    let unknown_head = err
+        .downcast_ref::<UnknownHead>()
+        .expect("error should be an unknown head");
+    assert_eq!(unknown_head.cs_id, unknown);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_seed_dry_run(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let head = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    let tailer = new_tailer(&blobrepo, &conns, None, Some(vec![head])).await?;
+    let estimate = tailer.dry_run(&ctx).await?;
+
+    let sc_version_store =
+        SegmentedChangelogVersionStore::new(conns.0.clone(), blobrepo.get_repoid());
+    assert_eq!(sc_version_store.get(&ctx).await?, None);
+
+    seed(&ctx, &blobrepo, &conns, head).await?;
+    let iddag = load_owned(&ctx, &blobrepo, &conns).await?.iddag;
+    let all = iddag.all()?;
+    assert_eq!(estimate.vertexes, all.count());
+    let segments = iddag.idset_to_flat_segments(all)?.segments.len() as u64;
+    assert_eq!(estimate.segments, segments);
+    assert_eq!(estimate.idmap_bytes, estimate.vertexes * 48);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_tailer_run_forever(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let start_cs_id =
+        resolve_cs_id(&ctx, &blobrepo, "607314ef579bd2407752361ba1b0c1729d08b281").await?;
+    seed(&ctx, &blobrepo, &conns, start_cs_id).await?;
+
+    tokio::time::pause();
+    let tailer = new_tailer_for_tailing(&blobrepo, &conns).await?;
+    let stop = CancellationToken::new();
+    let run = tailer.run_forever(&ctx, Duration::from_secs(10), stop.clone());
+    let cancel = async {
+        // Leaves time for a few updates.
+        tokio::time::sleep(Duration::from_secs(35)).await;
+        stop.cancel();
+    };
+    // The tailer returns once it is stopped.
+    tokio::time::timeout(Duration::from_secs(60), join(run, cancel)).await?;
+
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+    let master = resolve_cs_id(&ctx, &blobrepo, "79a13814c5ce7330173ec04d279bf95ab3f652fb").await?;
+    assert_eq!(sc.head(&ctx).await?, master);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_tailer_run_forever_seeds_then_tails(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let start_hg_id = "607314ef579bd2407752361ba1b0c1729d08b281"; // commit 4
+    let start_cs_id = resolve_cs_id(&ctx, &blobrepo, start_hg_id).await?;
+    set_bookmark(fb, &blobrepo, start_hg_id, BOOKMARK_NAME.clone()).await;
+
+    tokio::time::pause();
+    let tailer = new_tailer_for_tailing(&blobrepo, &conns).await?;
+    let stop = CancellationToken::new();
+    let run = tailer.run_forever(&ctx, Duration::from_secs(10), stop.clone());
+    let master_hg_id = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+    let check = async {
+        // The first update seeds the repo.
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+        assert_eq!(sc.head(&ctx).await?, start_cs_id);
+
+        // The following ones tail the bookmark.
+        set_bookmark(fb, &blobrepo, master_hg_id, BOOKMARK_NAME.clone()).await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        stop.cancel();
+        Ok::<_, anyhow::Error>(())
+    };
+    let (_, checked) = tokio::time::timeout(Duration::from_secs(60), join(run, check)).await?;
+    checked?;
+
+    let sc = load_owned(&ctx, &blobrepo, &conns).await?;
+    let master = resolve_cs_id(&ctx, &blobrepo, master_hg_id).await?;
+    assert_eq!(sc.head(&ctx).await?, master);
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_periodic_reload(fb: FacebookInit) -> Result<()> {
     let ctx = CoreContext::test_mock(fb);
@@ -1078,6 +1990,73 @@ async fn test_periodic_reload(fb: FacebookInit) -> Result<()> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_manager_reload_keeps_idmap_cache(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb);
+    let blobrepo = Linear::getrepo(fb).await;
+    let conns = SegmentedChangelogSqlConnections::with_sqlite_in_memory()?;
+
+    let start_cs_id =
+        resolve_cs_id(&ctx, &blobrepo, "607314ef579bd2407752361ba1b0c1729d08b281").await?;
+    seed(&ctx, &blobrepo, &conns, start_cs_id).await?;
+
+    let dag_to_cs_handler = CachelibHandler::create_mock();
+    let cs_to_dag_handler = CachelibHandler::create_mock();
+    let cache_handlers = CacheHandlers::new(
+        dag_to_cs_handler.clone(),
+        cs_to_dag_handler.clone(),
+        MemcacheHandler::create_mock(),
+    );
+    let repo_id = blobrepo.get_repoid();
+    let blobstore = Arc::new(blobrepo.get_blobstore());
+    let idmap_factory =
+        IdMapFactory::new(conns.0.clone(), Arc::new(NoReplicaLagMonitor()), repo_id)
+            .with_cache_handlers(cache_handlers);
+    let manager = SegmentedChangelogManager::new(
+        repo_id,
+        SegmentedChangelogVersionStore::new(conns.0.clone(), repo_id),
+        IdDagSaveStore::new(repo_id, blobstore),
+        idmap_factory,
+        blobrepo.changeset_fetcher_arc(),
+        blobrepo.bookmarks_arc(),
+        vec![],
+        SegmentedChangelogType::Owned,
+        None,
+    );
+
+    let (sc, first_version) = manager.load(&ctx).await?;
+    let expected = sc
+        .location_to_changeset_id(&ctx, Location::new(start_cs_id, 1))
+        .await?;
+
+    // A new iddag version, with the same idmap version.
+    let tailer = new_tailer_for_tailing(&blobrepo, &conns).await?;
+    let _ = tailer.once(&ctx, false).await?;
+    let (sc, second_version) = manager.load(&ctx).await?;
+    assert_ne!(first_version.iddag_version, second_version.iddag_version);
+    assert_eq!(first_version.idmap_version, second_version.idmap_version);
+
+    let answer = sc
+        .location_to_changeset_id(&ctx, Location::new(start_cs_id, 1))
+        .await?;
+    assert_eq!(answer, expected);
+
+    // The idmap of every loaded version goes through the same cache handlers, so the lookups
+    // after the reload are served from the cache warmed before it.
+    let cs_to_dag_stats = cs_to_dag_handler
+        .mock_store()
+        .expect("mock handler has mock store")
+        .stats();
+    let dag_to_cs_stats = dag_to_cs_handler
+        .mock_store()
+        .expect("mock handler has mock store")
+        .stats();
+    assert_eq!((cs_to_dag_stats.misses, cs_to_dag_stats.hits), (1, 1));
+    assert_eq!((dag_to_cs_stats.misses, dag_to_cs_stats.hits), (1, 1));
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_manager_check_if_indexed(fb: FacebookInit) -> Result<()> {
     let ctx = CoreContext::test_mock(fb);
@@ -36,6 +36,7 @@ pub trait SegmentedChangelog: Send + Sync {
     /// The client using segmented changelog will have only a set of identifiers for the commits in
     /// the graph. To retrieve the identifier of an commit that is now known they will provide a
     /// known descendant and the distance from the known commit to the commit we inquire about.
+    /// The walk from the descendant always follows the first parent of merge commits.
     async fn location_to_changeset_id(
         &self,
         ctx: &CoreContext,
@@ -66,6 +67,25 @@ pub trait SegmentedChangelog: Send + Sync {
         count: u64,
     ) -> Result<Vec<ChangesetId>>;
 
+    /// Get the `count` ancestors of `head` starting `start_distance` away from it, each with its
+    /// distance from `head`.
+    ///
+    /// Same as `location_to_many_changeset_ids` with `Location::new(head, start_distance)`,
+    /// with the distances filled in.
+    async fn location_range(
+        &self,
+        ctx: &CoreContext,
+        head: ChangesetId,
+        start_distance: u64,
+        count: u64,
+    ) -> Result<Vec<(u64, ChangesetId)>> {
+        let location = Location::new(head, start_distance);
+        let ids = self
+            .location_to_many_changeset_ids(ctx, location, count)
+            .await?;
+        Ok((start_distance..).zip(ids).collect())
+    }
+
     /// Get the graph location of a given commit identifier.
     ///
     /// ## Practical use-cases
@@ -179,6 +199,41 @@ pub trait SegmentedChangelog: Send + Sync {
         ids.remove(&cs_id).transpose()
     }
 
+    /// Like `changeset_id_to_location`, but the location is always relative to one of `heads`,
+    /// the one closest to `cs_id`. Ties go to the head that comes first in `heads`, so the
+    /// answer doesn't depend on the order of the DAG. Returns None when `cs_id` is not an
+    /// ancestor of any of the heads. As with `many_changeset_ids_to_locations`, the heads that
+    /// are not known are ignored, as long as one of them is known.
+    async fn changeset_id_to_location_multi(
+        &self,
+        ctx: &CoreContext,
+        heads: Vec<ChangesetId>,
+        cs_id: ChangesetId,
+    ) -> Result<Option<Location<ChangesetId>>> {
+        if heads.is_empty() {
+            return Ok(None);
+        }
+        // Every known head can be located relative to the heads, the others are left out.
+        let known_heads = self
+            .many_changeset_ids_to_locations(ctx, heads.clone(), heads.clone())
+            .await?;
+        let mut closest: Option<Location<ChangesetId>> = None;
+        for head in heads {
+            if !known_heads.contains_key(&head) {
+                continue;
+            }
+            let location = self
+                .changeset_id_to_location(ctx, vec![head], cs_id)
+                .await?;
+            if let Some(location) = location {
+                if closest.map_or(true, |closest| location.distance < closest.distance) {
+                    closest = Some(location);
+                }
+            }
+        }
+        Ok(closest)
+    }
+
     /// Get the graph locations given a set of commit identifier.
     ///
     /// Batch variation of `changeset_id_to_location`. The assumption is that we are dealing with
@@ -222,6 +277,19 @@ pub trait SegmentedChangelog: Send + Sync {
         missing: Vec<ChangesetId>,
     ) -> Result<CloneData<ChangesetId>>;
 
+    /// Returns data necessary for a client to initialize a shallow SegmentedChangelog, that only
+    /// has the recent history of the master group.
+    ///
+    /// The commits sent are `since` and the commits of the master group that were assigned an
+    /// id after it, which includes the heads. Parents outside of that range are not sent, so
+    /// the client fails to resolve locations that go past the roots of its dag. Fails if a merge
+    /// in that range has its first parent outside of it, but not its other parents.
+    async fn clone_data_since(
+        &self,
+        ctx: &CoreContext,
+        since: ChangesetId,
+    ) -> Result<CloneData<ChangesetId>>;
+
     /// Whether segmented changelog is disabled.
     ///
     /// A quick way to test if the backend supports segmented changelog or not
@@ -239,6 +307,26 @@ pub trait SegmentedChangelog: Send + Sync {
         descendant: ChangesetId,
     ) -> Result<Option<bool>>;
 
+    /// Get the ancestor `n` first-parent steps away from `cs_id`, `cs_id~n` in revset notation.
+    /// This is the walk `location_to_changeset_id` does, but returns None instead of failing
+    /// when the first-parent chain of `cs_id` is shorter than `n`.
+    async fn first_parent_ancestor(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+        n: u64,
+    ) -> Result<Option<ChangesetId>>;
+
+    /// Get `head` and all its ancestors that are at most `max_distance` parent steps away from
+    /// it. Unlike `location_to_many_changeset_ids`, both parents of merges are followed. The
+    /// commits are sorted from `head` down to the oldest ancestor.
+    async fn ancestors_within(
+        &self,
+        ctx: &CoreContext,
+        head: ChangesetId,
+        max_distance: u64,
+    ) -> Result<Vec<ChangesetId>>;
+
     /// Try update segmented changelog to given heads. No-op by default. Useful
     /// for tests. Returns: `true` if update was successful; `false` if the
     /// implementation doesn't support updates; an error otherwise.
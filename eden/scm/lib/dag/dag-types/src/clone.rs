@@ -7,12 +7,28 @@
 
 use std::collections::BTreeMap;
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 
 use crate::id::Id;
+use crate::segment::FlatSegment;
 use crate::segment::PreparedFlatSegments;
 
+/// Version of the encoding written by `CloneData::to_wire_bytes`. It is the first byte of the
+/// encoded data, and has to be bumped whenever the encoding changes.
+pub const CLONE_DATA_WIRE_VERSION: u8 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CloneDataWireError {
+    #[error("clone data is empty")]
+    Empty,
+    #[error("unsupported clone data wire version {0}")]
+    VersionMismatch(u8),
+    #[error("invalid clone data: {0}")]
+    Encoding(#[from] mincode::Error),
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
 pub struct CloneData<Name> {
@@ -30,6 +46,89 @@ impl<Name> CloneData<Name> {
     }
 }
 
+impl<Name: Serialize + DeserializeOwned> CloneData<Name> {
+    /// Encodes the clone data to send it to another process. The encoding is the one of
+    /// `WireCloneData`, not of the in-process types, and starts with `CLONE_DATA_WIRE_VERSION`.
+    pub fn to_wire_bytes(&self) -> Result<Vec<u8>, CloneDataWireError> {
+        let mut bytes = vec![CLONE_DATA_WIRE_VERSION];
+        mincode::serialize_into(&mut bytes, &WireCloneData::from(self))?;
+        Ok(bytes)
+    }
+
+    /// Decodes clone data encoded by `to_wire_bytes`. Data written with another wire version
+    /// is rejected.
+    pub fn from_wire_bytes(bytes: &[u8]) -> Result<Self, CloneDataWireError> {
+        match bytes.split_first() {
+            None => Err(CloneDataWireError::Empty),
+            Some((&CLONE_DATA_WIRE_VERSION, data)) => {
+                let wire: WireCloneData<Name> = mincode::deserialize(data)?;
+                Ok(wire.into())
+            }
+            Some((&version, _)) => Err(CloneDataWireError::VersionMismatch(version)),
+        }
+    }
+}
+
+/// Wire form of `CloneData`. Changing it requires bumping `CLONE_DATA_WIRE_VERSION`, while the
+/// in-process types can change freely as long as they convert from and to it.
+#[derive(Serialize, Deserialize)]
+struct WireCloneData<Name> {
+    segments: Vec<WireFlatSegment>,
+    idmap: Vec<(u64, Name)>,
+}
+
+/// Wire form of `FlatSegment`.
+#[derive(Serialize, Deserialize)]
+struct WireFlatSegment {
+    low: u64,
+    high: u64,
+    parents: Vec<u64>,
+}
+
+impl<'a, Name> From<&'a CloneData<Name>> for WireCloneData<&'a Name> {
+    fn from(clone_data: &'a CloneData<Name>) -> Self {
+        let segments = clone_data
+            .flat_segments
+            .segments
+            .iter()
+            .map(|segment| WireFlatSegment {
+                low: segment.low.0,
+                high: segment.high.0,
+                parents: segment.parents.iter().map(|parent| parent.0).collect(),
+            })
+            .collect();
+        let idmap = clone_data
+            .idmap
+            .iter()
+            .map(|(id, name)| (id.0, name))
+            .collect();
+        WireCloneData { segments, idmap }
+    }
+}
+
+impl<Name> From<WireCloneData<Name>> for CloneData<Name> {
+    fn from(wire: WireCloneData<Name>) -> Self {
+        let segments = wire
+            .segments
+            .into_iter()
+            .map(|segment| FlatSegment {
+                low: Id(segment.low),
+                high: Id(segment.high),
+                parents: segment.parents.into_iter().map(Id).collect(),
+            })
+            .collect();
+        let idmap = wire
+            .idmap
+            .into_iter()
+            .map(|(id, name)| (Id(id), name))
+            .collect();
+        CloneData {
+            flat_segments: PreparedFlatSegments { segments },
+            idmap,
+        }
+    }
+}
+
 #[cfg(any(test, feature = "for-tests"))]
 use quickcheck::Arbitrary;
 #[cfg(any(test, feature = "for-tests"))]
@@ -50,3 +149,58 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::quickcheck;
+
+    use super::*;
+
+    #[test]
+    fn test_wire_version_mismatch() {
+        let clone_data: CloneData<u64> = CloneData {
+            flat_segments: PreparedFlatSegments::default(),
+            idmap: BTreeMap::new(),
+        };
+        let mut bytes = clone_data.to_wire_bytes().unwrap();
+        bytes[0] = CLONE_DATA_WIRE_VERSION + 1;
+        assert!(matches!(
+            CloneData::<u64>::from_wire_bytes(&bytes),
+            Err(CloneDataWireError::VersionMismatch(v)) if v == CLONE_DATA_WIRE_VERSION + 1
+        ));
+        assert!(matches!(
+            CloneData::<u64>::from_wire_bytes(&[]),
+            Err(CloneDataWireError::Empty)
+        ));
+    }
+
+    #[test]
+    fn test_wire_encoding() {
+        // Changing these bytes breaks the clients of the current version.
+        let segments = [(0, 2, vec![]), (3, 4, vec![2, 1])]
+            .into_iter()
+            .map(|(low, high, parents): (u64, u64, Vec<u64>)| FlatSegment {
+                low: Id(low),
+                high: Id(high),
+                parents: parents.into_iter().map(Id).collect(),
+            })
+            .collect();
+        let clone_data: CloneData<u64> = CloneData {
+            flat_segments: PreparedFlatSegments { segments },
+            idmap: BTreeMap::from([(Id(4), 7)]),
+        };
+        let bytes = clone_data.to_wire_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            [CLONE_DATA_WIRE_VERSION, 2, 0, 2, 0, 3, 4, 2, 2, 1, 1, 4, 7]
+        );
+        assert_eq!(CloneData::from_wire_bytes(&bytes).unwrap(), clone_data);
+    }
+
+    quickcheck! {
+        fn test_wire_roundtrip(clone_data: CloneData<u64>) -> bool {
+            let bytes = clone_data.to_wire_bytes().unwrap();
+            CloneData::from_wire_bytes(&bytes).unwrap() == clone_data
+        }
+    }
+}
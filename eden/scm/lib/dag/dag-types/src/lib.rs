@@ -13,6 +13,7 @@ pub mod location;
 pub mod segment;
 
 pub use clone::CloneData;
+pub use clone::CloneDataWireError;
 pub use id::Bytes;
 pub use id::Group;
 pub use id::Id;
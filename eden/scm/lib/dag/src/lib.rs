@@ -36,6 +36,7 @@ mod vertex_options;
 pub use dag_types::clone;
 pub use dag_types::id;
 pub use dag_types::CloneData;
+pub use dag_types::CloneDataWireError;
 pub use dag_types::Group;
 pub use dag_types::Id;
 pub use dag_types::Location;
@@ -0,0 +1,42 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Correlation ids tie the requests that remote stores send during a prefetch to the
+//! operation that triggered it.
+//!
+//! The id is kept per thread, so that the `RemoteHistoryStore` and `RemoteDataStore` traits
+//! don't need an extra parameter: the caller sets it around a prefetch with
+//! `with_correlation_id`, and remote stores read it with `correlation_id` to tag their
+//! outbound requests. Without a caller setting it, there is no correlation id.
+
+use std::cell::RefCell;
+
+thread_local! {
+    static CORRELATION_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Returns the correlation id of the prefetch running on this thread, if any.
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.with(|id| id.borrow().clone())
+}
+
+/// Runs `f` with `id` as the correlation id of this thread. The previous id is restored
+/// afterwards, even if `f` panics.
+pub fn with_correlation_id<R>(id: Option<String>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<String>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CORRELATION_ID.with(|id| *id.borrow_mut() = previous);
+        }
+    }
+
+    let previous = CORRELATION_ID.with(|current| current.replace(id));
+    let _restore = Restore(previous);
+    f()
+}
@@ -66,6 +66,11 @@ impl RemoteHistoryStore for EdenApiHistoryStore {
 
         block_on(response)
     }
+
+    fn ping(&self) -> Result<()> {
+        block_on(self.remote.client.health())?;
+        Ok(())
+    }
 }
 
 impl HgIdHistoryStore for EdenApiHistoryStore {
@@ -77,6 +82,13 @@ impl HgIdHistoryStore for EdenApiHistoryStore {
     fn refresh(&self) -> Result<()> {
         Ok(())
     }
+
+    /// Fetches the whole batch with a single request.
+    fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+        let store_keys: Vec<_> = keys.iter().cloned().map(StoreKey::hgid).collect();
+        self.prefetch(&store_keys)?;
+        self.store.get_node_info_batch(keys)
+    }
 }
 
 impl LocalStore for EdenApiHistoryStore {
@@ -134,6 +146,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_file_history_batch() -> Result<()> {
+        let (k1, k2, unknown) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let n1 = NodeInfo {
+            parents: [key("b", "2"), null_key("a")],
+            linknode: hgid("3"),
+        };
+        let n2 = NodeInfo {
+            parents: [null_key("b"), null_key("b")],
+            linknode: hgid("4"),
+        };
+        let history = hashmap! { k1.clone() => n1.clone(), k2.clone() => n2.clone() };
+
+        let client = FakeEdenApi::new().history(history).into_arc();
+        let remote = EdenApiRemoteStore::<File>::new(client);
+
+        let tmp = TempDir::new()?;
+        let local = Arc::new(IndexedLogHgIdHistoryStore::new(
+            &tmp,
+            &empty_config(),
+            StoreType::Shared,
+        )?);
+        let edenapi = remote.historystore(local.clone());
+
+        // The results line up with the keys, unknown keys included.
+        let infos = edenapi.get_node_info_batch(&[k2.clone(), unknown, k1.clone()])?;
+        assert_eq!(infos, vec![Some(n2.clone()), None, Some(n1.clone())]);
+
+        assert_eq!(local.get_node_info(&k1)?, Some(n1));
+        assert_eq!(local.get_node_info(&k2)?, Some(n2));
+
+        Ok(())
+    }
+
     #[test]
     #[should_panic]
     fn test_tree_history() {
@@ -14,12 +14,43 @@ use http::status::StatusCode;
 use http_client::HttpClientError;
 use http_client::Method;
 use thiserror::Error;
+use types::Key;
 use url::Url;
 
+use crate::types::StoreKey;
+use crate::unionstore::StoreId;
+
 #[derive(Debug, Error)]
 #[error("Empty Mutable Pack")]
 pub struct EmptyMutablePack;
 
+#[derive(Debug, Error)]
+#[error("Cycle in history: {0} is its own ancestor")]
+pub struct HistoryCycleError(pub Key);
+
+#[derive(Debug, Error)]
+#[error("Stores {first:?} and {other:?} have a different history for {key}")]
+pub struct HistoryMismatchError {
+    pub key: Key,
+    pub first: StoreId,
+    pub other: StoreId,
+}
+
+#[derive(Debug, Error)]
+#[error("{0} is not supported by this store")]
+pub struct UnsupportedError(pub &'static str);
+
+#[derive(Debug, Error)]
+#[error("Timed out after waiting {0:?}")]
+pub struct TimeoutError(pub Duration);
+
+#[derive(Debug, Error)]
+#[error("{} keys couldn't be prefetched, {} stores failed: {:?}", .missing.len(), .errors.len(), .errors)]
+pub struct PrefetchError {
+    pub missing: Vec<StoreKey>,
+    pub errors: Vec<Error>,
+}
+
 #[derive(Error, Debug)]
 #[error("Fetch failed: {} {}", .url, .method)]
 pub struct FetchError {
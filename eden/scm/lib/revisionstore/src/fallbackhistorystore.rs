@@ -0,0 +1,122 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use types::Key;
+use types::NodeInfo;
+
+use crate::historystore::HgIdHistoryStore;
+use crate::historystore::RemoteHistoryStore;
+use crate::localstore::LocalStore;
+use crate::types::StoreKey;
+
+/// A `LoggingFallbackHistoryStore` wraps the last store of a union store, and counts the keys
+/// it is asked for, which are the keys all the previous stores missed. The keys can also be
+/// logged, to find out which ones the caches don't have.
+///
+/// Only `get_node_info` and `get_node_info_batch` lookups are counted. Everything else is
+/// forwarded to the wrapped store as is.
+pub struct LoggingFallbackHistoryStore<T> {
+    inner: T,
+    log_misses: bool,
+    misses: AtomicU64,
+}
+
+impl<T> LoggingFallbackHistoryStore<T> {
+    /// When `log_misses` is set, every key looked up is logged at debug level.
+    pub fn new(inner: T, log_misses: bool) -> Self {
+        Self {
+            inner,
+            log_misses,
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of keys looked up in the wrapped store.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn record_misses(&self, keys: &[Key]) {
+        self.misses.fetch_add(keys.len() as u64, Ordering::Relaxed);
+        if self.log_misses {
+            for key in keys {
+                tracing::debug!(target: "history_fallback", %key, "history lookup fell back");
+            }
+        }
+    }
+}
+
+impl<T: HgIdHistoryStore> HgIdHistoryStore for LoggingFallbackHistoryStore<T> {
+    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        self.record_misses(std::slice::from_ref(key));
+        self.inner.get_node_info(key)
+    }
+
+    fn refresh(&self) -> Result<()> {
+        self.inner.refresh()
+    }
+
+    fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+        self.record_misses(keys);
+        self.inner.get_node_info_batch(keys)
+    }
+}
+
+impl<T: RemoteHistoryStore> RemoteHistoryStore for LoggingFallbackHistoryStore<T> {
+    fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        self.inner.prefetch(keys)
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.inner.ping()
+    }
+}
+
+impl<T: LocalStore> LocalStore for LoggingFallbackHistoryStore<T> {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        self.inner.get_missing(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use types::testutil::*;
+
+    use super::*;
+    use crate::testutil::MapHgIdHistoryStore;
+    use crate::unionhistorystore::UnionHgIdHistoryStore;
+
+    #[test]
+    fn test_counts_fallback_lookups() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let fallback = Arc::new(LoggingFallbackHistoryStore::new(
+            MapHgIdHistoryStore::new(&[k2.clone()]),
+            true,
+        ));
+
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn HgIdHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k1.clone()])));
+        unionstore.add(Box::new(fallback.clone()));
+
+        assert!(unionstore.get_node_info(&k1)?.is_some());
+        assert_eq!(fallback.misses(), 0);
+        assert!(unionstore.get_node_info(&k2)?.is_some());
+        assert!(unionstore.get_node_info(&k3)?.is_none());
+        assert_eq!(fallback.misses(), 2);
+
+        fallback.get_node_info_batch(&[k1, k2, k3])?;
+        assert_eq!(fallback.misses(), 5);
+        Ok(())
+    }
+}
@@ -7,18 +7,41 @@
 
 use std::ops::Deref;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::Result;
+use async_trait::async_trait;
 use edenapi_types::HistoryEntry;
+use tokio::task::spawn_blocking;
 use types::Key;
 use types::NodeInfo;
+use types::RepoPath;
 
+use crate::error::UnsupportedError;
 use crate::localstore::LocalStore;
 use crate::types::StoreKey;
 
 pub trait HgIdHistoryStore: LocalStore + Send + Sync {
     fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>>;
     fn refresh(&self) -> Result<()>;
+
+    /// Look up the history of several keys at once. The result has an entry for every key,
+    /// in the same order as `keys`. Stores that can resolve a batch more efficiently than
+    /// one key at a time should override this.
+    fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+        keys.iter().map(|key| self.get_node_info(key)).collect()
+    }
+
+    /// Returns the keys of all the entries whose path is `prefix` or is under `prefix`. Stores
+    /// that can't enumerate their keys fail with an `UnsupportedError`.
+    fn keys_with_prefix(&self, _prefix: &RepoPath) -> Result<Vec<Key>> {
+        Err(UnsupportedError("keys_with_prefix").into())
+    }
+}
+
+/// Whether `path` is `prefix` or is under `prefix`.
+pub(crate) fn path_has_prefix(path: &RepoPath, prefix: &RepoPath) -> bool {
+    path == prefix || path.parents().any(|parent| parent == prefix)
 }
 
 pub trait HgIdMutableHistoryStore: HgIdHistoryStore + Send + Sync {
@@ -40,6 +63,59 @@ pub trait RemoteHistoryStore: HgIdHistoryStore + Send + Sync {
     /// everything that was asked. On a higher level store, such as the `MetadataStore`, this will
     /// avoid fetching data that is already present locally.
     fn prefetch(&self, keys: &[StoreKey]) -> Result<()>;
+
+    /// Checks that the remote side of this store is reachable, without fetching any data.
+    /// Stores that don't talk to a remote are always reachable.
+    fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Async counterpart of `HgIdHistoryStore`, for stores that can be queried without blocking.
+#[async_trait]
+pub trait AsyncHgIdHistoryStore: Send + Sync {
+    async fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>>;
+
+    /// Returns all the keys that aren't present in this store.
+    async fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>>;
+}
+
+/// Async counterpart of `RemoteHistoryStore`.
+#[async_trait]
+pub trait AsyncRemoteHistoryStore: AsyncHgIdHistoryStore {
+    async fn prefetch(&self, keys: &[StoreKey]) -> Result<()>;
+}
+
+/// Exposes a synchronous history store through the async traits, by running its operations
+/// on the blocking thread pool.
+pub struct SpawnBlockingHistoryStore<T: ?Sized>(pub Arc<T>);
+
+#[async_trait]
+impl<T: HgIdHistoryStore + ?Sized + 'static> AsyncHgIdHistoryStore
+    for SpawnBlockingHistoryStore<T>
+{
+    async fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        let store = self.0.clone();
+        let key = key.clone();
+        spawn_blocking(move || T::get_node_info(&store, &key)).await?
+    }
+
+    async fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let store = self.0.clone();
+        let keys = keys.to_vec();
+        spawn_blocking(move || T::get_missing(&store, &keys)).await?
+    }
+}
+
+#[async_trait]
+impl<T: RemoteHistoryStore + ?Sized + 'static> AsyncRemoteHistoryStore
+    for SpawnBlockingHistoryStore<T>
+{
+    async fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        let store = self.0.clone();
+        let keys = keys.to_vec();
+        spawn_blocking(move || T::prefetch(&store, &keys)).await?
+    }
 }
 
 /// Implement `HgIdHistoryStore` for all types that can be `Deref` into a `HgIdHistoryStore`.
@@ -51,6 +127,14 @@ impl<T: HgIdHistoryStore + ?Sized, U: Deref<Target = T> + Send + Sync> HgIdHisto
     fn refresh(&self) -> Result<()> {
         T::refresh(self)
     }
+
+    fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+        T::get_node_info_batch(self, keys)
+    }
+
+    fn keys_with_prefix(&self, prefix: &RepoPath) -> Result<Vec<Key>> {
+        T::keys_with_prefix(self, prefix)
+    }
 }
 
 impl<T: HgIdMutableHistoryStore + ?Sized, U: Deref<Target = T> + Send + Sync>
@@ -69,4 +153,30 @@ impl<T: RemoteHistoryStore + ?Sized, U: Deref<Target = T> + Send + Sync> RemoteH
     fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
         T::prefetch(self, keys)
     }
+
+    fn ping(&self) -> Result<()> {
+        T::ping(self)
+    }
+}
+
+#[async_trait]
+impl<T: AsyncHgIdHistoryStore + ?Sized, U: Deref<Target = T> + Send + Sync> AsyncHgIdHistoryStore
+    for U
+{
+    async fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        T::get_node_info(self, key).await
+    }
+
+    async fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        T::get_missing(self, keys).await
+    }
+}
+
+#[async_trait]
+impl<T: AsyncRemoteHistoryStore + ?Sized, U: Deref<Target = T> + Send + Sync>
+    AsyncRemoteHistoryStore for U
+{
+    async fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        T::prefetch(self, keys).await
+    }
 }
@@ -270,6 +270,7 @@ impl HgIdHistoryStore for IndexedLogHgIdHistoryStore {
 
 impl HgIdMutableHistoryStore for IndexedLogHgIdHistoryStore {
     fn add(&self, key: &Key, info: &NodeInfo) -> Result<()> {
+        info.validate(key)?;
         let entry = Entry::new(key, info);
         entry.write_to_log(&self.log)
     }
@@ -329,6 +330,37 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_add_invalid() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let log = IndexedLogHgIdHistoryStore::new(&tempdir, &empty_config(), StoreType::Shared)?;
+        let k = key("a", "1");
+        let nodeinfo = NodeInfo {
+            parents: [k.clone(), null_key("a")],
+            linknode: hgid("3"),
+        };
+
+        assert!(log.add(&k, &nodeinfo).is_err());
+        assert_eq!(log.get_node_info(&k)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_second_parent_only() -> Result<()> {
+        // hg writes a null first parent and the source as second parent for some copies.
+        let tempdir = TempDir::new()?;
+        let log = IndexedLogHgIdHistoryStore::new(&tempdir, &empty_config(), StoreType::Shared)?;
+        let k = key("a", "1");
+        let nodeinfo = NodeInfo {
+            parents: [null_key("a"), key("b", "2")],
+            linknode: hgid("3"),
+        };
+
+        log.add(&k, &nodeinfo)?;
+        assert_eq!(log.get_node_info(&k)?, Some(nodeinfo));
+        Ok(())
+    }
+
     #[test]
     fn test_add_get_node_info() -> Result<()> {
         let tempdir = TempDir::new()?;
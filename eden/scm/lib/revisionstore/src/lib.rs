@@ -123,22 +123,28 @@
 //!
 
 mod contentstore;
+mod correlation;
 mod dataindex;
 #[cfg(all(fbcode_build, target_os = "linux"))]
 mod facebook;
 mod fanouttable;
+mod fallbackhistorystore;
 mod fetch_logger;
 mod historyindex;
 mod indexedloghistorystore;
 mod indexedlogutil;
 mod lfs;
+mod lruhistorystore;
 mod memcache;
 mod metadatastore;
 mod missing;
 mod redacted;
 mod remotestore;
 mod repack;
+mod retryhistorystore;
 mod sliceext;
+mod timeouthistorystore;
+mod ttlhistorystore;
 mod types;
 mod unionstore;
 
@@ -159,6 +165,7 @@ pub mod packstore;
 pub mod packwriter;
 pub mod scmstore;
 pub mod trait_impls;
+pub mod unioncontentandhistory;
 pub mod uniondatastore;
 pub mod unionhistorystore;
 pub mod util;
@@ -167,6 +174,8 @@ pub use revisionstore_types::*;
 
 pub use crate::contentstore::ContentStore;
 pub use crate::contentstore::ContentStoreBuilder;
+pub use crate::correlation::correlation_id;
+pub use crate::correlation::with_correlation_id;
 pub use crate::datapack::DataEntry;
 pub use crate::datapack::DataPack;
 pub use crate::datapack::DataPackVersion;
@@ -181,12 +190,16 @@ pub use crate::datastore::StoreResult;
 pub use crate::edenapi::EdenApiFileStore;
 pub use crate::edenapi::EdenApiRemoteStore;
 pub use crate::edenapi::EdenApiTreeStore;
+pub use crate::fallbackhistorystore::LoggingFallbackHistoryStore;
 pub use crate::historypack::HistoryEntry;
 pub use crate::historypack::HistoryPack;
 pub use crate::historypack::HistoryPackVersion;
+pub use crate::historystore::AsyncHgIdHistoryStore;
+pub use crate::historystore::AsyncRemoteHistoryStore;
 pub use crate::historystore::HgIdHistoryStore;
 pub use crate::historystore::HgIdMutableHistoryStore;
 pub use crate::historystore::RemoteHistoryStore;
+pub use crate::historystore::SpawnBlockingHistoryStore;
 pub use crate::indexedlogauxstore::AuxStore;
 pub use crate::indexedlogdatastore::IndexedLogHgIdDataStore;
 pub use crate::indexedlogdatastore::IndexedLogHgIdDataStoreConfig;
@@ -194,6 +207,7 @@ pub use crate::indexedloghistorystore::IndexedLogHgIdHistoryStore;
 pub use crate::indexedlogutil::StoreType;
 pub use crate::localstore::ExtStoredPolicy;
 pub use crate::localstore::LocalStore;
+pub use crate::lruhistorystore::LruHgIdHistoryStore;
 pub use crate::memcache::MemcacheStore;
 pub use crate::metadatastore::MetadataStore;
 pub use crate::metadatastore::MetadataStoreBuilder;
@@ -213,9 +227,17 @@ pub use crate::repack::RepackKind;
 pub use crate::repack::RepackLocation;
 pub use crate::repack::Repackable;
 pub use crate::repack::ToKeys;
+pub use crate::retryhistorystore::RetryingRemoteHistoryStore;
+pub use crate::timeouthistorystore::TimeoutRemoteHistoryStore;
+pub use crate::ttlhistorystore::TtlCacheHistoryStore;
 pub use crate::types::ContentHash;
 pub use crate::types::StoreKey;
+pub use crate::unioncontentandhistory::UnionContentAndHistory;
 pub use crate::uniondatastore::UnionHgIdDataStore;
+pub use crate::unionstore::BatchSizeHistogram;
+pub use crate::unionstore::StoreId;
+pub use crate::unionstore::UnionMemberStats;
+pub use crate::unionstore::UnionStoreBuilder;
 pub use crate::util::Error;
 
 #[cfg(any(test, feature = "for-tests"))]
@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use lru_cache::LruCache;
+use parking_lot::Mutex;
+use types::Key;
+use types::NodeInfo;
+use types::RepoPath;
+
+use crate::historystore::path_has_prefix;
+use crate::historystore::HgIdHistoryStore;
+use crate::historystore::HgIdMutableHistoryStore;
+use crate::localstore::LocalStore;
+use crate::types::StoreKey;
+
+/// A bounded in-memory history store. Once `capacity` entries are stored, adding a new entry
+/// evicts the least recently used one. Meant to be placed first in a `UnionHgIdHistoryStore`
+/// to absorb repeated lookups.
+pub struct LruHgIdHistoryStore {
+    cache: Mutex<LruCache<Key, NodeInfo>>,
+}
+
+impl LruHgIdHistoryStore {
+    pub fn new(capacity: usize) -> Self {
+        LruHgIdHistoryStore {
+            cache: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl HgIdHistoryStore for LruHgIdHistoryStore {
+    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        Ok(self.cache.lock().get_mut(key).cloned())
+    }
+
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn keys_with_prefix(&self, prefix: &RepoPath) -> Result<Vec<Key>> {
+        let mut keys: Vec<Key> = self
+            .cache
+            .lock()
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| path_has_prefix(&key.path, prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+impl HgIdMutableHistoryStore for LruHgIdHistoryStore {
+    fn add(&self, key: &Key, info: &NodeInfo) -> Result<()> {
+        info.validate(key)?;
+        self.cache.lock().insert(key.clone(), info.clone());
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
+        Ok(None)
+    }
+}
+
+impl LocalStore for LruHgIdHistoryStore {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let mut cache = self.cache.lock();
+        Ok(keys
+            .iter()
+            .filter(|key| match key.maybe_as_key() {
+                Some(key) => !cache.contains_key(key),
+                None => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::testutil::*;
+
+    use super::*;
+
+    fn node_info(key: &Key) -> NodeInfo {
+        NodeInfo {
+            parents: [null_key(key.path.as_str()), null_key(key.path.as_str())],
+            linknode: key.hgid,
+        }
+    }
+
+    #[test]
+    fn test_eviction() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let store = LruHgIdHistoryStore::new(2);
+        store.add(&k1, &node_info(&k1))?;
+        store.add(&k2, &node_info(&k2))?;
+
+        // Looking up k1 makes k2 the least recently used entry.
+        assert_eq!(store.get_node_info(&k1)?, Some(node_info(&k1)));
+        store.add(&k3, &node_info(&k3))?;
+
+        assert_eq!(store.get_node_info(&k1)?, Some(node_info(&k1)));
+        assert_eq!(store.get_node_info(&k2)?, None);
+        assert_eq!(store.get_node_info(&k3)?, Some(node_info(&k3)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_with_prefix() -> Result<()> {
+        let keys = [
+            key("a", "1"),
+            key("a/b", "2"),
+            key("a/b/c", "3"),
+            key("ab", "4"),
+            key("d", "5"),
+        ];
+        let store = LruHgIdHistoryStore::new(10);
+        for key in &keys {
+            store.add(key, &node_info(key))?;
+        }
+
+        let prefix = RepoPath::from_str("a/b")?;
+        assert_eq!(store.keys_with_prefix(prefix)?, keys[1..3].to_vec());
+        let prefix = RepoPath::from_str("a")?;
+        assert_eq!(store.keys_with_prefix(prefix)?, keys[..3].to_vec());
+        assert_eq!(store.keys_with_prefix(RepoPath::empty())?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing_after_eviction() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let keys: Vec<StoreKey> = vec![k1.clone(), k2.clone(), k3.clone()]
+            .into_iter()
+            .map(StoreKey::hgid)
+            .collect();
+        let store = LruHgIdHistoryStore::new(2);
+        assert_eq!(store.get_missing(&keys)?, keys);
+
+        store.add(&k1, &node_info(&k1))?;
+        store.add(&k2, &node_info(&k2))?;
+        assert_eq!(store.get_missing(&keys)?, vec![keys[2].clone()]);
+
+        store.add(&k3, &node_info(&k3))?;
+        assert_eq!(store.get_missing(&keys)?, vec![keys[0].clone()]);
+        Ok(())
+    }
+}
@@ -137,6 +137,7 @@ impl HgIdHistoryStore for MemcacheStore {
 
 impl HgIdMutableHistoryStore for MemcacheStore {
     fn add(&self, key: &Key, info: &NodeInfo) -> Result<()> {
+        info.validate(key)?;
         self.add_hist(key, info);
         Ok(())
     }
@@ -333,6 +334,7 @@ impl HgIdHistoryStore for MemcacheHgIdHistoryStore {
 
 impl HgIdMutableHistoryStore for MemcacheHgIdHistoryStore {
     fn add(&self, key: &Key, info: &NodeInfo) -> Result<()> {
+        info.validate(key)?;
         if self.use_memcache() {
             self.memcache.add_hist(key, info);
         }
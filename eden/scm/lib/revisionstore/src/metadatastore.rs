@@ -140,6 +140,13 @@ impl RemoteHistoryStore for MetadataStore {
             Ok(())
         }
     }
+
+    fn ping(&self) -> Result<()> {
+        match self.remote_store.as_ref() {
+            Some(remote_store) => remote_store.ping(),
+            None => Ok(()),
+        }
+    }
 }
 
 impl LocalStore for MetadataStore {
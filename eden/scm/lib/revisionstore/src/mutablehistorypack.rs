@@ -149,6 +149,7 @@ impl MutableHistoryPack {
 
 impl HgIdMutableHistoryStore for MutableHistoryPack {
     fn add(&self, key: &Key, info: &NodeInfo) -> Result<()> {
+        info.validate(key)?;
         let mut guard = self.inner.lock();
         let pack = self.get_pack(&mut guard)?;
         // Loops in the graph aren't allowed. Since this is a logic error in the code, let's
@@ -467,6 +468,23 @@ mod tests {
         muthistorypack.add(&k, &nodeinfo).unwrap();
     }
 
+    #[test]
+    fn test_second_parent_only() -> Result<()> {
+        // hg writes a null first parent and the source as second parent for some copies.
+        let tempdir = tempdir()?;
+        let muthistorypack = MutableHistoryPack::new(tempdir.path(), HistoryPackVersion::One);
+        let k = key("a", "1");
+        let null_key = Key::new(k.path.clone(), HgId::null_id().clone());
+        let nodeinfo = NodeInfo {
+            parents: [null_key, key("b", "2")],
+            linknode: Default::default(),
+        };
+
+        muthistorypack.add(&k, &nodeinfo)?;
+        assert_eq!(muthistorypack.get_node_info(&k)?, Some(nodeinfo));
+        Ok(())
+    }
+
     #[test]
     fn test_empty() {
         let tempdir = tempdir().unwrap();
@@ -0,0 +1,196 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use types::Key;
+use types::NodeInfo;
+
+use crate::historystore::HgIdHistoryStore;
+use crate::historystore::RemoteHistoryStore;
+use crate::localstore::LocalStore;
+use crate::types::StoreKey;
+
+/// A `RetryingRemoteHistoryStore` wraps a `RemoteHistoryStore` and retries failed prefetches,
+/// waiting twice as long before each new attempt. Only the keys that are still missing after a
+/// failed attempt are requested again.
+pub struct RetryingRemoteHistoryStore<T> {
+    inner: T,
+    max_retries: usize,
+    initial_backoff: Duration,
+}
+
+impl<T> RetryingRemoteHistoryStore<T> {
+    /// Retry a failed prefetch up to `max_retries` times, waiting `initial_backoff` before the
+    /// first retry.
+    pub fn new(inner: T, max_retries: usize, initial_backoff: Duration) -> Self {
+        Self {
+            inner,
+            max_retries,
+            initial_backoff,
+        }
+    }
+}
+
+impl<T: RemoteHistoryStore> RemoteHistoryStore for RetryingRemoteHistoryStore<T> {
+    fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        let mut keys = keys.to_vec();
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            let err = match self.inner.prefetch(&keys) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if retries >= self.max_retries {
+                return Err(err);
+            }
+            retries += 1;
+
+            thread::sleep(backoff);
+            backoff *= 2;
+
+            keys = self.inner.get_missing(&keys)?;
+            if keys.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.inner.ping()
+    }
+}
+
+impl<T: HgIdHistoryStore> HgIdHistoryStore for RetryingRemoteHistoryStore<T> {
+    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        self.inner.get_node_info(key)
+    }
+
+    fn refresh(&self) -> Result<()> {
+        self.inner.refresh()
+    }
+
+    fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+        self.inner.get_node_info_batch(keys)
+    }
+}
+
+impl<T: LocalStore> LocalStore for RetryingRemoteHistoryStore<T> {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        self.inner.get_missing(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use anyhow::bail;
+    use parking_lot::Mutex;
+    use types::testutil::*;
+
+    use super::*;
+
+    /// A remote store that fails its first `failures` prefetches after fetching only the first
+    /// requested key.
+    struct FlakyRemoteHistoryStore {
+        fetched: Mutex<HashSet<StoreKey>>,
+        failures: Mutex<usize>,
+        calls: Mutex<Vec<Vec<StoreKey>>>,
+    }
+
+    impl FlakyRemoteHistoryStore {
+        fn new(failures: usize) -> Self {
+            Self {
+                fetched: Mutex::new(HashSet::new()),
+                failures: Mutex::new(failures),
+                calls: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    impl RemoteHistoryStore for FlakyRemoteHistoryStore {
+        fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+            self.calls.lock().push(keys.to_vec());
+            let mut failures = self.failures.lock();
+            if *failures > 0 {
+                *failures -= 1;
+                self.fetched.lock().extend(keys.first().cloned());
+                bail!("transient failure");
+            }
+            self.fetched.lock().extend(keys.iter().cloned());
+            Ok(())
+        }
+    }
+
+    impl HgIdHistoryStore for FlakyRemoteHistoryStore {
+        fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+            if !self.fetched.lock().contains(&StoreKey::hgid(key.clone())) {
+                return Ok(None);
+            }
+            Ok(Some(NodeInfo {
+                parents: [null_key(key.path.as_str()), null_key(key.path.as_str())],
+                linknode: key.hgid,
+            }))
+        }
+
+        fn refresh(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LocalStore for FlakyRemoteHistoryStore {
+        fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+            let fetched = self.fetched.lock();
+            Ok(keys
+                .iter()
+                .filter(|k| !fetched.contains(k))
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn store_keys(keys: &[Key]) -> Vec<StoreKey> {
+        keys.iter().cloned().map(StoreKey::hgid).collect()
+    }
+
+    #[test]
+    fn test_prefetch_retries_missing_keys() -> Result<()> {
+        let keys = store_keys(&[key("a", "1"), key("b", "2"), key("c", "3")]);
+        let store = RetryingRemoteHistoryStore::new(
+            FlakyRemoteHistoryStore::new(2),
+            3,
+            Duration::from_millis(1),
+        );
+
+        store.prefetch(&keys)?;
+
+        assert!(store.get_missing(&keys)?.is_empty());
+        assert_eq!(
+            *store.inner.calls.lock(),
+            vec![keys.clone(), keys[1..].to_vec(), keys[2..].to_vec()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_gives_up() {
+        let keys = store_keys(&[key("a", "1"), key("b", "2"), key("c", "3")]);
+        let store = RetryingRemoteHistoryStore::new(
+            FlakyRemoteHistoryStore::new(3),
+            1,
+            Duration::from_millis(1),
+        );
+
+        assert!(store.prefetch(&keys).is_err());
+        assert_eq!(store.inner.calls.lock().len(), 2);
+        assert_eq!(store.get_missing(&keys).unwrap(), keys[2..].to_vec());
+    }
+}
@@ -31,6 +31,7 @@ use futures::prelude::*;
 #[cfg(test)]
 pub use lfs_mocks::*;
 use minibytes::Bytes;
+use types::testutil::null_key;
 use types::Key;
 use types::NodeInfo;
 use types::Parents;
@@ -206,6 +207,54 @@ impl LocalStore for FakeRemoteHistoryStore {
     }
 }
 
+/// History store that has the history of a fixed set of keys. As everything is local,
+/// `prefetch` has nothing to do.
+pub struct MapHgIdHistoryStore(pub HashMap<Key, NodeInfo>);
+
+impl MapHgIdHistoryStore {
+    /// Gives every key of `keys` a history without parents, linked to its own hgid.
+    pub fn new(keys: &[Key]) -> Self {
+        MapHgIdHistoryStore(
+            keys.iter()
+                .map(|key| {
+                    let null = null_key(key.path.as_str());
+                    let info = NodeInfo::new(key, [null.clone(), null], key.hgid).unwrap();
+                    (key.clone(), info)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl HgIdHistoryStore for MapHgIdHistoryStore {
+    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStore for MapHgIdHistoryStore {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        Ok(keys
+            .iter()
+            .filter(|key| match key {
+                StoreKey::HgId(key) => !self.0.contains_key(key),
+                StoreKey::Content(_, _) => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+impl RemoteHistoryStore for MapHgIdHistoryStore {
+    fn prefetch(&self, _keys: &[StoreKey]) -> Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 pub struct FakeEdenApi {
     files: HashMap<Key, (Bytes, Option<u64>)>,
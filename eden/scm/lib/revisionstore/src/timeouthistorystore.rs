@@ -0,0 +1,167 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::panic;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_runtime::block_on;
+use async_runtime::spawn_blocking;
+use tokio::time::timeout;
+use types::Key;
+use types::NodeInfo;
+
+use crate::error::TimeoutError;
+use crate::historystore::HgIdHistoryStore;
+use crate::historystore::RemoteHistoryStore;
+use crate::localstore::LocalStore;
+use crate::types::StoreKey;
+
+/// A `TimeoutRemoteHistoryStore` wraps a `RemoteHistoryStore` and fails its `prefetch` and
+/// `ping` with a `TimeoutError` when they take longer than the configured timeout. The other
+/// operations don't reach the network and are forwarded as is.
+///
+/// The remote operations run on the blocking pool of the shared async runtime, where they are
+/// left to finish in the background when the timeout expires.
+pub struct TimeoutRemoteHistoryStore<T> {
+    inner: Arc<T>,
+    timeout: Duration,
+}
+
+impl<T> TimeoutRemoteHistoryStore<T> {
+    pub fn new(inner: T, timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            timeout,
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> TimeoutRemoteHistoryStore<T> {
+    fn with_timeout<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&T) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let inner = self.inner.clone();
+        let task = spawn_blocking(move || f(&inner));
+        match block_on(timeout(self.timeout, task)) {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(TimeoutError(self.timeout).into()),
+        }
+    }
+}
+
+impl<T: RemoteHistoryStore + 'static> RemoteHistoryStore for TimeoutRemoteHistoryStore<T> {
+    fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        let keys = keys.to_vec();
+        self.with_timeout(move |store| store.prefetch(&keys))
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.with_timeout(|store| store.ping())
+    }
+}
+
+impl<T: HgIdHistoryStore + 'static> HgIdHistoryStore for TimeoutRemoteHistoryStore<T> {
+    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        self.inner.get_node_info(key)
+    }
+
+    fn refresh(&self) -> Result<()> {
+        self.inner.refresh()
+    }
+
+    fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+        self.inner.get_node_info_batch(keys)
+    }
+}
+
+impl<T: LocalStore + 'static> LocalStore for TimeoutRemoteHistoryStore<T> {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        self.inner.get_missing(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use types::testutil::*;
+
+    use super::*;
+
+    /// A remote store that takes `delay` to answer `prefetch` and `ping`, and never has any
+    /// data.
+    struct SlowRemoteHistoryStore {
+        delay: Duration,
+    }
+
+    impl RemoteHistoryStore for SlowRemoteHistoryStore {
+        fn prefetch(&self, _keys: &[StoreKey]) -> Result<()> {
+            thread::sleep(self.delay);
+            Ok(())
+        }
+
+        fn ping(&self) -> Result<()> {
+            thread::sleep(self.delay);
+            Ok(())
+        }
+    }
+
+    impl HgIdHistoryStore for SlowRemoteHistoryStore {
+        fn get_node_info(&self, _key: &Key) -> Result<Option<NodeInfo>> {
+            Ok(None)
+        }
+
+        fn refresh(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LocalStore for SlowRemoteHistoryStore {
+        fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+            Ok(keys.to_vec())
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let keys = vec![StoreKey::hgid(key("a", "1"))];
+        let store = TimeoutRemoteHistoryStore::new(
+            SlowRemoteHistoryStore {
+                delay: Duration::from_secs(5),
+            },
+            Duration::from_millis(10),
+        );
+
+        let err = store.prefetch(&keys).unwrap_err();
+        assert!(err.is::<TimeoutError>());
+        let err = store.ping().unwrap_err();
+        assert!(err.is::<TimeoutError>());
+        // Local lookups aren't subject to the timeout.
+        assert_eq!(store.get_missing(&keys).unwrap(), keys);
+    }
+
+    #[test]
+    fn test_no_timeout() -> Result<()> {
+        let keys = vec![StoreKey::hgid(key("a", "1"))];
+        let store = TimeoutRemoteHistoryStore::new(
+            SlowRemoteHistoryStore {
+                delay: Duration::from_millis(1),
+            },
+            Duration::from_secs(5),
+        );
+
+        store.prefetch(&keys)?;
+        store.ping()?;
+        assert_eq!(store.get_missing(&keys)?, keys);
+        Ok(())
+    }
+}
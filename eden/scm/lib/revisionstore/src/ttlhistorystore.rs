@@ -0,0 +1,205 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::time::Duration;
+
+use anyhow::Result;
+use lru_cache::LruCache;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use types::Key;
+use types::NodeInfo;
+
+use crate::historystore::HgIdHistoryStore;
+use crate::historystore::RemoteHistoryStore;
+use crate::localstore::LocalStore;
+use crate::types::StoreKey;
+
+/// How long a `TtlCacheHistoryStore` keeps a `NodeInfo` unless configured otherwise.
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// How many `NodeInfo` a `TtlCacheHistoryStore` keeps unless configured otherwise.
+const DEFAULT_CAPACITY: usize = 100_000;
+
+/// A `TtlCacheHistoryStore` wraps a history store, usually a remote one, and keeps the
+/// `NodeInfo` it returns in memory for a fixed duration. Lookups of the same key during that
+/// time don't reach the wrapped store.
+///
+/// At most `capacity` entries are kept. Once it is reached, caching a new entry evicts the least
+/// recently used one, whether it expired or not, so expired entries that are never looked up
+/// again don't accumulate.
+///
+/// Time is read from `tokio::time::Instant`, so pausing the tokio clock in tests also pauses
+/// the expiry of the entries.
+pub struct TtlCacheHistoryStore<T> {
+    inner: T,
+    ttl: Duration,
+    cache: Mutex<LruCache<Key, (NodeInfo, Instant)>>,
+}
+
+impl<T> TtlCacheHistoryStore<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            ttl: DEFAULT_TTL,
+            cache: Mutex::new(LruCache::new(DEFAULT_CAPACITY)),
+        }
+    }
+
+    /// Keeps at most `capacity` entries. Entries cached so far are dropped.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.cache = Mutex::new(LruCache::new(capacity));
+        self
+    }
+
+    /// Keeps the entries for `ttl` after they are fetched from the wrapped store.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Returns the cached `NodeInfo` of `key`, dropping it if it expired.
+    fn cached(&self, key: &Key) -> Option<NodeInfo> {
+        let mut cache = self.cache.lock();
+        let (info, fetched) = cache.get_mut(key)?;
+        if fetched.elapsed() < self.ttl {
+            return Some(info.clone());
+        }
+        cache.remove(key);
+        None
+    }
+}
+
+impl<T: HgIdHistoryStore> HgIdHistoryStore for TtlCacheHistoryStore<T> {
+    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        if let Some(info) = self.cached(key) {
+            return Ok(Some(info));
+        }
+        let info = self.inner.get_node_info(key)?;
+        if let Some(info) = &info {
+            self.cache
+                .lock()
+                .insert(key.clone(), (info.clone(), Instant::now()));
+        }
+        Ok(info)
+    }
+
+    fn refresh(&self) -> Result<()> {
+        self.inner.refresh()
+    }
+}
+
+impl<T: RemoteHistoryStore> RemoteHistoryStore for TtlCacheHistoryStore<T> {
+    fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        let missing = self.get_missing(keys)?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+        self.inner.prefetch(&missing)
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.inner.ping()
+    }
+}
+
+impl<T: LocalStore> LocalStore for TtlCacheHistoryStore<T> {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let uncached: Vec<StoreKey> = keys
+            .iter()
+            .filter(|key| match key.maybe_as_key() {
+                Some(key) => self.cached(key).is_none(),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        self.inner.get_missing(&uncached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use types::testutil::*;
+
+    use super::*;
+
+    /// History store that has every key, and counts the lookups.
+    #[derive(Default)]
+    struct CountingHistoryStore {
+        lookups: AtomicUsize,
+    }
+
+    impl HgIdHistoryStore for CountingHistoryStore {
+        fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            Ok(Some(NodeInfo {
+                parents: [null_key(key.path.as_str()), null_key(key.path.as_str())],
+                linknode: key.hgid,
+            }))
+        }
+
+        fn refresh(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LocalStore for CountingHistoryStore {
+        fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+            // Every history entry is available, only content keys are missing.
+            Ok(keys
+                .iter()
+                .filter(|key| key.maybe_as_key().is_none())
+                .cloned()
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refetch_after_ttl() -> Result<()> {
+        tokio::time::pause();
+        let k = key("a", "1");
+        let store = TtlCacheHistoryStore::new(CountingHistoryStore::default())
+            .with_ttl(Duration::from_secs(10));
+
+        let info = store.get_node_info(&k)?;
+        assert!(info.is_some());
+        assert_eq!(store.get_node_info(&k)?, info);
+        assert_eq!(store.inner.lookups.load(Ordering::Relaxed), 1);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(store.get_node_info(&k)?, info);
+        assert_eq!(store.inner.lookups.load(Ordering::Relaxed), 1);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert_eq!(store.get_node_info(&k)?, info);
+        assert_eq!(store.inner.lookups.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_capacity() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let store = TtlCacheHistoryStore::new(CountingHistoryStore::default()).with_capacity(2);
+
+        store.get_node_info(&k1)?;
+        store.get_node_info(&k2)?;
+        // Using the first key makes the second one the least recently used.
+        store.get_node_info(&k1)?;
+        store.get_node_info(&k3)?;
+        assert_eq!(store.cache.lock().len(), 2);
+        assert_eq!(store.inner.lookups.load(Ordering::Relaxed), 3);
+
+        store.get_node_info(&k1)?;
+        assert_eq!(store.inner.lookups.load(Ordering::Relaxed), 3);
+        store.get_node_info(&k2)?;
+        assert_eq!(store.inner.lookups.load(Ordering::Relaxed), 4);
+        Ok(())
+    }
+}
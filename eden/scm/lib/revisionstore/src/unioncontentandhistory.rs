@@ -0,0 +1,153 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Result;
+use types::Key;
+use types::NodeInfo;
+
+use crate::datastore::HgIdDataStore;
+use crate::datastore::Metadata;
+use crate::datastore::RemoteDataStore;
+use crate::datastore::StoreResult;
+use crate::historystore::HgIdHistoryStore;
+use crate::historystore::RemoteHistoryStore;
+use crate::types::StoreKey;
+use crate::uniondatastore::UnionHgIdDataStore;
+use crate::unionhistorystore::UnionHgIdHistoryStore;
+
+/// A `UnionContentAndHistory` holds a union of data stores and a union of history stores, so
+/// that both can be passed around as a single object.
+///
+/// As both sides have a `prefetch` and a `refresh`, the facade doesn't implement the store
+/// traits, but has methods for each side instead.
+pub struct UnionContentAndHistory<D, H> {
+    data: UnionHgIdDataStore<D>,
+    history: UnionHgIdHistoryStore<H>,
+}
+
+impl<D, H> UnionContentAndHistory<D, H> {
+    pub fn new(data: UnionHgIdDataStore<D>, history: UnionHgIdHistoryStore<H>) -> Self {
+        Self { data, history }
+    }
+
+    pub fn data(&self) -> &UnionHgIdDataStore<D> {
+        &self.data
+    }
+
+    pub fn history(&self) -> &UnionHgIdHistoryStore<H> {
+        &self.history
+    }
+
+    pub fn into_parts(self) -> (UnionHgIdDataStore<D>, UnionHgIdHistoryStore<H>) {
+        (self.data, self.history)
+    }
+}
+
+impl<D: HgIdDataStore, H> UnionContentAndHistory<D, H> {
+    pub fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        self.data.get(key)
+    }
+
+    pub fn get_meta(&self, key: StoreKey) -> Result<StoreResult<Metadata>> {
+        self.data.get_meta(key)
+    }
+}
+
+impl<D: RemoteDataStore, H> UnionContentAndHistory<D, H> {
+    /// Prefetches the content of `keys`, see `RemoteDataStore::prefetch`.
+    pub fn prefetch_data(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        RemoteDataStore::prefetch(&self.data, keys)
+    }
+}
+
+impl<D, H: HgIdHistoryStore> UnionContentAndHistory<D, H> {
+    pub fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        HgIdHistoryStore::get_node_info(&self.history, key)
+    }
+}
+
+impl<D, H: RemoteHistoryStore> UnionContentAndHistory<D, H> {
+    /// Prefetches the history of `keys`, see `RemoteHistoryStore::prefetch`.
+    pub fn prefetch_history(&self, keys: &[StoreKey]) -> Result<()> {
+        RemoteHistoryStore::prefetch(&self.history, keys)
+    }
+}
+
+impl<D: HgIdDataStore, H: HgIdHistoryStore> UnionContentAndHistory<D, H> {
+    /// Refreshes the stores of both sides.
+    pub fn refresh(&self) -> Result<()> {
+        HgIdDataStore::refresh(&self.data)?;
+        HgIdHistoryStore::refresh(&self.history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use minibytes::Bytes;
+    use tempfile::TempDir;
+    use types::testutil::*;
+
+    use super::*;
+    use crate::indexedlogdatastore::IndexedLogHgIdDataStore;
+    use crate::indexedlogdatastore::IndexedLogHgIdDataStoreConfig;
+    use crate::indexedlogutil::StoreType;
+    use crate::localstore::ExtStoredPolicy;
+    use crate::remotestore::HgIdRemoteStore;
+    use crate::testutil::FakeHgIdRemoteStore;
+    use crate::testutil::MapHgIdHistoryStore;
+
+    #[test]
+    fn test_content_and_history() -> Result<()> {
+        let (k1, k2) = (key("a", "1"), key("b", "2"));
+        let tempdir = TempDir::new()?;
+        let config = IndexedLogHgIdDataStoreConfig {
+            max_log_count: None,
+            max_bytes_per_log: None,
+            max_bytes: None,
+        };
+        let local = IndexedLogHgIdDataStore::new(
+            &tempdir,
+            ExtStoredPolicy::Use,
+            &config,
+            StoreType::Shared,
+        )?;
+        let mut remote = FakeHgIdRemoteStore::new();
+        remote.data(HashMap::from([(
+            k1.clone(),
+            (Bytes::from("content"), None),
+        )]));
+        let data = Arc::new(remote).datastore(Arc::new(local));
+        let history = MapHgIdHistoryStore::new(&[k1.clone()]);
+        let info = history.0[&k1].clone();
+
+        let mut data_union = UnionHgIdDataStore::new();
+        data_union.add(data);
+        let mut history_union = UnionHgIdHistoryStore::new();
+        history_union.add(history);
+        let store = UnionContentAndHistory::new(data_union, history_union);
+
+        let keys = vec![StoreKey::hgid(k1.clone()), StoreKey::hgid(k2.clone())];
+        assert_eq!(store.prefetch_data(&keys[..1])?, vec![]);
+        store.prefetch_history(&keys)?;
+        store.refresh()?;
+
+        assert_eq!(
+            store.get(StoreKey::hgid(k1.clone()))?,
+            StoreResult::Found(b"content".to_vec())
+        );
+        assert_eq!(
+            store.get_meta(StoreKey::hgid(k2.clone()))?,
+            StoreResult::NotFound(StoreKey::hgid(k2.clone()))
+        );
+        assert_eq!(store.get_node_info(&k1)?, Some(info));
+        assert_eq!(store.get_node_info(&k2)?, None);
+        Ok(())
+    }
+}
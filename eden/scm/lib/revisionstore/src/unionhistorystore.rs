@@ -6,27 +6,187 @@
  */
 
 // Union history store
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::panic;
+use std::thread;
+
+use anyhow::format_err;
+use anyhow::Error;
 use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream;
+use futures::Stream;
+use futures::StreamExt;
+use types::HgId;
 use types::Key;
 use types::NodeInfo;
+use types::RepoPath;
 
+use crate::correlation::correlation_id;
+use crate::correlation::with_correlation_id;
+use crate::error::HistoryCycleError;
+use crate::error::HistoryMismatchError;
+use crate::error::PrefetchError;
+use crate::error::UnsupportedError;
+use crate::historystore::AsyncHgIdHistoryStore;
+use crate::historystore::AsyncRemoteHistoryStore;
 use crate::historystore::HgIdHistoryStore;
 use crate::historystore::RemoteHistoryStore;
 use crate::types::StoreKey;
+use crate::unionstore::dedup_keys;
+use crate::unionstore::intersect_missing;
+use crate::unionstore::Member;
+use crate::unionstore::StoreId;
 use crate::unionstore::UnionStore;
 
+pub use crate::unionstore::ConsistencyMode;
+pub use crate::unionstore::LookupPolicy;
+pub use crate::unionstore::PrefetchPolicy;
+
 pub type UnionHgIdHistoryStore<T> = UnionStore<T>;
 
-impl<T: HgIdHistoryStore> HgIdHistoryStore for UnionHgIdHistoryStore<T> {
-    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
-        for store in self {
-            match store.get_node_info(key)? {
-                None => continue,
-                Some(res) => return Ok(Some(res)),
+/// Number of keys `get_node_info_stream` prefetches at once.
+const NODE_INFO_STREAM_WINDOW: usize = 1000;
+
+/// Estimated size of a history entry without copy information: its node, its parents and its
+/// linknode.
+const HISTORY_ENTRY_SIZE: u64 = 4 * HgId::len() as u64;
+
+/// Estimates the size of the history entry of `key`, which is also the size of its entry in a
+/// history pack: the hashes, plus the path it was copied from, if any.
+fn estimated_size(key: &Key, info: &NodeInfo) -> u64 {
+    let [p1, _] = info.parents();
+    let copy_from = if p1.path != key.path {
+        p1.path.as_str().len()
+    } else {
+        0
+    };
+    HISTORY_ENTRY_SIZE + copy_from as u64
+}
+
+/// A lookup of the history of one key through the stores of a union, one answer at a time.
+/// Both the sync and the async lookups go through it, so that they follow the same
+/// `LookupPolicy` and `ConsistencyMode`.
+struct NodeInfoLookup<'a> {
+    key: &'a Key,
+    strict: bool,
+    fail_fast: bool,
+    found: Option<(NodeInfo, StoreId)>,
+    first_error: Option<Error>,
+}
+
+impl<'a> NodeInfoLookup<'a> {
+    fn new<T>(union: &UnionHgIdHistoryStore<T>, key: &'a Key) -> Self {
+        NodeInfoLookup {
+            key,
+            strict: union.consistency_mode() == ConsistencyMode::Strict,
+            fail_fast: union.lookup_policy() == LookupPolicy::FailFast,
+            found: None,
+            first_error: None,
+        }
+    }
+
+    /// Takes the answer of the store `id`. Returns the result of the lookup once the
+    /// remaining stores don't need to be consulted.
+    fn answer<T>(
+        &mut self,
+        member: &Member<T>,
+        id: StoreId,
+        answer: Result<Option<NodeInfo>>,
+    ) -> Option<Result<Option<(NodeInfo, StoreId)>>> {
+        match answer {
+            Ok(None) => member.record_miss(),
+            Ok(Some(res)) => {
+                member.record_hit(estimated_size(self.key, &res));
+                match self.found {
+                    None if !self.strict => return Some(Ok(Some((res, id)))),
+                    None => self.found = Some((res, id)),
+                    Some((ref info, first)) if *info != res => {
+                        return Some(Err(HistoryMismatchError {
+                            key: self.key.clone(),
+                            first,
+                            other: id,
+                        }
+                        .into()));
+                    }
+                    Some(_) => {}
+                }
+            }
+            Err(e) => {
+                member.record_error();
+                if self.fail_fast {
+                    return Some(Err(e));
+                }
+                if self.first_error.is_none() {
+                    self.first_error = Some(e);
+                }
+            }
+        }
+        None
+    }
+
+    /// The result of the lookup once every store answered.
+    fn finish(self) -> Result<Option<(NodeInfo, StoreId)>> {
+        match (self.found, self.first_error) {
+            (None, Some(e)) => Err(e),
+            (found, _) => Ok(found),
+        }
+    }
+}
+
+/// The failures of a prefetch through the stores of a union, handled as `PrefetchPolicy`
+/// says. Shared by the sync and the async prefetches.
+struct PrefetchErrors {
+    policy: PrefetchPolicy,
+    errors: Vec<Error>,
+}
+
+impl PrefetchErrors {
+    fn new(policy: PrefetchPolicy) -> Self {
+        PrefetchErrors {
+            policy,
+            errors: vec![],
+        }
+    }
+
+    /// Fails right away with `PrefetchPolicy::FailFast`, otherwise keeps the error for later.
+    fn add(&mut self, e: Error) -> Result<()> {
+        match self.policy {
+            PrefetchPolicy::FailFast => Err(e),
+            PrefetchPolicy::BestEffort => {
+                self.errors.push(e);
+                Ok(())
             }
         }
+    }
 
-        Ok(None)
+    /// Returns the keys that are still `missing`, or a `PrefetchError` if some are missing
+    /// after a store failed.
+    fn finish(self, missing: Vec<StoreKey>) -> Result<Vec<StoreKey>> {
+        if !self.errors.is_empty() && !missing.is_empty() {
+            return Err(PrefetchError {
+                missing,
+                errors: self.errors,
+            }
+            .into());
+        }
+        Ok(missing)
+    }
+}
+
+/// Records that `requested` keys were prefetched from the store of `member`, of which
+/// `still_missing` weren't found. The sizes of the fetched entries aren't known without
+/// looking them up, so they are counted as entries without copy information.
+fn record_prefetched<T>(member: &Member<T>, requested: usize, still_missing: usize) {
+    let fetched = requested.saturating_sub(still_missing);
+    member.record_prefetched_bytes(fetched as u64 * HISTORY_ENTRY_SIZE);
+}
+
+impl<T: HgIdHistoryStore> HgIdHistoryStore for UnionHgIdHistoryStore<T> {
+    fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        Ok(self.get_node_info_with_source(key)?.map(|(info, _)| info))
     }
 
     fn refresh(&self) -> Result<()> {
@@ -35,36 +195,493 @@ impl<T: HgIdHistoryStore> HgIdHistoryStore for UnionHgIdHistoryStore<T> {
         }
         Ok(())
     }
+
+    /// Every store is asked once for the keys that the previous stores didn't have.
+    fn keys_with_prefix(&self, prefix: &RepoPath) -> Result<Vec<Key>> {
+        let mut seen = HashSet::new();
+        let mut keys = vec![];
+        for store in self {
+            let store_keys = match store.keys_with_prefix(prefix) {
+                Ok(store_keys) => store_keys,
+                // Stores that can't list their keys, like remote stores, are skipped.
+                Err(e) if e.is::<UnsupportedError>() => continue,
+                Err(e) => return Err(e),
+            };
+            for key in store_keys {
+                if seen.insert(key.clone()) {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Every store is asked once, with a single batch, for the keys that the previous stores
+    /// didn't have, or for all the keys with `ConsistencyMode::Strict`. Failures and mismatches
+    /// are handled as in `get_node_info_with_source`: with `LookupPolicy::ServeAvailable`, the
+    /// error of the first failing store is only returned if some key wasn't found at all.
+    fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+        let strict = self.consistency_mode() == ConsistencyMode::Strict;
+        let fail_fast = self.lookup_policy() == LookupPolicy::FailFast;
+        let mut results: Vec<Option<(NodeInfo, StoreId)>> = vec![None; keys.len()];
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        let mut first_error = None;
+        for (index, member) in self.members().enumerate() {
+            if pending.is_empty() {
+                break;
+            }
+            let pending_keys: Vec<Key> = pending.iter().map(|i| keys[*i].clone()).collect();
+            let found = match member.store.get_node_info_batch(&pending_keys) {
+                Ok(found) => found,
+                Err(e) => {
+                    member.record_error();
+                    if fail_fast {
+                        return Err(e);
+                    }
+                    if first_error.is_none() {
+                        first_error = Some(e);
+                    }
+                    continue;
+                }
+            };
+            let mut still_pending = vec![];
+            for (i, info) in pending.into_iter().zip(found) {
+                let info = match info {
+                    Some(info) => info,
+                    None => {
+                        member.record_miss();
+                        still_pending.push(i);
+                        continue;
+                    }
+                };
+                member.record_hit(estimated_size(&keys[i], &info));
+                match results[i] {
+                    None => results[i] = Some((info, StoreId(index))),
+                    Some((ref first_info, first)) if *first_info != info => {
+                        return Err(HistoryMismatchError {
+                            key: keys[i].clone(),
+                            first,
+                            other: StoreId(index),
+                        }
+                        .into());
+                    }
+                    Some(_) => {}
+                }
+                // The remaining stores must agree with the history found.
+                if strict {
+                    still_pending.push(i);
+                }
+            }
+            pending = still_pending;
+        }
+
+        match first_error {
+            Some(e) if results.iter().any(Option::is_none) => Err(e),
+            _ => Ok(results
+                .into_iter()
+                .map(|result| result.map(|(info, _)| info))
+                .collect()),
+        }
+    }
 }
 
-impl<T: RemoteHistoryStore> RemoteHistoryStore for UnionHgIdHistoryStore<T> {
-    fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
-        let initial_keys = Ok(keys.to_vec());
-        self.into_iter()
-            .fold(initial_keys, |missing_keys, store| match missing_keys {
-                Ok(missing_keys) => {
-                    if !missing_keys.is_empty() {
-                        store.prefetch(&missing_keys)?;
-                        store.get_missing(&missing_keys)
-                    } else {
-                        Ok(vec![])
+impl<T: HgIdHistoryStore> UnionHgIdHistoryStore<T> {
+    /// Same as `get_node_info`, but a failing store doesn't stop the lookup, the next
+    /// stores are still consulted. Returns the first result found, along with the errors
+    /// of the stores consulted before it.
+    pub fn get_node_info_lenient(&self, key: &Key) -> (Option<NodeInfo>, Vec<Error>) {
+        let mut errors = vec![];
+        for member in self.members() {
+            match member.store.get_node_info(key) {
+                Ok(None) => member.record_miss(),
+                Ok(Some(res)) => {
+                    member.record_hit(estimated_size(key, &res));
+                    return (Some(res), errors);
+                }
+                Err(e) => {
+                    member.record_error();
+                    errors.push(e);
+                }
+            }
+        }
+
+        (None, errors)
+    }
+
+    /// Same as `get_node_info`, but also returns which store the history came from.
+    ///
+    /// With `ConsistencyMode::Strict`, this is the first store that has the history, and a
+    /// `HistoryMismatchError` is returned if another store has a different one.
+    ///
+    /// With `LookupPolicy::ServeAvailable`, the stores that fail are skipped, and the error of
+    /// the first one is only returned if no other store has the history.
+    pub fn get_node_info_with_source(&self, key: &Key) -> Result<Option<(NodeInfo, StoreId)>> {
+        let mut lookup = NodeInfoLookup::new(self, key);
+        for (index, member) in self.members().enumerate() {
+            let answer = member.store.get_node_info(key);
+            if let Some(result) = lookup.answer(member, StoreId(index), answer) {
+                return result;
+            }
+        }
+        lookup.finish()
+    }
+
+    /// Returns the history of the `keys` that the stores already have, without fetching
+    /// anything: a store is only asked for the keys its `get_missing` doesn't report, and
+    /// remote stores report all the keys as missing. The results are in the order of `keys`,
+    /// the other keys are left for a later prefetch.
+    pub fn prefetch_available(&self, keys: &[StoreKey]) -> Result<Vec<(Key, NodeInfo)>> {
+        let keys = dedup_keys(keys);
+        let mut missing = keys.clone();
+        let mut found = HashMap::new();
+        for member in self.members() {
+            if missing.is_empty() {
+                break;
+            }
+            let still_missing = member.store.get_missing(&missing)?;
+            let still_missing_set: HashSet<_> = still_missing.iter().collect();
+            for key in missing.iter() {
+                if still_missing_set.contains(key) {
+                    continue;
+                }
+                if let StoreKey::HgId(key) = key {
+                    if let Some(info) = member.store.get_node_info(key)? {
+                        member.record_hit(estimated_size(key, &info));
+                        found.insert(key.clone(), info);
                     }
                 }
-                Err(e) => Err(e),
-            })?;
+            }
+            missing = Cow::Owned(still_missing);
+        }
 
+        Ok(keys
+            .iter()
+            .filter_map(|key| match key {
+                StoreKey::HgId(key) => Some((key.clone(), found.remove(key)?)),
+                StoreKey::Content(_, _) => None,
+            })
+            .collect())
+    }
+}
+
+impl<T: RemoteHistoryStore> RemoteHistoryStore for UnionHgIdHistoryStore<T> {
+    fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        self.prefetch_report(keys)?;
+        Ok(())
+    }
+
+    /// Succeeds if all the stores are reachable.
+    fn ping(&self) -> Result<()> {
+        self.ping_all().into_iter().collect()
+    }
+}
+
+/// Follows the same `LookupPolicy`, `ConsistencyMode` and `PrefetchPolicy` as the synchronous
+/// union, through the same lookup and prefetch bookkeeping.
+#[async_trait]
+impl<T: AsyncHgIdHistoryStore> AsyncHgIdHistoryStore for UnionHgIdHistoryStore<T> {
+    async fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+        let mut lookup = NodeInfoLookup::new(self, key);
+        for (index, member) in self.members().enumerate() {
+            let answer = member.store.get_node_info(key).await;
+            if let Some(result) = lookup.answer(member, StoreId(index), answer) {
+                return Ok(result?.map(|(info, _)| info));
+            }
+        }
+        Ok(lookup.finish()?.map(|(info, _)| info))
+    }
+
+    async fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let mut missing = dedup_keys(keys);
+        for store in self {
+            if missing.is_empty() {
+                break;
+            }
+            missing = Cow::Owned(store.get_missing(&missing).await?);
+        }
+        Ok(missing.into_owned())
+    }
+}
+
+#[async_trait]
+impl<T: AsyncRemoteHistoryStore> AsyncRemoteHistoryStore for UnionHgIdHistoryStore<T> {
+    async fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+        let mut missing = dedup_keys(keys);
+        let mut errors = PrefetchErrors::new(self.prefetch_policy());
+        for member in self.members() {
+            if missing.is_empty() {
+                break;
+            }
+            match self.prefetch_member_async(member, &missing).await {
+                Ok(still_missing) => missing = Cow::Owned(still_missing),
+                Err(e) => errors.add(e)?,
+            }
+        }
+        errors.finish(missing.into_owned())?;
         Ok(())
     }
 }
 
+impl<T: AsyncRemoteHistoryStore> UnionHgIdHistoryStore<T> {
+    /// Async counterpart of `prefetch_member`.
+    async fn prefetch_member_async(
+        &self,
+        member: &Member<T>,
+        keys: &[StoreKey],
+    ) -> Result<Vec<StoreKey>> {
+        for batch in self.prefetch_batches(keys) {
+            member.record_prefetch(batch.len());
+            member.store.prefetch(batch).await?;
+        }
+        let still_missing = member.store.get_missing(keys).await?;
+        record_prefetched(member, keys.len(), still_missing.len());
+        Ok(still_missing)
+    }
+}
+
+impl<T: RemoteHistoryStore> UnionHgIdHistoryStore<T> {
+    /// Same as `prefetch`, but returns the keys that none of the stores could provide.
+    pub fn prefetch_report(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        self.prefetch_with_policy(keys, self.prefetch_policy())
+    }
+
+    /// Same as `prefetch_report`, with `id` as the correlation id that the remote stores can
+    /// read with `correlation_id` to tag the requests they send.
+    pub fn prefetch_with_correlation_id(
+        &self,
+        keys: &[StoreKey],
+        id: impl Into<String>,
+    ) -> Result<Vec<StoreKey>> {
+        with_correlation_id(Some(id.into()), || self.prefetch_report(keys))
+    }
+
+    /// Same as `prefetch_report`, with `policy` deciding what happens when a store fails.
+    pub fn prefetch_with_policy(
+        &self,
+        keys: &[StoreKey],
+        policy: PrefetchPolicy,
+    ) -> Result<Vec<StoreKey>> {
+        let mut missing = dedup_keys(keys);
+        let mut errors = PrefetchErrors::new(policy);
+        for member in self.members() {
+            // Once everything was found, the remaining stores aren't contacted at all, as even
+            // an empty request to a remote store has a cost.
+            if missing.is_empty() {
+                break;
+            }
+            match self.prefetch_member(member, &missing) {
+                Ok(still_missing) => missing = Cow::Owned(still_missing),
+                Err(e) => errors.add(e)?,
+            }
+        }
+        errors.finish(missing.into_owned())
+    }
+
+    /// Pings every store, in the order they are consulted.
+    pub fn ping_all(&self) -> Vec<Result<()>> {
+        self.into_iter().map(|store| store.ping()).collect()
+    }
+
+    /// Prefetches `keys` from the store of `member`, in batches if a maximum batch size is
+    /// configured. Returns the keys that the store still doesn't have.
+    fn prefetch_member(&self, member: &Member<T>, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        for batch in self.prefetch_batches(keys) {
+            member.record_prefetch(batch.len());
+            member.store.prefetch(batch)?;
+        }
+        let still_missing = member.store.get_missing(keys)?;
+        record_prefetched(member, keys.len(), still_missing.len());
+        Ok(still_missing)
+    }
+
+    /// Same as `prefetch`, but every store is asked for all the `keys` concurrently,
+    /// instead of only the keys that the previous stores couldn't provide. This avoids
+    /// serializing the round trips to independent remote stores. Returns the keys that
+    /// none of the stores could provide.
+    pub fn prefetch_parallel(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let keys = dedup_keys(keys);
+        // The correlation id is per thread, so it is passed on to the prefetching threads.
+        let id = correlation_id();
+        let missing_per_store = thread::scope(|scope| {
+            let keys = &keys;
+            let handles: Vec<_> = self
+                .members()
+                .map(|member| {
+                    let id = id.clone();
+                    scope.spawn(move || {
+                        with_correlation_id(id, || self.prefetch_member(member, keys))
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        Ok(intersect_missing(keys.into_owned(), missing_per_store))
+    }
+
+    /// Looks up the history of every key of `keys`, prefetching them in windows of a bounded
+    /// size, so that memory use doesn't depend on the number of keys. The results come in the
+    /// order of `keys`. A failed prefetch yields a single error for its whole window.
+    ///
+    /// The stores are synchronous, so polling the stream blocks while a window is fetched.
+    pub fn get_node_info_stream<'a>(
+        &'a self,
+        keys: impl Stream<Item = Key> + 'a,
+    ) -> impl Stream<Item = Result<(Key, Option<NodeInfo>)>> + 'a {
+        keys.chunks(NODE_INFO_STREAM_WINDOW)
+            .map(move |window| {
+                let store_keys: Vec<_> = window.iter().cloned().map(StoreKey::HgId).collect();
+                let results: Vec<_> = match self.prefetch(&store_keys) {
+                    Err(e) => vec![Err(e)],
+                    Ok(()) => window
+                        .into_iter()
+                        .map(|key| {
+                            let info = self.get_node_info(&key)?;
+                            Ok((key, info))
+                        })
+                        .collect(),
+                };
+                stream::iter(results)
+            })
+            .flatten()
+    }
+
+    /// Returns the history of `key` and of all its ancestors, following the parents until
+    /// they are null. Each generation of ancestors is prefetched at once. Fails if some
+    /// history is missing from all the stores, or if the history contains a cycle.
+    pub fn get_ancestors(&self, key: &Key) -> Result<HashMap<Key, NodeInfo>> {
+        let mut ancestors = HashMap::new();
+        let mut frontier = vec![key.clone()];
+        while !frontier.is_empty() {
+            let store_keys: Vec<_> = frontier.iter().cloned().map(StoreKey::HgId).collect();
+            self.prefetch(&store_keys)?;
+
+            let mut next = vec![];
+            for key in frontier {
+                if ancestors.contains_key(&key) {
+                    continue;
+                }
+                let info = self
+                    .get_node_info(&key)?
+                    .ok_or_else(|| format_err!("history of {} is missing", key))?;
+                next.extend(
+                    info.parents()
+                        .iter()
+                        .filter(|parent| !parent.hgid.is_null() && !ancestors.contains_key(*parent))
+                        .cloned(),
+                );
+                ancestors.insert(key, info);
+            }
+            frontier = next;
+        }
+
+        check_acyclic(&ancestors)?;
+        Ok(ancestors)
+    }
+
+    /// Returns the history of `key`, after prefetching it together with up to `depth`
+    /// generations of its ancestors, so that looking the ancestors up afterwards doesn't go to
+    /// the remote stores. Unlike `get_ancestors`, history missing from all the stores only
+    /// stops the prefetching of that line. With a `depth` of 0, this is `get_node_info`.
+    pub fn get_node_info_with_prefetch(&self, key: &Key, depth: usize) -> Result<Option<NodeInfo>> {
+        if depth == 0 {
+            return self.get_node_info(key);
+        }
+
+        let mut seen = HashSet::new();
+        let mut frontier = vec![key.clone()];
+        for generation in 0..=depth {
+            let store_keys: Vec<_> = frontier.iter().cloned().map(StoreKey::HgId).collect();
+            self.prefetch(&store_keys)?;
+            if generation == depth {
+                break;
+            }
+
+            let mut next = vec![];
+            for key in frontier {
+                if !seen.insert(key.clone()) {
+                    continue;
+                }
+                if let Some(info) = self.get_node_info(&key)? {
+                    next.extend(
+                        info.parents()
+                            .iter()
+                            .filter(|parent| !parent.hgid.is_null() && !seen.contains(*parent))
+                            .cloned(),
+                    );
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+        self.get_node_info(key)
+    }
+}
+
+/// Fails if following the parents in `history` leads back to the same key.
+fn check_acyclic(history: &HashMap<Key, NodeInfo>) -> Result<()> {
+    // Keys whose ancestors were all checked already.
+    let mut done = HashSet::new();
+    for start in history.keys() {
+        if done.contains(start) {
+            continue;
+        }
+        // Depth first walk, with the index of the next parent to visit for every key.
+        let mut path = vec![(start, 0)];
+        let mut on_path = HashSet::new();
+        on_path.insert(start);
+        while let Some(top) = path.last_mut() {
+            let key = top.0;
+            let parents = history[key].parents();
+            if top.1 == parents.len() {
+                done.insert(key);
+                on_path.remove(key);
+                path.pop();
+                continue;
+            }
+            let parent = &parents[top.1];
+            top.1 += 1;
+            if !history.contains_key(parent) || done.contains(parent) {
+                continue;
+            }
+            if !on_path.insert(parent) {
+                return Err(HistoryCycleError(parent.clone()).into());
+            }
+            path.push((parent, 0));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
     use quickcheck::quickcheck;
     use thiserror::Error;
+    use types::testutil::*;
 
     use super::*;
+    use crate::historystore::HgIdMutableHistoryStore;
+    use crate::historystore::SpawnBlockingHistoryStore;
     use crate::localstore::LocalStore;
+    use crate::lruhistorystore::LruHgIdHistoryStore;
+    use crate::testutil::MapHgIdHistoryStore;
     use crate::types::StoreKey;
+    use crate::unionstore::BatchSizeHistogram;
+    use crate::unionstore::UnionMemberStats;
+    use crate::unionstore::UnionStoreBuilder;
 
     struct BadHgIdHistoryStore;
 
@@ -106,6 +723,1085 @@ mod tests {
         }
     }
 
+    impl RemoteHistoryStore for BadHgIdHistoryStore {
+        fn prefetch(&self, _keys: &[StoreKey]) -> Result<()> {
+            Err(BadHgIdHistoryStoreError.into())
+        }
+
+        fn ping(&self) -> Result<()> {
+            Err(BadHgIdHistoryStoreError.into())
+        }
+    }
+
+    type CallLog = Arc<Mutex<Vec<(&'static str, Vec<StoreKey>)>>>;
+
+    /// Remote store that can provide the history of a fixed set of keys, and logs the
+    /// keys of every `prefetch` call.
+    struct FakeRemoteHistoryStore {
+        name: &'static str,
+        available: HashMap<Key, NodeInfo>,
+        fetched: Mutex<HashMap<Key, NodeInfo>>,
+        calls: CallLog,
+    }
+
+    impl FakeRemoteHistoryStore {
+        fn new(name: &'static str, available: &[Key], calls: CallLog) -> Self {
+            let history = available
+                .iter()
+                .map(|key| (key.clone(), node_info(key)))
+                .collect();
+            Self::with_history(name, history, calls)
+        }
+
+        fn with_history(
+            name: &'static str,
+            available: HashMap<Key, NodeInfo>,
+            calls: CallLog,
+        ) -> Self {
+            FakeRemoteHistoryStore {
+                name,
+                available,
+                fetched: Mutex::new(HashMap::new()),
+                calls,
+            }
+        }
+    }
+
+    impl HgIdHistoryStore for FakeRemoteHistoryStore {
+        fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+            Ok(self.fetched.lock().get(key).cloned())
+        }
+
+        fn refresh(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LocalStore for FakeRemoteHistoryStore {
+        fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+            let fetched = self.fetched.lock();
+            Ok(keys
+                .iter()
+                .filter(|key| match key {
+                    StoreKey::HgId(key) => !fetched.contains_key(key),
+                    StoreKey::Content(_, _) => true,
+                })
+                .cloned()
+                .collect())
+        }
+    }
+
+    impl RemoteHistoryStore for FakeRemoteHistoryStore {
+        fn prefetch(&self, keys: &[StoreKey]) -> Result<()> {
+            self.calls.lock().push((self.name, keys.to_vec()));
+            let mut fetched = self.fetched.lock();
+            for key in keys {
+                if let StoreKey::HgId(key) = key {
+                    if let Some(info) = self.available.get(key) {
+                        fetched.insert(key.clone(), info.clone());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Local store that has a fixed set of keys, and logs the keys of every `get_missing`
+    /// call.
+    struct LoggingLocalStore {
+        name: &'static str,
+        keys: HashSet<StoreKey>,
+        calls: CallLog,
+    }
+
+    impl LocalStore for LoggingLocalStore {
+        fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+            self.calls.lock().push((self.name, keys.to_vec()));
+            Ok(keys
+                .iter()
+                .filter(|key| !self.keys.contains(key))
+                .cloned()
+                .collect())
+        }
+    }
+
+    /// Remote store whose lookups each cost a round trip, and logs the keys of every round
+    /// trip. A batch lookup is a single round trip.
+    struct RoundTripHistoryStore {
+        name: &'static str,
+        history: HashMap<Key, NodeInfo>,
+        calls: CallLog,
+    }
+
+    impl RoundTripHistoryStore {
+        fn new(name: &'static str, available: &[Key], calls: CallLog) -> Self {
+            RoundTripHistoryStore {
+                name,
+                history: MapHgIdHistoryStore::new(available).0,
+                calls,
+            }
+        }
+    }
+
+    impl HgIdHistoryStore for RoundTripHistoryStore {
+        fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
+            Ok(self
+                .get_node_info_batch(std::slice::from_ref(key))?
+                .remove(0))
+        }
+
+        fn refresh(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn get_node_info_batch(&self, keys: &[Key]) -> Result<Vec<Option<NodeInfo>>> {
+            self.calls.lock().push((self.name, store_keys(keys)));
+            Ok(keys
+                .iter()
+                .map(|key| self.history.get(key).cloned())
+                .collect())
+        }
+    }
+
+    impl LocalStore for RoundTripHistoryStore {
+        fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+            Ok(keys.to_vec())
+        }
+    }
+
+    fn node_info(key: &Key) -> NodeInfo {
+        let null = null_key(key.path.as_str());
+        NodeInfo::new(key, [null.clone(), null], key.hgid).unwrap()
+    }
+
+    fn store_keys(keys: &[Key]) -> Vec<StoreKey> {
+        keys.iter().cloned().map(StoreKey::HgId).collect()
+    }
+
+    #[test]
+    fn test_prefetch_waterfall() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let calls = CallLog::default();
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        let first = FakeRemoteHistoryStore::new("first", &[k1.clone()], calls.clone());
+        let second = FakeRemoteHistoryStore::new("second", &[k2.clone()], calls.clone());
+        unionstore.add(first);
+        unionstore.add(second);
+
+        unionstore.prefetch(&store_keys(&[k1.clone(), k2.clone(), k3.clone()]))?;
+
+        // The second store is only asked for what the first one couldn't provide.
+        assert_eq!(
+            *calls.lock(),
+            vec![
+                ("first", store_keys(&[k1.clone(), k2.clone(), k3.clone()])),
+                ("second", store_keys(&[k2.clone(), k3.clone()])),
+            ]
+        );
+        assert_eq!(unionstore.get_node_info(&k2)?, Some(node_info(&k2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_batch_sizes() -> Result<()> {
+        let keys = [key("a", "1"), key("b", "2"), key("c", "3")];
+        let calls = CallLog::default();
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        let first = FakeRemoteHistoryStore::new("first", &keys[..1], calls.clone());
+        let second = FakeRemoteHistoryStore::new("second", &keys[1..], calls.clone());
+        unionstore.add(first);
+        unionstore.add(second);
+
+        unionstore.prefetch(&store_keys(&keys))?;
+        unionstore.prefetch(&store_keys(&keys[1..2]))?;
+        unionstore.prefetch(&store_keys(&[]))?;
+
+        // The second store is only sent the keys the first store didn't have, and empty
+        // prefetches don't reach any store.
+        assert_eq!(
+            unionstore.prefetch_batch_sizes(),
+            vec![
+                BatchSizeHistogram::from([(1, 1), (4, 1)]),
+                BatchSizeHistogram::from([(1, 1), (2, 1)]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_prefetch_batch() -> Result<()> {
+        let keys: Vec<Key> = (1..=5).map(|i| key("a", &i.to_string())).collect();
+        let calls = CallLog::default();
+        let mut unionstore = UnionHgIdHistoryStore::new().with_max_prefetch_batch(2);
+        let first = FakeRemoteHistoryStore::new("first", &keys[..1], calls.clone());
+        let second = FakeRemoteHistoryStore::new("second", &keys, calls.clone());
+        unionstore.add(first);
+        unionstore.add(second);
+
+        let missing = unionstore.prefetch_report(&store_keys(&keys))?;
+
+        assert!(missing.is_empty());
+        assert_eq!(
+            *calls.lock(),
+            vec![
+                ("first", store_keys(&keys[0..2])),
+                ("first", store_keys(&keys[2..4])),
+                ("first", store_keys(&keys[4..5])),
+                ("second", store_keys(&keys[1..3])),
+                ("second", store_keys(&keys[3..5])),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder() -> Result<()> {
+        let keys: Vec<Key> = (1..=3).map(|i| key("a", &i.to_string())).collect();
+        let calls = CallLog::default();
+        let first = FakeRemoteHistoryStore::new("first", &keys, calls.clone());
+        let second = FakeRemoteHistoryStore::new("second", &keys, calls.clone());
+        let unionstore: UnionHgIdHistoryStore<Box<dyn RemoteHistoryStore>> =
+            UnionStoreBuilder::new()
+                .store(Box::new(BadHgIdHistoryStore))
+                .store(Box::new(first))
+                .store_with_priority(Box::new(second), -1)
+                .max_prefetch_batch(2)
+                .prefetch_policy(PrefetchPolicy::BestEffort)
+                .build();
+
+        assert_eq!(unionstore.len(), 3);
+        assert_eq!(unionstore.prefetch_policy(), PrefetchPolicy::BestEffort);
+        unionstore.prefetch(&store_keys(&keys))?;
+        // The prioritized store has everything, so neither of the others is consulted.
+        assert_eq!(
+            *calls.lock(),
+            vec![
+                ("second", store_keys(&keys[..2])),
+                ("second", store_keys(&keys[2..])),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ping() {
+        let calls = CallLog::default();
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn RemoteHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(FakeRemoteHistoryStore::new(
+            "remote",
+            &[],
+            calls.clone(),
+        )));
+        assert!(unionstore.ping().is_ok());
+
+        unionstore.add(Box::new(BadHgIdHistoryStore));
+        let results = unionstore.ping_all();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().unwrap_err();
+        assert!(err.is::<BadHgIdHistoryStoreError>());
+        assert!(unionstore.ping().is_err());
+        // Pinging doesn't fetch anything.
+        assert!(calls.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_node_info_stream() -> Result<()> {
+        let keys: Vec<Key> = (1..=2500).map(|i| key("a", &format!("{:x}", i))).collect();
+        let calls = CallLog::default();
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(FakeRemoteHistoryStore::new(
+            "remote",
+            &keys[1..],
+            calls.clone(),
+        ));
+
+        let results: Vec<_> = unionstore
+            .get_node_info_stream(stream::iter(keys.clone()))
+            .collect()
+            .await;
+
+        let results = results.into_iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(results.len(), keys.len());
+        assert_eq!(results[0], (keys[0].clone(), None));
+        for (key, info) in &results[1..] {
+            assert_eq!(info.as_ref(), Some(&node_info(key)));
+        }
+        let batch_sizes: Vec<usize> = calls.lock().iter().map(|(_, keys)| keys.len()).collect();
+        assert_eq!(batch_sizes, vec![1000, 1000, 500]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_skips_remote_when_found_locally() -> Result<()> {
+        let keys = [key("a", "1"), key("b", "2")];
+        let calls = CallLog::default();
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn RemoteHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&keys)));
+        unionstore.add(Box::new(FakeRemoteHistoryStore::new(
+            "remote",
+            &keys,
+            calls.clone(),
+        )));
+
+        unionstore.prefetch(&store_keys(&keys))?;
+        unionstore.prefetch(&store_keys(&keys[..1]))?;
+        unionstore.prefetch(&[])?;
+
+        assert!(calls.lock().is_empty());
+        assert!(unionstore.prefetch_batch_sizes()[1].is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_dedups_keys() -> Result<()> {
+        let (k1, k2) = (key("a", "1"), key("b", "2"));
+        let calls = CallLog::default();
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        let first = FakeRemoteHistoryStore::new("first", &[k1.clone()], calls.clone());
+        let second = FakeRemoteHistoryStore::new("second", &[], calls.clone());
+        unionstore.add(first);
+        unionstore.add(second);
+
+        let keys = store_keys(&[k2.clone(), k1.clone(), k2.clone(), k1.clone()]);
+        unionstore.prefetch(&keys)?;
+
+        assert_eq!(
+            *calls.lock(),
+            vec![
+                ("first", store_keys(&[k2.clone(), k1.clone()])),
+                ("second", store_keys(&[k2.clone()])),
+            ]
+        );
+        assert_eq!(unionstore.get_missing(&keys)?, store_keys(&[k2.clone()]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_report() -> Result<()> {
+        let keys = [key("a", "1"), key("b", "2"), key("c", "3"), key("d", "4")];
+        let calls = CallLog::default();
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(FakeRemoteHistoryStore::new(
+            "remote",
+            &keys[..2],
+            calls.clone(),
+        ));
+
+        let missing = unionstore.prefetch_report(&store_keys(&keys))?;
+
+        assert_eq!(missing, store_keys(&keys[2..]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_best_effort() -> Result<()> {
+        let keys = [key("a", "1"), key("b", "2")];
+        let calls = CallLog::default();
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn RemoteHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(BadHgIdHistoryStore));
+        unionstore.add(Box::new(FakeRemoteHistoryStore::new(
+            "first",
+            &keys[..1],
+            calls.clone(),
+        )));
+
+        // The error is ignored, but the second key is still missing.
+        let err = unionstore
+            .prefetch_with_policy(&store_keys(&keys), PrefetchPolicy::BestEffort)
+            .unwrap_err();
+        let err = err.downcast::<PrefetchError>()?;
+        assert_eq!(err.missing, store_keys(&keys[1..]));
+        assert_eq!(err.errors.len(), 1);
+        assert!(err.errors[0].is::<BadHgIdHistoryStoreError>());
+
+        unionstore.add(Box::new(FakeRemoteHistoryStore::new(
+            "second",
+            &keys[1..],
+            calls.clone(),
+        )));
+        assert!(unionstore.prefetch(&store_keys(&keys)).is_err());
+        assert_eq!(
+            unionstore.prefetch_with_policy(&store_keys(&keys), PrefetchPolicy::BestEffort)?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_prefetch_waterfall() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let calls = CallLog::default();
+        let first = FakeRemoteHistoryStore::new("first", &[k1.clone()], calls.clone());
+        let second = FakeRemoteHistoryStore::new("second", &[k2.clone()], calls.clone());
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(SpawnBlockingHistoryStore(Arc::new(first)));
+        unionstore.add(SpawnBlockingHistoryStore(Arc::new(second)));
+
+        let keys = store_keys(&[k1.clone(), k2.clone(), k3.clone()]);
+        AsyncRemoteHistoryStore::prefetch(&unionstore, &keys).await?;
+
+        assert_eq!(
+            *calls.lock(),
+            vec![
+                ("first", store_keys(&[k1.clone(), k2.clone(), k3.clone()])),
+                ("second", store_keys(&[k2.clone(), k3.clone()])),
+            ]
+        );
+        assert_eq!(
+            AsyncHgIdHistoryStore::get_node_info(&unionstore, &k2).await?,
+            Some(node_info(&k2))
+        );
+        assert_eq!(
+            AsyncHgIdHistoryStore::get_node_info(&unionstore, &k3).await?,
+            None
+        );
+        assert_eq!(
+            AsyncHgIdHistoryStore::get_missing(&unionstore, &keys).await?,
+            store_keys(&[k3.clone()])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_policies() -> Result<()> {
+        let (k, parent) = (key("a", "1"), key("a", "2"));
+        let conflicting =
+            MapHgIdHistoryStore(HashMap::from([(k.clone(), child_info(&k, &parent))]));
+        let store = |store: Arc<dyn RemoteHistoryStore>| SpawnBlockingHistoryStore(store);
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(store(Arc::new(BadHgIdHistoryStore)));
+        unionstore.add(store(Arc::new(MapHgIdHistoryStore::new(&[k.clone()]))));
+        unionstore.add(store(Arc::new(conflicting)));
+
+        // Both the lookup and the prefetch fail fast by default.
+        let err = AsyncHgIdHistoryStore::get_node_info(&unionstore, &k)
+            .await
+            .unwrap_err();
+        assert!(err.is::<BadHgIdHistoryStoreError>());
+        let err = AsyncRemoteHistoryStore::prefetch(&unionstore, &store_keys(&[k.clone()]))
+            .await
+            .unwrap_err();
+        assert!(err.is::<BadHgIdHistoryStoreError>());
+
+        let unionstore = unionstore
+            .with_lookup_policy(LookupPolicy::ServeAvailable)
+            .with_prefetch_policy(PrefetchPolicy::BestEffort);
+        assert_eq!(
+            AsyncHgIdHistoryStore::get_node_info(&unionstore, &k).await?,
+            Some(node_info(&k))
+        );
+        AsyncRemoteHistoryStore::prefetch(&unionstore, &store_keys(&[k.clone()])).await?;
+        let err = AsyncRemoteHistoryStore::prefetch(&unionstore, &store_keys(&[parent.clone()]))
+            .await
+            .unwrap_err();
+        let err = err.downcast::<PrefetchError>()?;
+        assert_eq!(err.missing, store_keys(&[parent]));
+        assert_eq!(err.errors.len(), 1);
+
+        let unionstore = unionstore.with_consistency_mode(ConsistencyMode::Strict);
+        let err = AsyncHgIdHistoryStore::get_node_info(&unionstore, &k)
+            .await
+            .unwrap_err();
+        let err = err.downcast::<HistoryMismatchError>()?;
+        assert_eq!((err.first, err.other), (StoreId(1), StoreId(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_parallel() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let keys = store_keys(&[k1.clone(), k2.clone(), k3.clone()]);
+        let calls = CallLog::default();
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        let first = FakeRemoteHistoryStore::new("first", &[k1.clone()], calls.clone());
+        let second = FakeRemoteHistoryStore::new("second", &[k2.clone()], calls.clone());
+        unionstore.add(first);
+        unionstore.add(second);
+
+        // Duplicated keys are only fetched and reported once.
+        let mut duplicated = keys.clone();
+        duplicated.extend(keys.clone());
+        let missing = unionstore.prefetch_parallel(&duplicated)?;
+        assert_eq!(missing, store_keys(&[k3]));
+
+        // Both stores are asked for all the keys, in no particular order.
+        let mut calls = calls.lock().clone();
+        calls.sort();
+        assert_eq!(calls, vec![("first", keys.clone()), ("second", keys)]);
+        assert_eq!(unionstore.get_node_info(&k1)?, Some(node_info(&k1)));
+        assert_eq!(unionstore.get_node_info(&k2)?, Some(node_info(&k2)));
+        Ok(())
+    }
+
+    /// Remote store without any history, that records the correlation id of every `prefetch`
+    /// call.
+    #[derive(Default)]
+    struct CorrelatedRemoteHistoryStore {
+        ids: Mutex<Vec<Option<String>>>,
+    }
+
+    impl HgIdHistoryStore for CorrelatedRemoteHistoryStore {
+        fn get_node_info(&self, _key: &Key) -> Result<Option<NodeInfo>> {
+            Ok(None)
+        }
+
+        fn refresh(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LocalStore for CorrelatedRemoteHistoryStore {
+        fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+            Ok(keys.to_vec())
+        }
+    }
+
+    impl RemoteHistoryStore for CorrelatedRemoteHistoryStore {
+        fn prefetch(&self, _keys: &[StoreKey]) -> Result<()> {
+            self.ids.lock().push(correlation_id());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_prefetch_correlation_id() -> Result<()> {
+        let keys = store_keys(&[key("a", "1")]);
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(CorrelatedRemoteHistoryStore::default());
+        unionstore.add(CorrelatedRemoteHistoryStore::default());
+
+        unionstore.prefetch(&keys)?;
+        unionstore.prefetch_with_correlation_id(&keys, "req-1")?;
+        with_correlation_id(Some("req-2".to_string()), || {
+            unionstore.prefetch_parallel(&keys)
+        })?;
+        assert_eq!(correlation_id(), None);
+
+        for store in &unionstore {
+            assert_eq!(
+                *store.ids.lock(),
+                vec![None, Some("req-1".to_string()), Some("req-2".to_string())]
+            );
+        }
+        Ok(())
+    }
+
+    fn child_info(key: &Key, parent: &Key) -> NodeInfo {
+        let null = null_key(key.path.as_str());
+        NodeInfo::new(key, [parent.clone(), null], key.hgid).unwrap()
+    }
+
+    #[test]
+    fn test_add_with_priority() -> Result<()> {
+        let k = key("a", "1");
+        let parent = key("a", "2");
+        let fast = MapHgIdHistoryStore(
+            vec![(k.clone(), child_info(&k, &parent))]
+                .into_iter()
+                .collect(),
+        );
+
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn HgIdHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(EmptyHgIdHistoryStore));
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k.clone()])));
+        unionstore.add_with_priority(Box::new(fast), -1);
+
+        assert_eq!(unionstore.get_node_info(&k)?, Some(child_info(&k, &parent)));
+        let hits: Vec<u64> = unionstore.stats().iter().map(|s| s.hits).collect();
+        assert_eq!(hits, vec![1, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_and_replace() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(MapHgIdHistoryStore::new(&[k1.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k2.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k3.clone()]));
+        assert_eq!(unionstore.len(), 3);
+
+        let removed = unionstore.remove(1);
+        assert!(removed.0.contains_key(&k2));
+        assert_eq!(unionstore.len(), 2);
+        assert_eq!(unionstore.get_node_info(&k1)?, Some(node_info(&k1)));
+        assert_eq!(unionstore.get_node_info(&k2)?, None);
+        assert_eq!(unionstore.get_node_info(&k3)?, Some(node_info(&k3)));
+
+        let replaced = unionstore.replace(0, MapHgIdHistoryStore::new(&[k2.clone()]));
+        assert!(replaced.0.contains_key(&k1));
+        assert_eq!(unionstore.get_node_info(&k1)?, None);
+        assert_eq!(unionstore.get_node_info(&k2)?, Some(node_info(&k2)));
+
+        unionstore.clear();
+        assert!(unionstore.is_empty());
+        assert_eq!(unionstore.get_node_info(&k3)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_members() {
+        let (k1, k2) = (key("a", "1"), key("b", "2"));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(MapHgIdHistoryStore::new(&[k1.clone(), k2.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k2.clone()]));
+
+        assert_eq!(unionstore.iter_members().count(), 3);
+        let sizes: Vec<usize> = unionstore
+            .iter_members()
+            .map(|store| store.0.len())
+            .collect();
+        assert_eq!(sizes, vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_get_missing_dedups_keys() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(MapHgIdHistoryStore::new(&[k2.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[]));
+
+        let keys = store_keys(&[k3.clone(), k1.clone(), k2.clone(), k3.clone(), k1.clone()]);
+        assert_eq!(unionstore.get_missing(&keys)?, store_keys(&[k3, k1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing_parallel() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let keys = store_keys(&[k1.clone(), k2.clone(), k3.clone(), k1.clone()]);
+
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn HgIdHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(EmptyHgIdHistoryStore));
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k2.clone()])));
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k1.clone()])));
+        assert_eq!(
+            unionstore.get_missing_parallel(&keys)?,
+            unionstore.get_missing(&keys)?
+        );
+        assert_eq!(unionstore.get_missing_parallel(&keys)?, store_keys(&[k3]));
+
+        unionstore.add(Box::new(BadHgIdHistoryStore));
+        assert!(unionstore.get_missing(&keys).is_err());
+        assert!(unionstore.get_missing_parallel(&keys).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_by_store() -> Result<()> {
+        let (k1, k2, k3, k4) = (key("a", "1"), key("b", "2"), key("c", "3"), key("d", "4"));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(MapHgIdHistoryStore::new(&[k1.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k2.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k3.clone()]));
+
+        let keys = store_keys(&[k4.clone(), k3.clone(), k2.clone(), k1.clone(), k3.clone()]);
+        let (partition, missing) = unionstore.partition_by_store(&keys)?;
+        assert_eq!(
+            partition,
+            vec![
+                (StoreId(0), store_keys(&[k1])),
+                (StoreId(1), store_keys(&[k2])),
+                (StoreId(2), store_keys(&[k3])),
+            ]
+        );
+        assert_eq!(missing, store_keys(&[k4]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_node_info_with_source() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(MapHgIdHistoryStore::new(&[k1.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k1.clone(), k2.clone()]));
+
+        assert_eq!(
+            unionstore.get_node_info_with_source(&k1)?,
+            Some((node_info(&k1), StoreId(0)))
+        );
+        assert_eq!(
+            unionstore.get_node_info_with_source(&k2)?,
+            Some((node_info(&k2), StoreId(1)))
+        );
+        assert_eq!(unionstore.get_node_info_with_source(&k3)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_with_prefix() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("a/b", "2"), key("c", "3"));
+        let first = LruHgIdHistoryStore::new(10);
+        first.add(&k1, &node_info(&k1))?;
+        first.add(&k3, &node_info(&k3))?;
+        let second = LruHgIdHistoryStore::new(10);
+        second.add(&k1, &node_info(&k1))?;
+        second.add(&k2, &node_info(&k2))?;
+
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn HgIdHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(first));
+        unionstore.add(Box::new(EmptyHgIdHistoryStore));
+        unionstore.add(Box::new(second));
+
+        let prefix = RepoPath::from_str("a")?;
+        assert_eq!(unionstore.keys_with_prefix(prefix)?, vec![k1, k2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_member_stats() {
+        let k = key("a", "1");
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn HgIdHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(EmptyHgIdHistoryStore));
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k.clone()])));
+        unionstore.add(Box::new(BadHgIdHistoryStore));
+
+        assert!(unionstore.get_node_info(&k).unwrap().is_some());
+        assert!(unionstore.get_node_info(&k).unwrap().is_some());
+        assert!(unionstore.get_node_info(&key("b", "2")).is_err());
+
+        assert_eq!(
+            unionstore.stats(),
+            vec![
+                UnionMemberStats {
+                    hits: 0,
+                    misses: 3,
+                    errors: 0,
+                    bytes_served: 0,
+                    bytes_prefetched: 0,
+                },
+                UnionMemberStats {
+                    hits: 2,
+                    misses: 1,
+                    errors: 0,
+                    bytes_served: 2 * HISTORY_ENTRY_SIZE,
+                    bytes_prefetched: 0,
+                },
+                UnionMemberStats {
+                    hits: 0,
+                    misses: 0,
+                    errors: 1,
+                    bytes_served: 0,
+                    bytes_prefetched: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_byte_counters() -> Result<()> {
+        let (k1, k2, copied) = (key("a", "1"), key("b", "2"), key("a", "3"));
+        let copy_source = key("dir/b", "4");
+        let local = MapHgIdHistoryStore(HashMap::from([(
+            copied.clone(),
+            child_info(&copied, &copy_source),
+        )]));
+        let remote = FakeRemoteHistoryStore::new("remote", &[k1.clone()], CallLog::default());
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn RemoteHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(local));
+        unionstore.add(Box::new(remote));
+
+        unionstore.prefetch(&store_keys(&[k1.clone(), k2]))?;
+        assert!(unionstore.get_node_info(&k1)?.is_some());
+        assert!(unionstore.get_node_info(&copied)?.is_some());
+
+        let stats = unionstore.stats();
+        assert_eq!(stats[0].bytes_served, 80 + "dir/b".len() as u64);
+        assert_eq!(stats[0].bytes_prefetched, 0);
+        assert_eq!(stats[1].bytes_served, 80);
+        assert_eq!(stats[1].bytes_prefetched, 80);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_ancestors() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("a", "2"), key("a", "3"));
+        let calls = CallLog::default();
+        let recent = HashMap::from([
+            (k3.clone(), child_info(&k3, &k2)),
+            (k2.clone(), child_info(&k2, &k1)),
+        ]);
+        let old = HashMap::from([(k1.clone(), node_info(&k1))]);
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        let recent_store =
+            FakeRemoteHistoryStore::with_history("recent", recent.clone(), calls.clone());
+        let old_store = FakeRemoteHistoryStore::with_history("old", old.clone(), calls.clone());
+        unionstore.add(recent_store);
+        unionstore.add(old_store);
+
+        let mut expected = recent;
+        expected.extend(old);
+        assert_eq!(unionstore.get_ancestors(&k3)?, expected);
+        // Every generation was prefetched separately.
+        assert_eq!(
+            calls
+                .lock()
+                .iter()
+                .filter(|(name, _)| *name == "recent")
+                .count(),
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_ancestors_cycle() {
+        let (k1, k2) = (key("a", "1"), key("a", "2"));
+        let history = HashMap::from([
+            (k1.clone(), child_info(&k1, &k2)),
+            (k2.clone(), child_info(&k2, &k1)),
+        ]);
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        let store = FakeRemoteHistoryStore::with_history("cycle", history, CallLog::default());
+        unionstore.add(store);
+
+        let err = unionstore.get_ancestors(&k1).unwrap_err();
+        assert!(err.is::<HistoryCycleError>());
+    }
+
+    #[test]
+    fn test_get_node_info_with_prefetch() -> Result<()> {
+        let (k1, k2, k3, k4) = (key("a", "1"), key("a", "2"), key("a", "3"), key("a", "4"));
+        let history = HashMap::from([
+            (k4.clone(), child_info(&k4, &k3)),
+            (k3.clone(), child_info(&k3, &k2)),
+            (k2.clone(), child_info(&k2, &k1)),
+            (k1.clone(), node_info(&k1)),
+        ]);
+        let store = Arc::new(FakeRemoteHistoryStore::with_history(
+            "remote",
+            history.clone(),
+            CallLog::default(),
+        ));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(store.clone());
+
+        // Without prefetching, nothing is fetched.
+        assert_eq!(unionstore.get_node_info_with_prefetch(&k4, 0)?, None);
+
+        assert_eq!(
+            unionstore.get_node_info_with_prefetch(&k4, 2)?,
+            Some(history[&k4].clone())
+        );
+        let missing = store.get_missing(&store_keys(&[k1.clone(), k2, k3, k4]))?;
+        assert_eq!(missing, store_keys(&[k1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_node_info_batch() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(MapHgIdHistoryStore::new(&[k2.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k1.clone(), k2.clone()]));
+
+        let keys = [k1.clone(), k3, k2.clone(), k1.clone()];
+        assert_eq!(
+            unionstore.get_node_info_batch(&keys)?,
+            vec![
+                Some(node_info(&k1)),
+                None,
+                Some(node_info(&k2)),
+                Some(node_info(&k1)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_node_info_batch_round_trips() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let keys = [k1.clone(), k2.clone(), k3.clone()];
+        let expected = vec![Some(node_info(&k1)), Some(node_info(&k2)), None];
+        let unionstore = |calls: &CallLog| {
+            let mut unionstore: UnionHgIdHistoryStore<Box<dyn HgIdHistoryStore>> =
+                UnionHgIdHistoryStore::new();
+            unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k1.clone()])));
+            unionstore.add(Box::new(RoundTripHistoryStore::new(
+                "remote",
+                &[k1.clone(), k2.clone()],
+                calls.clone(),
+            )));
+            unionstore
+        };
+
+        // The remote store is only asked for what the local store is missing.
+        let calls = CallLog::default();
+        assert_eq!(unionstore(&calls).get_node_info_batch(&keys)?, expected);
+        assert_eq!(
+            *calls.lock(),
+            vec![("remote", store_keys(&[k2.clone(), k3.clone()]))]
+        );
+
+        let calls = CallLog::default();
+        let store = unionstore(&calls).with_lookup_policy(LookupPolicy::ServeAvailable);
+        assert_eq!(store.get_node_info_batch(&keys)?, expected);
+        assert_eq!(
+            *calls.lock(),
+            vec![("remote", store_keys(&[k2.clone(), k3.clone()]))]
+        );
+
+        // In strict mode, the remote store is asked for all the keys, still at once.
+        let calls = CallLog::default();
+        let store = unionstore(&calls).with_consistency_mode(ConsistencyMode::Strict);
+        assert_eq!(store.get_node_info_batch(&keys)?, expected);
+        assert_eq!(*calls.lock(), vec![("remote", store_keys(&keys))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing_stops_once_nothing_is_missing() -> Result<()> {
+        let keys = store_keys(&[key("a", "1"), key("b", "2")]);
+        let calls = CallLog::default();
+        let store = |name, keys: &[StoreKey]| LoggingLocalStore {
+            name,
+            keys: keys.iter().cloned().collect(),
+            calls: calls.clone(),
+        };
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(store("second", &keys[1..]));
+        unionstore.add(store("third", &[]));
+        unionstore.add_with_priority(store("first", &keys[..1]), -1);
+
+        assert_eq!(unionstore.get_missing(&keys)?, vec![]);
+        assert_eq!(
+            *calls.lock(),
+            vec![("first", keys.clone()), ("second", keys[1..].to_vec())]
+        );
+
+        calls.lock().clear();
+        assert_eq!(unionstore.get_missing(&[])?, vec![]);
+        assert!(calls.lock().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_many_keys_sharing_a_path() -> Result<()> {
+        let keys: Vec<Key> = (0..10_000)
+            .map(|i| key("dir/file", &format!("{:x}", i + 1)))
+            .collect();
+        let (found, not_found) = keys.split_at(6_000);
+        let mut requested = store_keys(&keys);
+        assert!(matches!(dedup_keys(&requested), Cow::Borrowed(_)));
+        requested.extend(store_keys(&keys[..10]));
+        assert_eq!(dedup_keys(&requested).len(), keys.len());
+
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        let calls = CallLog::default();
+        unionstore.add(FakeRemoteHistoryStore::new("remote", found, calls));
+
+        let not_found = store_keys(not_found);
+        assert_eq!(unionstore.prefetch_report(&requested)?, not_found);
+        assert_eq!(unionstore.get_missing(&requested)?, not_found);
+        for k in found {
+            assert_eq!(unionstore.get_node_info(k)?, Some(node_info(k)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_available() -> Result<()> {
+        let (k1, k2, k3) = (key("a", "1"), key("b", "2"), key("c", "3"));
+        let calls = CallLog::default();
+        let remote_keys = [k1.clone(), k3.clone()];
+        let remote = FakeRemoteHistoryStore::new("remote", &remote_keys, calls.clone());
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn RemoteHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k2.clone()])));
+        unionstore.add(Box::new(remote));
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k3.clone()])));
+
+        let keys = store_keys(&[k1, k2.clone(), k3.clone()]);
+        let available = unionstore.prefetch_available(&keys)?;
+        assert_eq!(
+            available,
+            vec![(k2.clone(), node_info(&k2)), (k3.clone(), node_info(&k3))]
+        );
+        assert!(calls.lock().is_empty());
+
+        unionstore.prefetch(&keys)?;
+        let available = unionstore.prefetch_available(&keys)?;
+        assert_eq!(available.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consistency_mode() -> Result<()> {
+        let (k, parent) = (key("a", "1"), key("a", "2"));
+        let conflicting =
+            MapHgIdHistoryStore(HashMap::from([(k.clone(), child_info(&k, &parent))]));
+        let mut unionstore = UnionHgIdHistoryStore::new();
+        unionstore.add(MapHgIdHistoryStore::new(&[k.clone()]));
+        unionstore.add(MapHgIdHistoryStore::new(&[k.clone(), parent.clone()]));
+        unionstore.add(conflicting);
+
+        assert_eq!(unionstore.get_node_info(&k)?, Some(node_info(&k)));
+
+        let unionstore = unionstore.with_consistency_mode(ConsistencyMode::Strict);
+        assert_eq!(unionstore.get_node_info(&parent)?, Some(node_info(&parent)));
+        let err = unionstore.get_node_info(&k).unwrap_err();
+        let err = err.downcast::<HistoryMismatchError>().unwrap();
+        assert_eq!((err.first, err.other), (StoreId(0), StoreId(2)));
+        assert!(unionstore.get_node_info_batch(&[k]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_available_when_remotes_fail() -> Result<()> {
+        let (k1, k2) = (key("a", "1"), key("b", "2"));
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn RemoteHistoryStore>> =
+            UnionStoreBuilder::new()
+                .store(Box::new(BadHgIdHistoryStore))
+                .store(Box::new(MapHgIdHistoryStore::new(&[k1.clone()])))
+                .store(Box::new(BadHgIdHistoryStore))
+                .prefetch_policy(PrefetchPolicy::BestEffort)
+                .build();
+
+        let keys = store_keys(&[k1.clone(), k2.clone()]);
+        assert!(unionstore.prefetch(&keys).is_err());
+        assert!(unionstore.get_node_info(&k1).is_err());
+
+        unionstore = unionstore.with_lookup_policy(LookupPolicy::ServeAvailable);
+        assert_eq!(unionstore.get_node_info(&k1)?, Some(node_info(&k1)));
+        let err = unionstore.get_node_info(&k2).unwrap_err();
+        assert!(err.is::<BadHgIdHistoryStoreError>());
+        let infos = unionstore.get_node_info_batch(&[k1.clone()])?;
+        assert_eq!(infos, vec![Some(node_info(&k1))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_node_info_lenient() {
+        let k = key("a", "1");
+        let mut unionstore: UnionHgIdHistoryStore<Box<dyn HgIdHistoryStore>> =
+            UnionHgIdHistoryStore::new();
+        unionstore.add(Box::new(BadHgIdHistoryStore));
+        unionstore.add(Box::new(EmptyHgIdHistoryStore));
+        unionstore.add(Box::new(MapHgIdHistoryStore::new(&[k.clone()])));
+
+        // The failing store hides the result from the strict lookup.
+        assert!(unionstore.get_node_info(&k).is_err());
+
+        let (info, errors) = unionstore.get_node_info_lenient(&k);
+        assert_eq!(info, Some(node_info(&k)));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].is::<BadHgIdHistoryStoreError>());
+
+        let (info, errors) = unionstore.get_node_info_lenient(&key("b", "2"));
+        assert_eq!(info, None);
+        assert_eq!(errors.len(), 1);
+    }
+
     quickcheck! {
         fn test_empty_unionstore_get_node_info(key: Key) -> bool {
             match UnionHgIdHistoryStore::<EmptyHgIdHistoryStore>::new().get_node_info(&key) {
@@ -132,22 +1828,47 @@ mod tests {
             }
         }
 
+        fn test_empty_unionstore_get_node_info_batch(keys: Vec<Key>) -> bool {
+            match UnionHgIdHistoryStore::<EmptyHgIdHistoryStore>::new().get_node_info_batch(&keys) {
+                Ok(infos) => infos.len() == keys.len() && infos.iter().all(Option::is_none),
+                _ => false,
+            }
+        }
+
+        fn test_empty_historystore_get_node_info_batch(keys: Vec<Key>) -> bool {
+            let mut unionstore = UnionHgIdHistoryStore::new();
+            unionstore.add(EmptyHgIdHistoryStore);
+            match unionstore.get_node_info_batch(&keys) {
+                Ok(infos) => infos.len() == keys.len() && infos.iter().all(Option::is_none),
+                _ => false,
+            }
+        }
+
+        fn test_bad_historystore_get_node_info_batch(keys: Vec<Key>) -> bool {
+            let mut unionstore = UnionHgIdHistoryStore::new();
+            unionstore.add(BadHgIdHistoryStore);
+            match unionstore.get_node_info_batch(&keys) {
+                Err(_) => !keys.is_empty(),
+                Ok(infos) => keys.is_empty() && infos.is_empty(),
+            }
+        }
+
         fn test_empty_unionstore_get_missing(keys: Vec<StoreKey>) -> bool {
-            keys == UnionHgIdHistoryStore::<EmptyHgIdHistoryStore>::new().get_missing(&keys).unwrap()
+            dedup_keys(&keys) == UnionHgIdHistoryStore::<EmptyHgIdHistoryStore>::new().get_missing(&keys).unwrap()
         }
 
         fn test_empty_historystore_get_missing(keys: Vec<StoreKey>) -> bool {
             let mut unionstore = UnionHgIdHistoryStore::new();
             unionstore.add(EmptyHgIdHistoryStore);
-            keys == unionstore.get_missing(&keys).unwrap()
+            dedup_keys(&keys) == unionstore.get_missing(&keys).unwrap()
         }
 
         fn test_bad_historystore_get_missing(keys: Vec<StoreKey>) -> bool {
             let mut unionstore = UnionHgIdHistoryStore::new();
             unionstore.add(BadHgIdHistoryStore);
             match unionstore.get_missing(&keys) {
-                Err(_) => true,
-                _ => false,
+                Err(_) => !keys.is_empty(),
+                Ok(missing) => keys.is_empty() && missing.is_empty(),
             }
         }
     }
@@ -7,10 +7,20 @@
 
 // Union store
 
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::iter::Map;
+use std::mem;
+use std::panic;
 use std::slice::Iter;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
 use std::vec::IntoIter;
 
 use anyhow::Result;
+use parking_lot::Mutex;
 use types::Key;
 
 use crate::localstore::LocalStore;
@@ -18,45 +28,441 @@ use crate::repack::ToKeys;
 use crate::types::StoreKey;
 
 pub struct UnionStore<T> {
-    stores: Vec<T>,
+    stores: Vec<Member<T>>,
+    max_prefetch_batch: Option<usize>,
+    prefetch_policy: PrefetchPolicy,
+    consistency_mode: ConsistencyMode,
+    lookup_policy: LookupPolicy,
+}
+
+/// How a union store prefetch handles a store that fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefetchPolicy {
+    /// Fail the prefetch with the error of the first store that fails. This is the default.
+    FailFast,
+    /// Keep going with the next stores, and only fail if some keys are still missing once all
+    /// the stores were consulted. The error is then a `PrefetchError` with the errors of all
+    /// the stores that failed.
+    BestEffort,
+}
+
+/// How a union store lookup handles stores that have different data for the same key, which
+/// means that one of them is corrupted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsistencyMode {
+    /// Return the data of the first store that has it, without asking the next stores. This is
+    /// the default.
+    FirstWins,
+    /// Ask all the stores, and fail if they don't all have the same data. This is only meant
+    /// for debugging, as every lookup reaches every store.
+    Strict,
+}
+
+/// How a union store lookup handles a store that fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupPolicy {
+    /// Fail the lookup with the error of the first store that fails. This is the default.
+    FailFast,
+    /// Keep going with the next stores, and only fail if none of them has the data. This
+    /// keeps serving what the local stores have when the remote stores are down.
+    ServeAvailable,
+}
+
+/// Identifies a store of a `UnionStore` by its position, in the order the stores are
+/// consulted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StoreId(pub usize);
+
+/// Lookup counters of a member of a `UnionStore`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UnionMemberStats {
+    /// Lookups answered by this member.
+    pub hits: u64,
+    /// Lookups this member didn't have the data for, passed on to the next members.
+    pub misses: u64,
+    /// Lookups that failed in this member.
+    pub errors: u64,
+    /// Estimated size of the data returned by the lookups answered by this member.
+    pub bytes_served: u64,
+    /// Estimated size of the data this member fetched in prefetches.
+    pub bytes_prefetched: u64,
+}
+
+/// Number of prefetch requests per batch size. Batch sizes are grouped by the power of two
+/// right above them: the entry for `n` counts the batches of more than `n / 2` and at most `n`
+/// keys. Empty batches are counted under 0.
+pub type BatchSizeHistogram = BTreeMap<usize, u64>;
+
+/// A store of a `UnionStore`, along with its lookup counters.
+pub struct Member<T> {
+    pub(crate) store: T,
+    priority: i32,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+    bytes_served: AtomicU64,
+    bytes_prefetched: AtomicU64,
+    prefetch_batch_sizes: Mutex<BatchSizeHistogram>,
+}
+
+impl<T> Member<T> {
+    fn new(store: T, priority: i32) -> Self {
+        Member {
+            store,
+            priority,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            bytes_prefetched: AtomicU64::new(0),
+            prefetch_batch_sizes: Mutex::new(BatchSizeHistogram::new()),
+        }
+    }
+
+    /// Records a lookup answered with `bytes` of data.
+    pub(crate) fn record_hit(&self, bytes: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_prefetch(&self, batch_size: usize) {
+        let bucket = match batch_size {
+            0 => 0,
+            n => n.next_power_of_two(),
+        };
+        *self.prefetch_batch_sizes.lock().entry(bucket).or_default() += 1;
+    }
+
+    pub(crate) fn record_prefetched_bytes(&self, bytes: u64) {
+        self.bytes_prefetched.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> UnionMemberStats {
+        UnionMemberStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+            bytes_prefetched: self.bytes_prefetched.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl<T> UnionStore<T> {
     pub fn new() -> UnionStore<T> {
-        UnionStore { stores: Vec::new() }
+        UnionStore {
+            stores: Vec::new(),
+            max_prefetch_batch: None,
+            prefetch_policy: PrefetchPolicy::FailFast,
+            consistency_mode: ConsistencyMode::FirstWins,
+            lookup_policy: LookupPolicy::FailFast,
+        }
+    }
+
+    /// Sets how the lookups handle a store that fails.
+    pub fn with_lookup_policy(mut self, policy: LookupPolicy) -> Self {
+        self.lookup_policy = policy;
+        self
+    }
+
+    pub fn lookup_policy(&self) -> LookupPolicy {
+        self.lookup_policy
+    }
+
+    /// Sets how the lookups handle stores that disagree.
+    pub fn with_consistency_mode(mut self, mode: ConsistencyMode) -> Self {
+        self.consistency_mode = mode;
+        self
+    }
+
+    pub fn consistency_mode(&self) -> ConsistencyMode {
+        self.consistency_mode
+    }
+
+    /// Sets how the prefetches handle a store that fails.
+    pub fn with_prefetch_policy(mut self, policy: PrefetchPolicy) -> Self {
+        self.prefetch_policy = policy;
+        self
+    }
+
+    pub fn prefetch_policy(&self) -> PrefetchPolicy {
+        self.prefetch_policy
+    }
+
+    /// Splits the prefetches sent to each store into batches of at most `max` keys, for
+    /// stores that can't handle large requests.
+    ///
+    /// Panics if `max` is 0.
+    pub fn with_max_prefetch_batch(mut self, max: usize) -> Self {
+        assert!(max > 0, "prefetch batches can't be empty");
+        self.max_prefetch_batch = Some(max);
+        self
+    }
+
+    /// Splits `keys` into the batches to prefetch from a store.
+    pub(crate) fn prefetch_batches<'a>(
+        &self,
+        keys: &'a [StoreKey],
+    ) -> impl Iterator<Item = &'a [StoreKey]> {
+        let batch_size = self.max_prefetch_batch.unwrap_or(keys.len()).max(1);
+        keys.chunks(batch_size)
     }
 
     pub fn add(&mut self, item: T) {
-        self.stores.push(item)
+        self.add_with_priority(item, 0)
+    }
+
+    /// Adds a store to the union. Stores are consulted by ascending `priority`, and
+    /// in insertion order for stores of equal priority. `add` uses a priority of 0.
+    pub fn add_with_priority(&mut self, item: T, priority: i32) {
+        let index = self
+            .stores
+            .partition_point(|member| member.priority <= priority);
+        self.stores.insert(index, Member::new(item, priority))
+    }
+
+    /// Removes and returns the store at position `index`, in the order the stores are
+    /// consulted.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> T {
+        self.stores.remove(index).store
+    }
+
+    /// Replaces the store at position `index` with `item`, which keeps the priority of the
+    /// store it replaces. Returns the replaced store.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn replace(&mut self, index: usize, item: T) -> T {
+        let priority = self.stores[index].priority;
+        mem::replace(&mut self.stores[index], Member::new(item, priority)).store
+    }
+
+    /// Removes all the stores.
+    pub fn clear(&mut self) {
+        self.stores.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stores.is_empty()
+    }
+
+    /// Returns the lookup counters of every member, in the order the members are
+    /// consulted.
+    pub fn stats(&self) -> Vec<UnionMemberStats> {
+        self.stores.iter().map(Member::stats).collect()
+    }
+
+    /// Returns the sizes of the prefetch requests sent to every member, in the order the
+    /// members are consulted.
+    pub fn prefetch_batch_sizes(&self) -> Vec<BatchSizeHistogram> {
+        self.stores
+            .iter()
+            .map(|member| member.prefetch_batch_sizes.lock().clone())
+            .collect()
+    }
+
+    /// Iterates over the stores, in the order they are consulted.
+    pub fn iter_members(&self) -> impl Iterator<Item = &T> {
+        self.into_iter()
+    }
+
+    pub(crate) fn members(&self) -> Iter<'_, Member<T>> {
+        self.stores.iter()
+    }
+}
+
+/// Builds a `UnionStore` along with its configuration. For simple cases, `UnionStore::new`
+/// followed by `UnionStore::add` is enough.
+pub struct UnionStoreBuilder<T> {
+    stores: Vec<(T, i32)>,
+    max_prefetch_batch: Option<usize>,
+    prefetch_policy: PrefetchPolicy,
+    consistency_mode: ConsistencyMode,
+    lookup_policy: LookupPolicy,
+}
+
+impl<T> UnionStoreBuilder<T> {
+    pub fn new() -> Self {
+        Self {
+            stores: Vec::new(),
+            max_prefetch_batch: None,
+            prefetch_policy: PrefetchPolicy::FailFast,
+            consistency_mode: ConsistencyMode::FirstWins,
+            lookup_policy: LookupPolicy::FailFast,
+        }
+    }
+
+    /// Adds a store, see `UnionStore::add`.
+    pub fn store(self, store: T) -> Self {
+        self.store_with_priority(store, 0)
+    }
+
+    /// Adds a store, see `UnionStore::add_with_priority`.
+    pub fn store_with_priority(mut self, store: T, priority: i32) -> Self {
+        self.stores.push((store, priority));
+        self
+    }
+
+    /// See `UnionStore::with_max_prefetch_batch`.
+    pub fn max_prefetch_batch(mut self, max: usize) -> Self {
+        self.max_prefetch_batch = Some(max);
+        self
+    }
+
+    /// See `UnionStore::with_prefetch_policy`.
+    pub fn prefetch_policy(mut self, policy: PrefetchPolicy) -> Self {
+        self.prefetch_policy = policy;
+        self
+    }
+
+    /// See `UnionStore::with_consistency_mode`.
+    pub fn consistency_mode(mut self, mode: ConsistencyMode) -> Self {
+        self.consistency_mode = mode;
+        self
+    }
+
+    /// See `UnionStore::with_lookup_policy`.
+    pub fn lookup_policy(mut self, policy: LookupPolicy) -> Self {
+        self.lookup_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> UnionStore<T> {
+        let mut store = UnionStore::new()
+            .with_prefetch_policy(self.prefetch_policy)
+            .with_consistency_mode(self.consistency_mode)
+            .with_lookup_policy(self.lookup_policy);
+        if let Some(max) = self.max_prefetch_batch {
+            store = store.with_max_prefetch_batch(max);
+        }
+        for (item, priority) in self.stores {
+            store.add_with_priority(item, priority);
+        }
+        store
+    }
+}
+
+impl<T> Default for UnionStoreBuilder<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<T: LocalStore> LocalStore for UnionStore<T> {
+    /// Asks the stores in the order they are consulted for the keys that the previous stores
+    /// don't have. The remaining stores aren't queried once no key is missing.
     fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
-        let initial_keys = Ok(keys.to_vec());
-        self.into_iter()
-            .fold(initial_keys, |missing_keys, store| match missing_keys {
-                Ok(missing_keys) => store.get_missing(&missing_keys),
-                Err(e) => Err(e),
-            })
+        let mut missing = dedup_keys(keys);
+        for store in self {
+            if missing.is_empty() {
+                break;
+            }
+            missing = Cow::Owned(store.get_missing(&missing)?);
+        }
+        Ok(missing.into_owned())
     }
 }
 
+impl<T: LocalStore> UnionStore<T> {
+    /// Same as `get_missing`, but every store is queried for all the `keys` concurrently,
+    /// instead of only the keys that the previous stores don't have. The result is the same.
+    pub fn get_missing_parallel(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let keys = dedup_keys(keys);
+        let missing_per_store = thread::scope(|scope| {
+            let keys = &keys;
+            let handles: Vec<_> = self
+                .into_iter()
+                .map(|store| scope.spawn(move || store.get_missing(keys)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(intersect_missing(keys.into_owned(), missing_per_store))
+    }
+
+    /// Splits `keys` by the first store, in the order they are consulted, that has them.
+    /// Every store gets an entry, possibly empty, and the keys that no store has are returned
+    /// separately. Stores are only asked for the keys that the previous stores don't have.
+    pub fn partition_by_store(
+        &self,
+        keys: &[StoreKey],
+    ) -> Result<(Vec<(StoreId, Vec<StoreKey>)>, Vec<StoreKey>)> {
+        let mut remaining = dedup_keys(keys).into_owned();
+        let mut partition = Vec::with_capacity(self.len());
+        for (index, store) in self.into_iter().enumerate() {
+            if remaining.is_empty() {
+                partition.push((StoreId(index), Vec::new()));
+                continue;
+            }
+            let store_missing: HashSet<_> = store.get_missing(&remaining)?.into_iter().collect();
+            let (missing, found) = remaining
+                .into_iter()
+                .partition(|key| store_missing.contains(key));
+            partition.push((StoreId(index), found));
+            remaining = missing;
+        }
+        Ok((partition, remaining))
+    }
+}
+
+/// Keeps the `keys` that are missing from every store, given the keys each store is missing.
+pub(crate) fn intersect_missing(
+    mut keys: Vec<StoreKey>,
+    missing_per_store: Vec<Vec<StoreKey>>,
+) -> Vec<StoreKey> {
+    for store_missing in missing_per_store {
+        let store_missing: HashSet<_> = store_missing.into_iter().collect();
+        keys.retain(|key| store_missing.contains(key));
+    }
+    keys
+}
+
+/// Removes the duplicates from `keys`, keeping the first occurrence of each key.
+///
+/// Prefetches can carry millions of keys, so `keys` is only copied when it has duplicates.
+/// Keys sharing a path still hold their own copy of it, as `RepoPathBuf` owns its string.
+pub(crate) fn dedup_keys(keys: &[StoreKey]) -> Cow<'_, [StoreKey]> {
+    let mut seen = HashSet::with_capacity(keys.len());
+    if keys.iter().all(|key| seen.insert(key)) {
+        return Cow::Borrowed(keys);
+    }
+    seen.clear();
+    let deduped = keys.iter().filter(|key| seen.insert(*key)).cloned();
+    Cow::Owned(deduped.collect())
+}
+
 impl<T> IntoIterator for UnionStore<T> {
     type Item = T;
-    type IntoIter = IntoIter<T>;
+    type IntoIter = Map<IntoIter<Member<T>>, fn(Member<T>) -> T>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.stores.into_iter()
+        let into_store: fn(Member<T>) -> T = |member| member.store;
+        self.stores.into_iter().map(into_store)
     }
 }
 
 impl<'a, T> IntoIterator for &'a UnionStore<T> {
     type Item = &'a T;
-    type IntoIter = Iter<'a, T>;
+    type IntoIter = Map<Iter<'a, Member<T>>, fn(&'a Member<T>) -> &'a T>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.stores.iter()
+        let store: fn(&'a Member<T>) -> &'a T = |member| &member.store;
+        self.stores.iter().map(store)
     }
 }
 
@@ -23,6 +23,7 @@ pub use crate::hgid::HgId;
 pub use crate::key::Key;
 pub use crate::node::Node;
 pub use crate::nodeinfo::NodeInfo;
+pub use crate::nodeinfo::NodeInfoError;
 pub use crate::parents::Parents;
 pub use crate::path::PathComponent;
 pub use crate::path::PathComponentBuf;
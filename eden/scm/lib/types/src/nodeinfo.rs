@@ -7,10 +7,15 @@
 
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use thiserror::Error;
 
 use crate::hgid::HgId;
 use crate::key::Key;
 
+/// The parents and linknode of a file revision.
+///
+/// Prefer `NodeInfo::new`, which validates the parents, and the accessors over using the fields
+/// directly.
 #[derive(
     Clone,
     Debug,
@@ -28,6 +33,55 @@ pub struct NodeInfo {
     pub linknode: HgId,
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NodeInfoError {
+    #[error("Second parent {0} without a first parent.")]
+    SecondParentWithoutFirst(Key),
+    #[error("Both parents are {0}.")]
+    DuplicateParents(Key),
+    #[error("{0} is its own parent.")]
+    OwnParent(Key),
+}
+
+impl NodeInfo {
+    /// Creates the `NodeInfo` of a new revision `key`, failing if `key` is one of its own
+    /// parents, if it only has a second parent, or if both parents are the same. Unlike
+    /// `validate`, this rejects the shapes of history that hg only produces for copies and in
+    /// old repos, as new revisions shouldn't have them.
+    pub fn new(key: &Key, parents: [Key; 2], linknode: HgId) -> Result<Self, NodeInfoError> {
+        let info = NodeInfo { parents, linknode };
+        info.validate(key)?;
+        let [p1, p2] = &info.parents;
+        if p1.hgid.is_null() && !p2.hgid.is_null() {
+            return Err(NodeInfoError::SecondParentWithoutFirst(p2.clone()));
+        }
+        if !p1.hgid.is_null() && p1 == p2 {
+            return Err(NodeInfoError::DuplicateParents(p1.clone()));
+        }
+        Ok(info)
+    }
+
+    pub fn parents(&self) -> &[Key; 2] {
+        &self.parents
+    }
+
+    pub fn linknode(&self) -> HgId {
+        self.linknode
+    }
+
+    /// Checks the invariants that hg guarantees for the history of `key`, which hold for any
+    /// entry fetched from a server: `key` isn't one of its own parents.
+    ///
+    /// A null first parent with a second parent, which hg writes for some copies, and twice the
+    /// same parent, found in old repos, are valid.
+    pub fn validate(&self, key: &Key) -> Result<(), NodeInfoError> {
+        if self.parents.contains(key) {
+            return Err(NodeInfoError::OwnParent(key.clone()));
+        }
+        Ok(())
+    }
+}
+
 #[cfg(any(test, feature = "for-tests"))]
 use quickcheck::Arbitrary;
 #[cfg(any(test, feature = "for-tests"))]
@@ -42,3 +96,50 @@ impl Arbitrary for NodeInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_validate() {
+        let info = |p1, p2| NodeInfo {
+            parents: [p1, p2],
+            linknode: hgid("9"),
+        };
+
+        let k = key("a", "2");
+        assert_eq!(info(null_key("a"), null_key("a")).validate(&k), Ok(()));
+        assert_eq!(info(key("a", "1"), null_key("a")).validate(&k), Ok(()));
+        assert_eq!(info(key("a", "1"), key("b", "2")).validate(&k), Ok(()));
+        // Both shapes come from real hg history.
+        assert_eq!(info(null_key("a"), key("a", "1")).validate(&k), Ok(()));
+        assert_eq!(info(key("a", "1"), key("a", "1")).validate(&k), Ok(()));
+        assert_eq!(
+            info(null_key("a"), k.clone()).validate(&k),
+            Err(NodeInfoError::OwnParent(k.clone()))
+        );
+    }
+
+    #[test]
+    fn test_new() {
+        let k = key("a", "2");
+        let info = NodeInfo::new(&k, [key("a", "1"), null_key("a")], hgid("9")).unwrap();
+        assert_eq!(info.parents(), &[key("a", "1"), null_key("a")]);
+        assert_eq!(info.linknode(), hgid("9"));
+
+        assert_eq!(
+            NodeInfo::new(&k, [k.clone(), null_key("a")], hgid("9")),
+            Err(NodeInfoError::OwnParent(k.clone()))
+        );
+        assert_eq!(
+            NodeInfo::new(&k, [null_key("a"), key("a", "1")], hgid("9")),
+            Err(NodeInfoError::SecondParentWithoutFirst(key("a", "1")))
+        );
+        assert_eq!(
+            NodeInfo::new(&k, [key("a", "1"), key("a", "1")], hgid("9")),
+            Err(NodeInfoError::DuplicateParents(key("a", "1")))
+        );
+    }
+}